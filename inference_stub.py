@@ -0,0 +1,37 @@
+#!/usr/bin/env python3
+"""Stand-in for the inference server configured in [inference] of Settings.toml.
+
+Answers every POST with a fixed prediction so pre-labelling can be tried
+without a model:
+
+    python3 inference_stub.py [port]
+"""
+import json
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        length = int(self.headers.get("Content-Length", 0))
+        image = self.rfile.read(length)
+        photo_id = self.headers.get("X-Tagify-Photo-Id")
+        print(f"photo {photo_id}: {len(image)} bytes, {self.headers.get('Content-Type')}")
+
+        body = json.dumps({
+            "model": "stub",
+            "predictions": [
+                {"label": "cat", "confidence": 0.9, "coordinates": "10,10,100,100"},
+                {"label": "dog", "confidence": 0.1, "coordinates": "10,10,100,100"},
+            ],
+        }).encode()
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8500
+    HTTPServer(("127.0.0.1", port), Handler).serve_forever()
@@ -20,6 +20,94 @@ ALTER TABLE users DROP CONSTRAINT IF EXISTS userchk;
 ALTER TABLE users
   ADD CONSTRAINT userchk CHECK (char_length(nickname) <= 64 AND char_length(nickname) >= 4);
 
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS oidc_subject TEXT UNIQUE;
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_idx ON users (lower(email));
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOL NOT NULL DEFAULT FALSE;
+-- 'active' or 'pending' while a self registered account waits for approval
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active';
+
+CREATE TABLE IF NOT EXISTS invite_codes (
+    code TEXT PRIMARY KEY,
+    created_by INT,
+    expires_at BIGINT NOT NULL,
+    used BOOL NOT NULL DEFAULT FALSE,
+    used_by INT,
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (used_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+-- Single use tokens sent by mail, only their hash is stored
+CREATE TABLE IF NOT EXISTS user_tokens (
+    token_hash TEXT PRIMARY KEY,
+    users_id INT NOT NULL,
+    purpose TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used BOOL NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS oidc_states (
+    state TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id SERIAL PRIMARY KEY,
+    users_id INT NOT NULL,
+    code_hash TEXT NOT NULL,
+    used BOOL NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS login_challenges (
+    token TEXT PRIMARY KEY,
+    users_id INT NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS two_factor_policy (
+    role TEXT PRIMARY KEY,
+    enforced BOOL NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS login_attempts (
+    attempt_key TEXT PRIMARY KEY,
+    failures INT NOT NULL DEFAULT 0,
+    last_failure BIGINT NOT NULL DEFAULT 0,
+    locked_until BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS failed_logins (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    attempted_at BIGINT NOT NULL
+);
+
+-- Actors are not foreign keys, entries must outlive deleted users
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor_id INT,
+    actor_name TEXT,
+    action TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id TEXT,
+    before JSONB,
+    after JSONB,
+    ip TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+CREATE INDEX IF NOT EXISTS audit_log_target_idx ON audit_log (target_type, target_id);
+CREATE INDEX IF NOT EXISTS audit_log_actor_idx ON audit_log (actor_id);
+
 CREATE TABLE IF NOT EXISTS albums (
     id SERIAL PRIMARY KEY,
     title VARCHAR(300) NOT NULL,
@@ -33,6 +121,13 @@ CREATE TABLE IF NOT EXISTS albums (
     date_modified DATE NOT NULL DEFAULT CURRENT_DATE,
     FOREIGN KEY (users_id) REFERENCES users (id)
 );
+ALTER TABLE albums ADD COLUMN IF NOT EXISTS gold_rate REAL NOT NULL DEFAULT 0.1;
+ALTER TABLE albums ADD COLUMN IF NOT EXISTS leaderboard_enabled BOOL NOT NULL DEFAULT FALSE;
+-- in assignment mode taggers only get photos of batches assigned to them
+ALTER TABLE albums ADD COLUMN IF NOT EXISTS assignment_mode BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE albums ADD COLUMN IF NOT EXISTS queue_strategy TEXT NOT NULL DEFAULT 'sequential';
+-- what happens to an upload whose content is already in the album: 'reject', 'link' or 'allow'
+ALTER TABLE albums ADD COLUMN IF NOT EXISTS duplicate_policy TEXT NOT NULL DEFAULT 'reject';
 
 CREATE TABLE IF NOT EXISTS is_tagging_album (
     users_id INT NOT NULL,
@@ -56,8 +151,169 @@ CREATE TABLE IF NOT EXISTS image_metas (
     date_modified DATE NOT NULL DEFAULT CURRENT_DATE,
     FOREIGN KEY (album_id) REFERENCES albums (id)
 );
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS tagged_by INT REFERENCES users (id) ON DELETE SET NULL;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS review_status TEXT NOT NULL DEFAULT '';
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS review_comment TEXT NOT NULL DEFAULT '';
+-- gold items keep the known label they are scored against
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS is_gold BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS gold_tag TEXT NOT NULL DEFAULT '';
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS gold_coordinates TEXT NOT NULL DEFAULT '';
+-- inputs of the queue strategies, score is a model confidence between 0 and 1
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS priority INT NOT NULL DEFAULT 0;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS score REAL;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS served_at BIGINT NOT NULL DEFAULT 0;
+-- read from the file on upload, NULL for photos uploaded before
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS width INT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS height INT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS mime_type TEXT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS byte_size BIGINT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS original_filename TEXT;
+-- EXIF, taken_at is the camera's local time without time zone
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS taken_at TEXT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS camera_make TEXT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS camera_model TEXT;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS gps_latitude DOUBLE PRECISION;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS gps_longitude DOUBLE PRECISION;
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS orientation INT;
+-- SHA-256 of the file, hex encoded
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS content_hash TEXT;
+CREATE INDEX IF NOT EXISTS image_metas_content_hash_idx ON image_metas (content_hash);
+-- 64 bit dHash, near duplicates differ in only a few bits
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS perceptual_hash BIGINT;
+
+CREATE TABLE IF NOT EXISTS task_batches (
+    id SERIAL PRIMARY KEY,
+    album_id INT NOT NULL,
+    name TEXT NOT NULL,
+    assigned_to INT,
+    created_by INT,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (album_id) REFERENCES albums (id) ON DELETE CASCADE,
+    FOREIGN KEY (assigned_to) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL
+);
+ALTER TABLE image_metas ADD COLUMN IF NOT EXISTS task_batch_id INT REFERENCES task_batches (id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS image_metas_task_batch_idx ON image_metas (task_batch_id);
+
+-- Every label a photo had, rows are only ever appended
+CREATE TABLE IF NOT EXISTS tag_revisions (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    users_id INT,
+    action TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    coordinates TEXT NOT NULL,
+    tagged BOOL NOT NULL,
+    verified BOOL NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS tag_revisions_photo_idx ON tag_revisions (image_metas_id);
+
+-- Reviewer decisions, kept for per tagger statistics
+CREATE TABLE IF NOT EXISTS photo_reviews (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    reviewer_id INT,
+    tagger_id INT,
+    decision TEXT NOT NULL,
+    comment TEXT NOT NULL DEFAULT '',
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (reviewer_id) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (tagger_id) REFERENCES users (id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS photo_reviews_album_idx ON photo_reviews (album_id);
 
+CREATE TABLE IF NOT EXISTS photo_comments (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    parent_id INT,
+    users_id INT,
+    body TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_id) REFERENCES photo_comments (id) ON DELETE CASCADE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS photo_comments_photo_idx ON photo_comments (image_metas_id);
+
+-- Problems reported by annotators, an open flag keeps the photo out of the tagging queue
+CREATE TABLE IF NOT EXISTS photo_flags (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    users_id INT,
+    flag_type TEXT NOT NULL,
+    note TEXT NOT NULL DEFAULT '',
+    resolved BOOL NOT NULL DEFAULT FALSE,
+    resolved_by INT,
+    resolved_at BIGINT,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE SET NULL,
+    FOREIGN KEY (resolved_by) REFERENCES users (id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS photo_flags_album_idx ON photo_flags (album_id) WHERE resolved = false;
 
+-- Answers given on gold items, one per user and item
+CREATE TABLE IF NOT EXISTS gold_answers (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    users_id INT NOT NULL,
+    tag TEXT NOT NULL,
+    coordinates TEXT NOT NULL,
+    correct BOOL NOT NULL,
+    created_at BIGINT NOT NULL,
+    UNIQUE (image_metas_id, users_id),
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS gold_answers_album_idx ON gold_answers (album_id);
 
+-- Labels proposed by the inference server for a photo
+CREATE TABLE IF NOT EXISTS photo_suggestions (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    model TEXT NOT NULL DEFAULT '',
+    label TEXT NOT NULL,
+    coordinates TEXT NOT NULL DEFAULT '',
+    confidence REAL NOT NULL,
+    accepted BOOL NOT NULL DEFAULT FALSE,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS photo_suggestions_photo_idx ON photo_suggestions (image_metas_id);
 
+-- Every model score ever uploaded, image_metas.score holds the latest one
+CREATE TABLE IF NOT EXISTS photo_scores (
+    id BIGSERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    model TEXT NOT NULL DEFAULT '',
+    score REAL NOT NULL,
+    -- whether the photo was verified when the score was uploaded
+    verified BOOL NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS photo_scores_album_idx ON photo_scores (album_id, created_at);
+CREATE INDEX IF NOT EXISTS photo_scores_photo_idx ON photo_scores (image_metas_id);
 
+-- uploads that were not stored because the album already had the same file
+CREATE TABLE IF NOT EXISTS duplicate_uploads (
+    id SERIAL PRIMARY KEY,
+    image_metas_id INT NOT NULL,
+    album_id INT NOT NULL,
+    users_id INT,
+    original_filename TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (image_metas_id) REFERENCES image_metas (id) ON DELETE CASCADE,
+    FOREIGN KEY (album_id) REFERENCES albums (id) ON DELETE CASCADE,
+    FOREIGN KEY (users_id) REFERENCES users (id) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS duplicate_uploads_photo_idx ON duplicate_uploads (image_metas_id);
@@ -0,0 +1,506 @@
+use crate::config::{Mail, Registration};
+use crate::errors::HandlerError;
+use crate::mailer::Mailer;
+use crate::my_identity_service::Identity;
+use crate::password::PasswordSettings;
+use crate::user_models::{
+    CreateUser, ForgotPassword, PendingUser, RegisterUser, ResetPassword, TokenQuery,
+    UpdateUserEmail, User, ROLES, STATUS_ACTIVE, STATUS_PENDING, TOKEN_EMAIL_VERIFICATION,
+    TOKEN_PASSWORD_RESET,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use deadpool_postgres::Pool;
+use log::{error, info};
+use std::sync::Arc;
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+use crate::errors;
+use crate::utils;
+
+// Mailers block on network or file IO
+async fn send_mail(
+    mailer: &Arc<dyn Mailer>,
+    to: String,
+    subject: String,
+    body: String,
+) -> Result<(), HandlerError> {
+    let mailer = mailer.clone();
+    match web::block(move || mailer.send(&to, &subject, &body)).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+fn public_link(conf: &Mail, path: &str, token: &str) -> String {
+    format!(
+        "{}{}?token={}",
+        conf.public_url.trim_end_matches('/'),
+        path,
+        token
+    )
+}
+
+pub async fn send_email_verification(
+    client: &deadpool_postgres::Client,
+    mailer: &Arc<dyn Mailer>,
+    conf: &Mail,
+    user: &User,
+    email: &str,
+) -> Result<(), HandlerError> {
+    let token = match db::create_user_token(
+        client,
+        user.id,
+        TOKEN_EMAIL_VERIFICATION,
+        conf.verification_token_ttl,
+    )
+    .await
+    {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let body = format!(
+        "Hello {},\n\nplease confirm your email address by opening this link:\n\n{}\n",
+        user.nickname,
+        public_link(conf, "/api/email/verify", &token)
+    );
+    send_mail(
+        mailer,
+        email.to_string(),
+        "Confirm your email address".to_string(),
+        body,
+    )
+    .await
+}
+
+async fn send_password_reset(pool: Pool, mailer: Arc<dyn Mailer>, conf: Mail, email: String) {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return;
+        }
+    };
+
+    let user = match db::get_user_by_email(&client, &email).await {
+        Ok(Some(i)) => i,
+        Ok(None) => {
+            info!("Password reset requested for unknown address");
+            return;
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return;
+        }
+    };
+
+    let token =
+        match db::create_user_token(&client, user.id, TOKEN_PASSWORD_RESET, conf.reset_token_ttl)
+            .await
+        {
+            Ok(i) => i,
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return;
+            }
+        };
+
+    let body = format!(
+        "Hello {},\n\nsomeone requested a new password for your account {}. \
+         Open this link to choose a new password, it is valid for {} minutes:\n\n{}\n\n\
+         If this was not you, ignore this mail.\n",
+        user.nickname,
+        user.username,
+        conf.reset_token_ttl / 60,
+        public_link(&conf, "/reset_password", &token)
+    );
+    if let Err(e) = send_mail(&mailer, email, "Reset your password".to_string(), body).await {
+        error!(
+            "Sending password reset mail to user {} failed: {}",
+            user.id, e
+        );
+    }
+}
+
+// Always answers OK right away, the lookup and the mail run in the background so neither
+// the response nor its timing reveals which addresses have an account
+pub async fn forgot_password(
+    pool: web::Data<Pool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    conf: web::Data<Mail>,
+    data: web::Json<ForgotPassword>,
+) -> Result<HttpResponse, HandlerError> {
+    actix_rt::spawn(send_password_reset(
+        pool.get_ref().clone(),
+        mailer.get_ref().clone(),
+        conf.get_ref().clone(),
+        data.email.trim().to_string(),
+    ));
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+pub async fn reset_password(
+    pool: web::Data<Pool>,
+    data: web::Json<ResetPassword>,
+    passwords: web::Data<PasswordSettings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user_id = match db::find_user_token(&client, &data.token, TOKEN_PASSWORD_RESET).await {
+        Ok(Some(i)) => i,
+        Ok(None) => {
+            return Err(HandlerError::BadClientData {
+                field: "Invalid or expired token".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let user = match db::get_user(&client, user_id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    if let Err(field) = passwords.check(&data.password, &user.username) {
+        return Err(HandlerError::BadClientData { field });
+    }
+    // Checked above without spending the token, so a rejected password can be retried
+    match db::consume_user_token(&client, &data.token, TOKEN_PASSWORD_RESET).await {
+        Ok(Some(i)) if i == user_id => {}
+        Ok(_) => {
+            return Err(HandlerError::BadClientData {
+                field: "Invalid or expired token".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    }
+    let new_user = User {
+        password: data.password.clone(),
+        ..user
+    };
+    if let Err(e) = db::update_user_password(&client, &new_user, &passwords).await {
+        return match e {
+            errors::DBError::BadArgs { err } => Err(HandlerError::BadClientData { field: err }),
+            e => {
+                error!("Error occured: {}", e);
+                Err(HandlerError::InternalError)
+            }
+        };
+    }
+
+    // Every reset token of the user is spent, also the ones from earlier requests
+    if let Err(e) = db::invalidate_user_tokens(&client, user_id, TOKEN_PASSWORD_RESET).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    let attempt_keys = vec![db::account_attempt_key(&new_user.username)];
+    if let Err(e) = db::reset_login_attempts(&client, &attempt_keys).await {
+        error!("Resetting login attempts failed: {}", e);
+    }
+    info!("User {} reset the password", new_user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&new_user),
+        AuditEvent::new("user.password_reset", "user", new_user.id),
+    )
+    .await;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+// Target of the link in the verification mail
+pub async fn verify_email(
+    pool: web::Data<Pool>,
+    query: web::Query<TokenQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user_id =
+        match db::consume_user_token(&client, &query.token, TOKEN_EMAIL_VERIFICATION).await {
+            Ok(Some(i)) => i,
+            Ok(None) => {
+                return Err(HandlerError::BadClientData {
+                    field: "Invalid or expired token".to_string(),
+                })
+            }
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        };
+
+    if let Err(e) = db::set_email_verified(&client, user_id).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    if let Err(e) = db::invalidate_user_tokens(&client, user_id, TOKEN_EMAIL_VERIFICATION).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    info!("User {} verified the email address", user_id);
+    audit::record(
+        &client,
+        &req,
+        None,
+        AuditEvent::new("user.email_verify", "user", user_id),
+    )
+    .await;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+// Changing the address needs a new verification
+pub async fn update_email(
+    pool: web::Data<Pool>,
+    id: Identity,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    conf: web::Data<Mail>,
+    data: web::Json<UpdateUserEmail>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+    let email = data.email.trim();
+    if !utils::is_valid_email(email) {
+        return Err(HandlerError::BadClientData {
+            field: "Invalid email address".to_string(),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_user_by_email(&client, email).await {
+        Ok(Some(other)) if other.id != user.id => {
+            return Err(HandlerError::BadClientData {
+                field: "Email address is already in use".to_string(),
+            })
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    }
+
+    let before = match db::get_user_email(&client, user.id).await {
+        Ok((email, _)) => email,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    if let Err(e) = db::set_user_email(&client, user.id, email).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.email_change", "user", user.id)
+            .before(&serde_json::json!({ "email": before }))
+            .after(&serde_json::json!({ "email": email })),
+    )
+    .await;
+    if let Err(e) = db::invalidate_user_tokens(&client, user.id, TOKEN_EMAIL_VERIFICATION).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    send_email_verification(&client, &mailer, &conf, &user, email).await?;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+// Public sign up, what it does depends on the registration policy
+pub async fn register(
+    pool: web::Data<Pool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    mail_conf: web::Data<Mail>,
+    registration: web::Data<Registration>,
+    passwords: web::Data<PasswordSettings>,
+    data: web::Json<RegisterUser>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let status = match registration.policy.as_str() {
+        "open" | "invite" => STATUS_ACTIVE,
+        "approval" => STATUS_PENDING,
+        _ => {
+            return Err(HandlerError::PermissionDenied {
+                err_message: "Registration is closed".to_string(),
+            })
+        }
+    };
+
+    if let Err(field) = passwords.check(&data.password, &data.username) {
+        return Err(HandlerError::BadClientData { field });
+    }
+
+    let email = data.email.as_ref().map(|e| e.trim().to_string());
+    match &email {
+        Some(email) if !utils::is_valid_email(email) => {
+            return Err(HandlerError::BadClientData {
+                field: "Invalid email address".to_string(),
+            })
+        }
+        None if mail_conf.require_email_verification => {
+            return Err(HandlerError::BadClientData {
+                field: "Email address is required".to_string(),
+            })
+        }
+        _ => {}
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::check_username_exists(&client, &data.username).await {
+        Ok(true) => {
+            return Err(HandlerError::BadClientData {
+                field: "Username is already taken".to_string(),
+            })
+        }
+        Ok(false) => {}
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    }
+    if let Some(email) = &email {
+        match db::get_user_by_email(&client, email).await {
+            Ok(Some(_)) => {
+                return Err(HandlerError::BadClientData {
+                    field: "Email address is already in use".to_string(),
+                })
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        }
+    }
+
+    let invite_code = if registration.policy == "invite" {
+        let code = match &data.invite_code {
+            Some(i) => i.trim(),
+            None => {
+                return Err(HandlerError::BadClientData {
+                    field: "Invite code is required".to_string(),
+                })
+            }
+        };
+        match db::claim_invite_code(&client, code).await {
+            Ok(true) => Some(code),
+            Ok(false) => {
+                return Err(HandlerError::BadClientData {
+                    field: "Invalid or expired invite code".to_string(),
+                })
+            }
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        }
+    } else {
+        None
+    };
+
+    let new_user = CreateUser {
+        username: data.username.clone(),
+        password: data.password.clone(),
+        nickname: data.nickname.clone(),
+        role: ROLES[1].to_string(),
+        email: email.clone(),
+    };
+    let user = match db::create_user_with_status(&client, &new_user, status, &passwords).await {
+        Ok(i) => i,
+        Err(e) => {
+            if let Some(code) = invite_code {
+                if let Err(e) = db::release_invite_code(&client, code).await {
+                    error!("Releasing invite code failed: {}", e);
+                }
+            }
+            return match e {
+                errors::DBError::BadArgs { err } => Err(HandlerError::BadClientData { field: err }),
+                e => {
+                    error!("Error occured: {}", e);
+                    Err(HandlerError::InternalError)
+                }
+            };
+        }
+    };
+    if let Some(code) = invite_code {
+        if let Err(e) = db::set_invite_code_user(&client, code, user.id).await {
+            error!("Error occured: {}", e);
+        }
+    }
+    info!("User {} registered with status {}", user.username, status);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.register", "user", user.id).after(&serde_json::json!({
+            "username": user.username,
+            "nickname": user.nickname,
+            "email": email,
+            "status": status,
+        })),
+    )
+    .await;
+
+    if let Some(email) = &email {
+        if mail_conf.require_email_verification {
+            send_email_verification(&client, &mailer, &mail_conf, &user, email).await?;
+        }
+    }
+
+    Ok(HttpResponse::build(StatusCode::OK).json(PendingUser {
+        id: user.id,
+        username: user.username,
+        nickname: user.nickname,
+        email,
+        status: status.to_string(),
+    }))
+}
@@ -1,20 +1,50 @@
+use crate::account_handlers::send_email_verification;
+use crate::album_models::{DuplicateQuery, PhotoQuery};
+use crate::audit_models::AuditQuery;
+use crate::config::{Mail, Registration};
 use crate::errors::HandlerError;
-use crate::user_models::{CreateUser, UpdateUserAdmin, User};
+use crate::mailer::Mailer;
+use crate::my_identity_service::Identity;
+use crate::password::PasswordSettings;
+use crate::user_models::{CreateUser, SendUser, UpdateUserAdmin, User};
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 use deadpool_postgres::Pool;
 use log::{error, info};
+use serde_json::json;
+use std::sync::Arc;
 
+use crate::audit::{self, AuditEvent};
 use crate::db;
 use crate::gg_storage;
+use crate::image_info;
+use crate::renditions;
+use crate::storage;
+use crate::transforms::{self, TransformCache};
+use crate::utils;
 
-use bytes::Bytes;
 use std::fs;
 
 pub async fn create_user(
     pool: web::Data<Pool>,
     data: web::Json<CreateUser>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    mail_conf: web::Data<Mail>,
+    passwords: web::Data<PasswordSettings>,
+    admin: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
+    if let Err(field) = passwords.check(&data.password, &data.username) {
+        return Err(HandlerError::BadClientData { field });
+    }
+    if let Some(email) = &data.email {
+        if !utils::is_valid_email(email) {
+            return Err(HandlerError::BadClientData {
+                field: "Invalid email address".to_string(),
+            });
+        }
+    }
+
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -23,13 +53,31 @@ pub async fn create_user(
         }
     };
 
-    let result = match db::create_user(&client, &data).await {
+    let result = match db::create_user(&client, &data, &passwords).await {
         Err(e) => {
             error!("Error occured: {}", e);
             return Err(HandlerError::InternalError);
         }
         Ok(item) => item,
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&admin.identity()),
+        AuditEvent::new("user.create", "user", result.id).after(&json!({
+            "username": result.username,
+            "nickname": result.nickname,
+            "role": result.role,
+            "email": data.email,
+        })),
+    )
+    .await;
+
+    if let Some(email) = &data.email {
+        if mail_conf.require_email_verification {
+            send_email_verification(&client, &mailer, &mail_conf, &result, email).await?;
+        }
+    }
 
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
@@ -38,6 +86,9 @@ pub async fn update_user(
     pool: web::Data<Pool>,
     id: web::Path<(i32,)>,
     data: web::Json<UpdateUserAdmin>,
+    passwords: web::Data<PasswordSettings>,
+    admin: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -57,27 +108,90 @@ pub async fn update_user(
         }
     };
 
+    let before = json!({ "nickname": user.nickname, "role": user.role });
     let new_user = User {
         nickname: data.nickname.clone(),
         password: data.password.clone(),
         role: data.role.clone(),
         ..user
     };
+    if let Err(field) = passwords.check(&new_user.password, &new_user.username) {
+        return Err(HandlerError::BadClientData { field });
+    }
 
-    let result = match db::update_user(&client, &new_user).await {
+    let result = match db::update_user(&client, &new_user, &passwords).await {
         Err(e) => {
             error!("Error occured: {}", e);
             return Err(HandlerError::InternalError);
         }
         Ok(item) => item,
     };
+    // The password itself is never written to the audit log
+    audit::record(
+        &client,
+        &req,
+        Some(&admin.identity()),
+        AuditEvent::new("user.update", "user", result.id)
+            .before(&before)
+            .after(&json!({
+                "nickname": result.nickname,
+                "role": result.role,
+                "password_changed": true,
+            })),
+    )
+    .await;
 
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
 
+// Lift the failed login lock of an account
+pub async fn unlock_user(
+    pool: web::Data<Pool>,
+    id: web::Path<(i32,)>,
+    admin: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user = match db::get_user(&client, id.0).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::BadClientData {
+                field: "User id does not exist".to_owned(),
+            });
+        }
+    };
+
+    match db::reset_login_attempts(&client, &[db::account_attempt_key(&user.username)]).await {
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+        Ok(_) => info!("Unlocked account of user {}", user.username),
+    };
+    audit::record(
+        &client,
+        &req,
+        Some(&admin.identity()),
+        AuditEvent::new("user.unlock", "user", user.id),
+    )
+    .await;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
 pub async fn delete_user(
     pool: web::Data<Pool>,
     data: web::Path<(i32,)>,
+    admin: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -96,7 +210,19 @@ pub async fn delete_user(
                 field: "User id does not exist".to_owned(),
             });
         }
-        Ok(_res) => {}
+        Ok(res) => {
+            audit::record(
+                &client,
+                &req,
+                Some(&admin.identity()),
+                AuditEvent::new("user.delete", "user", res.id).before(&json!({
+                    "username": res.username,
+                    "nickname": res.nickname,
+                    "role": res.role,
+                })),
+            )
+            .await;
+        }
     };
 
     Ok(HttpResponse::new(StatusCode::OK))
@@ -107,7 +233,10 @@ pub async fn get_photo(
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
     parameters: web::Path<(i32, i32)>,
+    query: web::Query<PhotoQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
+    let size = renditions::check_size(&query.size)?;
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -179,56 +308,32 @@ pub async fn get_photo(
         });
     }
 
-    let filepath = format!("{}{}", album_path, file_path_db);
-    let vec: Vec<&str> = file_path_db.split(".").collect();
-    let file_ext: &str = vec[1];
-    // Get image
-    if gg_storage_data.google_storage_enable {
-        let bytes = gg_storage::download_object_bytes_from_bucket(
-            &client_r,
-            &bearer_string,
-            &bucket_name,
-            &file_path_db,
-        )
-        .await;
-        let mut _bb = Bytes::new();
-        match bytes {
-            Err(e) => {
-                error!("Error downloading object from google storage {:?}", &e);
-                return Err(HandlerError::InternalError);
-            }
-            Ok(b) => {
-                _bb = b;
-            }
-        };
-        Ok(HttpResponse::build(StatusCode::OK)
-            .content_type(format!("image/{}", file_ext))
-            .body(_bb))
-    } else {
-        // Check file exist
-        if !std::path::Path::new(&filepath).exists() {
+    let store = storage::Store::new(
+        gg_storage_data.google_storage_enable,
+        &client_r,
+        &bearer_string,
+        &bucket_name,
+        &album_path,
+    );
+    if let Some(size) = size {
+        let rendition = renditions::rendition_name(&file_path_db, size);
+        if let Some(response) = store.serve(&req, &rendition, "image/jpeg").await? {
+            return Ok(response);
+        }
+    }
+
+    let content_type = image_info::content_type(&client, image_id, &file_path_db).await;
+    match store.serve(&req, &file_path_db, &content_type).await? {
+        Some(response) => Ok(response),
+        None => {
             error!(
-                "Error occured : Image file with id={} not found on disk",
-                &filepath
+                "Error occured : Image file {} of album id={} not found in storage",
+                &file_path_db, &album_id
             );
-            return Err(HandlerError::BadClientData {
-                field: format!("File {} not found on disk", filepath).to_string(),
-            });
+            Err(HandlerError::BadClientData {
+                field: format!("File {} not found in storage", file_path_db),
+            })
         }
-
-        let mut _bb: Vec<u8> = Vec::new();
-        match std::fs::read(filepath) {
-            Err(e) => {
-                error!("Error openning local file {:?}", &e);
-                return Err(HandlerError::InternalError);
-            }
-            Ok(bytes) => {
-                _bb = bytes;
-            }
-        };
-        Ok(HttpResponse::build(StatusCode::OK)
-            .content_type(format!("image/{}", file_ext))
-            .body(_bb))
     }
 }
 
@@ -236,7 +341,10 @@ pub async fn delete_photo(
     pool: web::Data<Pool>,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    transform_cache: web::Data<TransformCache>,
     parameters: web::Path<(i32, i32)>,
+    admin: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -307,6 +415,7 @@ pub async fn delete_photo(
             field: "Album not found".to_string(),
         });
     }
+    transforms::purge(&transform_cache, image_id).await;
 
     // Delete file from storage
     if gg_storage_data.google_storage_enable {
@@ -359,6 +468,16 @@ pub async fn delete_photo(
             return Err(HandlerError::InternalError);
         }
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&admin.identity()),
+        AuditEvent::new("photo.delete", "photo", image_id).before(&json!({
+            "album_id": album_id,
+            "file_path": file_path_db,
+        })),
+    )
+    .await;
 
     Ok(HttpResponse::build(StatusCode::OK).json(format!("Success delete image id={}", &image_id)))
 }
@@ -384,3 +503,274 @@ pub async fn get_all_users(pool: web::Data<Pool>) -> Result<HttpResponse, Handle
 
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
+
+// Self registered accounts waiting for approval
+pub async fn get_pending_users(pool: web::Data<Pool>) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let result = match db::get_pending_users(&client).await {
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+        Ok(item) => item,
+    };
+
+    Ok(HttpResponse::build(StatusCode::OK).json(result))
+}
+
+pub async fn approve_user(
+    pool: web::Data<Pool>,
+    id: web::Path<(i32,)>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    admin: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user = match db::approve_user(&client, id.0).await {
+        Ok(Some(i)) => i,
+        Ok(None) => {
+            return Err(HandlerError::BadClientData {
+                field: "No pending user with this id".to_owned(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    info!("Approved registration of {}", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&admin.identity()),
+        AuditEvent::new("user.approve", "user", user.id),
+    )
+    .await;
+
+    // The user is told about the approval if we know where to
+    match db::get_user_email(&client, user.id).await {
+        Ok((Some(email), _)) => {
+            let mailer = mailer.get_ref().clone();
+            let body = format!(
+                "Hello {},\n\nyour account {} was approved, you can log in now.\n",
+                user.nickname, user.username
+            );
+            if let Err(e) =
+                web::block(move || mailer.send(&email, "Your account was approved", &body)).await
+            {
+                error!("Sending approval mail failed: {}", e);
+            }
+        }
+        Ok((None, _)) => {}
+        Err(e) => error!("Error occured: {}", e),
+    }
+
+    Ok(HttpResponse::build(StatusCode::OK).json(SendUser {
+        id: user.id,
+        username: user.username,
+        nickname: user.nickname,
+        role: user.role,
+    }))
+}
+
+pub async fn reject_user(
+    pool: web::Data<Pool>,
+    id: web::Path<(i32,)>,
+    admin: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::reject_user(&client, id.0).await {
+        Ok(0) => Err(HandlerError::BadClientData {
+            field: "No pending user with this id".to_owned(),
+        }),
+        Ok(_) => {
+            info!("Rejected registration of user {}", id.0);
+            audit::record(
+                &client,
+                &req,
+                Some(&admin.identity()),
+                AuditEvent::new("user.reject", "user", id.0),
+            )
+            .await;
+            Ok(HttpResponse::new(StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn get_invite_codes(pool: web::Data<Pool>) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let result = match db::get_invite_codes(&client).await {
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+        Ok(item) => item,
+    };
+
+    Ok(HttpResponse::build(StatusCode::OK).json(result))
+}
+
+// Single use code for the "invite" registration policy
+pub async fn create_invite_code(
+    pool: web::Data<Pool>,
+    id: Identity,
+    registration: web::Data<Registration>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let result = match db::create_invite_code(&client, user.id, registration.invite_ttl).await {
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+        Ok(item) => item,
+    };
+    // Only a prefix is logged, the audit log must not hand out usable codes
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("invite.create", "invite", &result.code[..8]),
+    )
+    .await;
+
+    Ok(HttpResponse::build(StatusCode::OK).json(result))
+}
+
+pub async fn delete_invite_code(
+    pool: web::Data<Pool>,
+    code: web::Path<(String,)>,
+    admin: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::delete_invite_code(&client, &code.0).await {
+        Ok(0) => Err(HandlerError::BadClientData {
+            field: "Invite code does not exist".to_owned(),
+        }),
+        Ok(_) => {
+            let prefix: String = code.0.chars().take(8).collect();
+            audit::record(
+                &client,
+                &req,
+                Some(&admin.identity()),
+                AuditEvent::new("invite.delete", "invite", prefix),
+            )
+            .await;
+            Ok(HttpResponse::new(StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+const AUDIT_PAGE_SIZE: i64 = 50;
+const AUDIT_MAX_PAGE_SIZE: i64 = 500;
+
+// get api/admin/audit -> newest entries first, filtered by the query parameters
+pub async fn get_audit_log(
+    pool: web::Data<Pool>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, HandlerError> {
+    let page = query.page.unwrap_or(0);
+    let per_page = query.per_page.unwrap_or(AUDIT_PAGE_SIZE);
+    if page < 0 || per_page < 1 || per_page > AUDIT_MAX_PAGE_SIZE {
+        return Err(HandlerError::BadClientData {
+            field: format!(
+                "page must be positive and per_page between 1 and {}",
+                AUDIT_MAX_PAGE_SIZE
+            ),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let result = match db::get_audit_log(&client, &query, page, per_page).await {
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+        Ok(item) => item,
+    };
+
+    Ok(HttpResponse::build(StatusCode::OK).json(result))
+}
+
+// get api/admin/duplicates -> files stored more than once, largest spread over albums first
+pub async fn get_duplicates(
+    pool: web::Data<Pool>,
+    query: web::Query<DuplicateQuery>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_duplicate_report(&client, query.across_albums.unwrap_or(false)).await {
+        Ok(groups) => Ok(HttpResponse::build(StatusCode::OK).json(groups)),
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
@@ -1,18 +1,28 @@
-use crate::album_models::{AlbumsPreview, CreateAlbum, TagPhoto, UpdateAlbum, VerifyPhoto};
+use crate::album_models::{
+    AlbumsPreview, CreateAlbum, MarkGold, NearDuplicateQuery, PhotoTagState, RevertTag,
+    ReviewPhoto, SetPriorities, TagPhoto, TaggingQuery, UpdateAlbum, UserAccuracy, VerifyPhoto,
+    DUPLICATE_POLICIES, QUEUE_STRATEGIES, REVIEW_ACCEPT, REVIEW_REJECT,
+};
+use crate::duplicates;
 use crate::gg_storage;
+use crate::signed_urls::UrlSigner;
 use crate::user_models::User;
 extern crate reqwest;
 
 use crate::errors::{DBError, HandlerError};
 use crate::my_identity_service::Identity;
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 use deadpool_postgres::Pool;
 use log::{error, info};
 use std::fs;
 
+use crate::audit::{self, AuditEvent};
 use crate::db;
 
+const DEFAULT_TAGGING_BATCH: i64 = 20;
+const MAX_TAGGING_BATCH: i64 = 100;
+
 //json file with album id and tags
 pub async fn json_file(
     pool: web::Data<Pool>,
@@ -46,6 +56,7 @@ pub async fn create_album(
     id: Identity,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let user: User = id.identity();
 
@@ -120,6 +131,13 @@ pub async fn create_album(
             }
         }
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("album.create", "album", result.id).after(&result),
+    )
+    .await;
 
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
@@ -225,6 +243,7 @@ pub async fn get_photos_from_album(
         }
     };
 
+    // Public route, photo files stay behind a session so there are no signed urls here
     let result = match db::get_photos_from_album(&client, &data.0, &data.1).await {
         Err(e) => {
             error!("Error occured : {}", e);
@@ -240,12 +259,107 @@ pub async fn get_photos_from_album(
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
 
+// dimensions, format and EXIF fields recorded on upload
+pub async fn get_photo_metadata(
+    pool: web::Data<Pool>,
+    data: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    if db::get_image_file_path_with_id_from_album(&client, &data.0, &data.1).await == "" {
+        return Err(HandlerError::BadClientData {
+            field: format!("Image with id={} not found in album {}", data.1, data.0),
+        });
+    }
+    match db::get_photo_metadata(&client, data.1).await {
+        Ok(Some(metadata)) => Ok(HttpResponse::build(StatusCode::OK).json(metadata)),
+        Ok(None) => Err(HandlerError::BadClientData {
+            field: format!("Image with id={} not found in album {}", data.1, data.0),
+        }),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// Largest accepted max_distance, beyond it unrelated photos start to cluster
+const MAX_NEAR_DUPLICATE_DISTANCE: u32 = 12;
+
+// clusters of visually similar photos of the album, for its owner to review and delete
+pub async fn get_near_duplicates(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    query: web::Query<NearDuplicateQuery>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let max_distance = query.max_distance.unwrap_or(6);
+    if max_distance > MAX_NEAR_DUPLICATE_DISTANCE {
+        return Err(HandlerError::BadClientData {
+            field: format!(
+                "max_distance must be at most {}",
+                MAX_NEAR_DUPLICATE_DISTANCE
+            ),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, album_id.0).await?;
+
+    let owner_id = if user.role == "admin" {
+        None
+    } else {
+        Some(user.id)
+    };
+    let across_albums = query.across_albums.unwrap_or(false);
+    let photos = match db::get_perceptual_hashes(&client, album_id.0, across_albums, owner_id).await
+    {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let album = album_id.0;
+    let clusters = match web::block(move || -> Result<Vec<_>, ()> {
+        Ok(duplicates::cluster(photos, max_distance)
+            .into_iter()
+            .filter(|c| c.photos.iter().any(|p| p.album_id == album))
+            .collect())
+    })
+    .await
+    {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    Ok(HttpResponse::build(StatusCode::OK).json(clusters))
+}
+
 pub async fn delete_album_by_id(
     pool: web::Data<Pool>,
     album_id: web::Path<(i32,)>,
     id: Identity,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let user: User = id.identity();
 
@@ -326,6 +440,13 @@ pub async fn delete_album_by_id(
                 }
             }
         };
+        audit::record(
+            &client,
+            &req,
+            Some(&user),
+            AuditEvent::new("album.delete", "album", result.id).before(&result),
+        )
+        .await;
     } else {
         return Err(HandlerError::PermissionDenied {
             err_message: "You are not the owner of this album".to_string(),
@@ -339,9 +460,38 @@ pub async fn update_album_by_id(
     album_id: web::Path<(i32,)>,
     id: Identity,
     data: web::Json<UpdateAlbum>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let user: User = id.identity();
 
+    if let Some(rate) = data.gold_rate {
+        if !(0.0..=0.5).contains(&rate) {
+            return Err(HandlerError::BadClientData {
+                field: "Gold rate must be between 0 and 0.5".to_string(),
+            });
+        }
+    }
+    if let Some(strategy) = &data.queue_strategy {
+        if !QUEUE_STRATEGIES.contains(&strategy.as_str()) {
+            return Err(HandlerError::BadClientData {
+                field: format!(
+                    "Queue strategy must be one of {}",
+                    QUEUE_STRATEGIES.join(", ")
+                ),
+            });
+        }
+    }
+    if let Some(policy) = &data.duplicate_policy {
+        if !DUPLICATE_POLICIES.contains(&policy.as_str()) {
+            return Err(HandlerError::BadClientData {
+                field: format!(
+                    "Duplicate policy must be one of {}",
+                    DUPLICATE_POLICIES.join(", ")
+                ),
+            });
+        }
+    }
+
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -362,7 +512,7 @@ pub async fn update_album_by_id(
     };
 
     if user.id == result.users_id || user.role == "admin" {
-        match db::update_album(&client, album_id.0, &data).await {
+        let updated = match db::update_album(&client, album_id.0, &data).await {
             Err(e) => {
                 error!("Error occured : {}", e);
                 return Err(HandlerError::BadClientData {
@@ -371,6 +521,15 @@ pub async fn update_album_by_id(
             }
             Ok(num_updated) => num_updated,
         };
+        audit::record(
+            &client,
+            &req,
+            Some(&user),
+            AuditEvent::new("album.update", "album", updated.id)
+                .before(&result)
+                .after(&updated),
+        )
+        .await;
     } else {
         return Err(HandlerError::PermissionDenied {
             err_message: "You are not the owner of this album".to_string(),
@@ -379,11 +538,32 @@ pub async fn update_album_by_id(
     Ok(HttpResponse::new(StatusCode::OK))
 }
 
+// Audit a tag change with the state of the photo before and after it
+async fn record_tag_change(
+    client: &deadpool_postgres::Client,
+    req: &HttpRequest,
+    user: &User,
+    action: &'static str,
+    before: PhotoTagState,
+) {
+    let event = AuditEvent::new(action, "photo", before.id).before(&before);
+    let event = match db::get_photo_tag_state(client, before.id).await {
+        Ok(after) => event.after(&after),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            event
+        }
+    };
+    audit::record(client, req, Some(user), event).await;
+}
+
 // tag photo + set coordinates
 pub async fn tag_photo_by_id(
     pool: web::Data<Pool>,
     data_id: web::Path<(i32,)>,
     data: web::Json<TagPhoto>,
+    id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -393,7 +573,39 @@ pub async fn tag_photo_by_id(
         }
     };
 
-    let is_success = match db::tag_photo_by_id(client, &data_id.0, &data).await {
+    tag_photo(&client, &req, &id.identity(), data_id.0, &data).await?;
+    Ok(HttpResponse::build(StatusCode::OK).finish())
+}
+
+async fn tag_photo(
+    client: &deadpool_postgres::Client,
+    req: &HttpRequest,
+    user: &User,
+    photo_id: i32,
+    data: &TagPhoto,
+) -> Result<(), HandlerError> {
+    let before = match db::get_photo_tag_state(client, photo_id).await {
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::BadClientData {
+                field: "Photo does not exists in the database.".to_string(),
+            });
+        }
+        Ok(item) => item,
+    };
+
+    // answers on gold items are only scored, the tagger gets the same response as usual
+    if before.is_gold {
+        return match db::score_gold_answer(client, before.id, user.id, data).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Error occured : {}", e);
+                Err(HandlerError::InternalError)
+            }
+        };
+    }
+
+    let is_success = match db::tag_photo_by_id(client, &photo_id, data, user.id).await {
         Err(e) => {
             error!("Error occured : {}", e);
             return Err(HandlerError::BadClientData {
@@ -404,18 +616,90 @@ pub async fn tag_photo_by_id(
     };
 
     if is_success {
-        Ok(HttpResponse::build(StatusCode::OK).finish())
+        record_tag_change(client, req, user, "photo.tag", before).await;
+        Ok(())
     } else {
         error!("Error occured : timeout");
         Err(HandlerError::Timeout)
     }
 }
 
+// labels the inference server proposed for a photo
+pub async fn get_suggestions(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_photo_suggestions(&client, &[photo_id.0]).await {
+        Ok(suggestions) => Ok(HttpResponse::build(StatusCode::OK).json(suggestions)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// tag the photo with a suggestion as if the tagger had drawn it
+pub async fn accept_suggestion(
+    pool: web::Data<Pool>,
+    suggestion_id: web::Path<(i32,)>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let suggestion = match db::get_photo_suggestion(&client, suggestion_id.0).await {
+        Ok(Some(item)) => item,
+        Ok(None) => {
+            return Err(HandlerError::BadClientData {
+                field: "Suggestion does not exist".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let data = TagPhoto {
+        tag: suggestion.label.clone(),
+        coordinates: suggestion.coordinates.clone(),
+    };
+    tag_photo(
+        &client,
+        &req,
+        &id.identity(),
+        suggestion.image_metas_id,
+        &data,
+    )
+    .await?;
+
+    if let Err(e) = db::set_suggestion_accepted(&client, suggestion.id).await {
+        error!("Error occured : {}", e);
+    }
+    Ok(HttpResponse::build(StatusCode::OK).finish())
+}
+
 // verify_photo
 pub async fn verify_photo_by_id(
     pool: web::Data<Pool>,
     data_id: web::Path<(i32,)>,
     data: web::Json<VerifyPhoto>,
+    id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -425,7 +709,18 @@ pub async fn verify_photo_by_id(
         }
     };
 
-    match db::verify_photo_by_id(client, &data_id.0, data.verified).await {
+    let user: User = id.identity();
+    let before = check_photo_owner(&client, &user, data_id.0).await?;
+
+    if !data.verified && data.comment.as_ref().map_or(true, |c| c.trim().is_empty()) {
+        return Err(HandlerError::BadClientData {
+            field: "A rejection needs a comment for the tagger".to_string(),
+        });
+    }
+
+    match db::verify_photo_by_id(&client, &data_id.0, data.verified, data.comment.clone(), user.id)
+        .await
+    {
         Err(e) => {
             error!("Error occured : {}", e);
             return Err(HandlerError::BadClientData {
@@ -433,7 +728,15 @@ pub async fn verify_photo_by_id(
             });
         }
         Ok(item) => match item {
-            true => return Ok(HttpResponse::build(StatusCode::OK).json(item)),
+            true => {
+                let action = if data.verified {
+                    "photo.verify"
+                } else {
+                    "photo.reject"
+                };
+                record_tag_change(&client, &req, &user, action, before).await;
+                return Ok(HttpResponse::build(StatusCode::OK).json(item));
+            }
             false => {
                 error!("Error occured : timeout");
                 return Err(HandlerError::BadClientData {
@@ -444,11 +747,334 @@ pub async fn verify_photo_by_id(
     };
 }
 
-// get next 20 photos for tagging
+// Only the album owner and admins may review photos or see the album's statistics
+pub async fn check_album_owner(
+    client: &deadpool_postgres::Client,
+    user: &User,
+    album_id: i32,
+) -> Result<(), HandlerError> {
+    let album = match db::get_album_by_id(client, album_id).await {
+        Err(e) => {
+            error!("Error occured get album: {}", e);
+            return Err(HandlerError::BadClientData {
+                field: e.to_string(),
+            });
+        }
+        Ok(item) => item,
+    };
+    if album.users_id == user.id || user.role == "admin" {
+        Ok(())
+    } else {
+        Err(HandlerError::PermissionDenied {
+            err_message: "You are not the owner of this album".to_string(),
+        })
+    }
+}
+
+async fn check_photo_owner(
+    client: &deadpool_postgres::Client,
+    user: &User,
+    photo_id: i32,
+) -> Result<PhotoTagState, HandlerError> {
+    let state = match db::get_photo_tag_state(client, photo_id).await {
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::BadClientData {
+                field: "Photo does not exists in the database.".to_string(),
+            });
+        }
+        Ok(item) => item,
+    };
+
+    check_album_owner(client, user, state.album_id).await?;
+    Ok(state)
+}
+
+// list all labels a photo had, newest first
+pub async fn get_tag_history(
+    pool: web::Data<Pool>,
+    data_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_photo_owner(&client, &id.identity(), data_id.0).await?;
+
+    match db::get_tag_revisions(&client, data_id.0).await {
+        Ok(revisions) => Ok(HttpResponse::build(StatusCode::OK).json(revisions)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// restore the label of an earlier revision
+pub async fn revert_tag(
+    pool: web::Data<Pool>,
+    data_id: web::Path<(i32,)>,
+    data: web::Json<RevertTag>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    let before = check_photo_owner(&client, &user, data_id.0).await?;
+
+    match db::revert_tag(&client, data_id.0, data.revision_id, user.id).await {
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+        Ok(false) => Err(HandlerError::BadClientData {
+            field: "Revision does not belong to this photo.".to_string(),
+        }),
+        Ok(true) => {
+            record_tag_change(&client, &req, &user, "photo.revert", before).await;
+            Ok(HttpResponse::build(StatusCode::OK).finish())
+        }
+    }
+}
+
+// accept, reject with a reason or correct a tagged photo
+pub async fn review_photo(
+    pool: web::Data<Pool>,
+    data_id: web::Path<(i32,)>,
+    data: web::Json<ReviewPhoto>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    let before = check_photo_owner(&client, &user, data_id.0).await?;
+
+    if data.decision == REVIEW_REJECT && data.comment.as_ref().map_or(true, |c| c.trim().is_empty())
+    {
+        return Err(HandlerError::BadClientData {
+            field: "A rejection needs a comment for the tagger".to_string(),
+        });
+    }
+
+    match db::review_photo(&client, data_id.0, user.id, &data).await {
+        Err(DBError::BadArgs { err }) => Err(HandlerError::BadClientData { field: err }),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+        Ok(()) => {
+            let action = match data.decision.as_str() {
+                REVIEW_ACCEPT => "photo.verify",
+                REVIEW_REJECT => "photo.reject",
+                _ => "photo.correct",
+            };
+            record_tag_change(&client, &req, &user, action, before).await;
+            Ok(HttpResponse::build(StatusCode::OK).finish())
+        }
+    }
+}
+
+// tagged photos of an album that wait for review
+pub async fn get_review_queue(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_review_queue(&client, album_id.0).await {
+        Ok(photos) => Ok(HttpResponse::build(StatusCode::OK).json(photos)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// review outcome per tagger of an album
+pub async fn get_tagger_stats(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_tagger_stats(&client, album_id.0).await {
+        Ok(stats) => Ok(HttpResponse::build(StatusCode::OK).json(stats)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// mark a verified photo as gold item or take it out of the gold set
+pub async fn set_gold(
+    pool: web::Data<Pool>,
+    data_id: web::Path<(i32,)>,
+    data: web::Json<MarkGold>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    let before = check_photo_owner(&client, &user, data_id.0).await?;
+
+    match db::set_gold(&client, data_id.0, data.gold).await {
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+        Ok(false) => Err(HandlerError::BadClientData {
+            field: "Only verified photos can be gold items".to_string(),
+        }),
+        Ok(true) => {
+            record_tag_change(&client, &req, &user, "photo.gold", before).await;
+            Ok(HttpResponse::build(StatusCode::OK).finish())
+        }
+    }
+}
+
+// weights for the priority queue strategy
+pub async fn set_priorities(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    data: web::Json<SetPriorities>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::set_photo_priorities(&client, album_id.0, &data.photos).await {
+        Ok(updated) => Ok(HttpResponse::build(StatusCode::OK).json(updated)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// accuracy of every user who answered gold items of the album
+pub async fn get_album_accuracy(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_gold_accuracy(&client, Some(album_id.0), None).await {
+        Ok(accuracy) => Ok(HttpResponse::build(StatusCode::OK).json(accuracy)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// own accuracy over all albums
+pub async fn get_own_accuracy(
+    pool: web::Data<Pool>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    match db::get_gold_accuracy(&client, None, Some(user.id)).await {
+        Ok(mut accuracy) => {
+            let accuracy = accuracy.pop().unwrap_or(UserAccuracy {
+                users_id: user.id,
+                username: Some(user.username),
+                answered: 0,
+                correct: 0,
+                accuracy: 0.0,
+            });
+            Ok(HttpResponse::build(StatusCode::OK).json(accuracy))
+        }
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// get next 20 photos for tagging, photos rejected in review come first for their tagger
 pub async fn get_photos_for_tagging(
     pool: web::Data<Pool>,
     data: web::Path<(i32,)>,
+    query: web::Query<TaggingQuery>,
+    id: Identity,
+    signer: web::Data<UrlSigner>,
 ) -> Result<HttpResponse, HandlerError> {
+    let batch_size = query.batch_size.unwrap_or(DEFAULT_TAGGING_BATCH);
+    if batch_size < 1 || batch_size > MAX_TAGGING_BATCH {
+        return Err(HandlerError::BadClientData {
+            field: format!("batch_size must be between 1 and {}", MAX_TAGGING_BATCH),
+        });
+    }
+
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -467,13 +1093,18 @@ pub async fn get_photos_for_tagging(
         Ok(item) => item,
     };
 
-    let result = match db::get_photos_for_tagging(client, &data.0).await {
-        Err(e) => {
-            error!("Error occured : {}", e);
-            return Err(HandlerError::InternalError);
-        }
-        Ok(item) => item,
-    };
+    let user: User = id.identity();
+    let mut result =
+        match db::get_photos_for_tagging(client, &data.0, user.id, batch_size as usize).await {
+            Err(e) => {
+                error!("Error occured : {}", e);
+                return Err(HandlerError::InternalError);
+            }
+            Ok(item) => item,
+        };
+    for photo in result.iter_mut() {
+        photo.url = signer.photo_url(data.0, &photo.file_path);
+    }
 
     Ok(HttpResponse::build(StatusCode::OK).json(result))
 }
@@ -15,6 +15,12 @@ pub struct Album {
     pub tagged_number: i32,
     pub users_id: i32,
     pub first_photo: Option<i32>,
+    // share of gold items mixed into each tagging batch
+    pub gold_rate: f32,
+    pub leaderboard_enabled: bool,
+    pub assignment_mode: bool,
+    pub queue_strategy: String,
+    pub duplicate_policy: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,6 +33,11 @@ pub struct CreateAlbum {
 pub struct UpdateAlbum {
     pub title: String,
     pub description: String,
+    pub gold_rate: Option<f32>,
+    pub leaderboard_enabled: Option<bool>,
+    pub assignment_mode: Option<bool>,
+    pub queue_strategy: Option<String>,
+    pub duplicate_policy: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -63,14 +74,43 @@ pub struct AlbumsPreview {
     pub albums: Vec<AlbumPreview>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
-#[pg_mapper(table = "image_metas")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PhotoPreview {
     pub id: i32,
     pub file_path: String,
     pub tag: String,
     pub verified: bool,
     pub tagged: bool,
+    pub metadata: PhotoDimensions,
+    // signed download url, always None as the preview is public
+    pub url: Option<String>,
+}
+
+// The part of PhotoMetadata shown on the public preview, EXIF fields can reveal where a photo was
+// taken and who uploaded it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "image_metas")]
+pub struct PhotoDimensions {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub mime_type: Option<String>,
+}
+
+// File properties and EXIF fields, all empty for photos uploaded before they were recorded
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "image_metas")]
+pub struct PhotoMetadata {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub mime_type: Option<String>,
+    pub byte_size: Option<i64>,
+    pub original_filename: Option<String>,
+    pub taken_at: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub orientation: Option<i32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -79,9 +119,353 @@ pub struct TagPhoto {
     pub coordinates: String,
 }
 
+// Tag related columns of a photo, recorded before and after tagging
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "image_metas")]
+pub struct PhotoTagState {
+    pub id: i32,
+    pub album_id: i32,
+    pub tag: String,
+    pub coordinates: String,
+    pub tagged: bool,
+    pub verified: bool,
+    pub is_gold: bool,
+}
+
+// One entry of the append-only label history of a photo
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "tag_revisions")]
+pub struct TagRevision {
+    pub id: i32,
+    pub image_metas_id: i32,
+    pub album_id: i32,
+    pub users_id: Option<i32>,
+    pub action: String,
+    pub tag: String,
+    pub coordinates: String,
+    pub tagged: bool,
+    pub verified: bool,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevertTag {
+    pub revision_id: i32,
+}
+
+// Values of tag_revisions.action
+pub const TAG_REVISION_TAG: &str = "tag";
+pub const TAG_REVISION_VERIFY: &str = "verify";
+pub const TAG_REVISION_REJECT: &str = "reject";
+pub const TAG_REVISION_REVERT: &str = "revert";
+pub const TAG_REVISION_CORRECT: &str = "correct";
+
+// Values of image_metas.review_status and photo_reviews.decision
+pub const REVIEW_PENDING: &str = "pending";
+pub const REVIEW_ACCEPT: &str = "accept";
+pub const REVIEW_REJECT: &str = "reject";
+pub const REVIEW_CORRECT: &str = "correct";
+
+// decision is one of accept, reject (comment required) or correct (tag and coordinates required)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReviewPhoto {
+    pub decision: String,
+    pub comment: Option<String>,
+    pub tag: Option<String>,
+    pub coordinates: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "image_metas")]
+pub struct PhotoToReview {
+    pub id: i32,
+    pub file_path: String,
+    pub tag: String,
+    pub coordinates: String,
+    pub tagged_by: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaggerStats {
+    pub tagger_id: i32,
+    pub username: Option<String>,
+    pub reviewed: i64,
+    pub accepted: i64,
+    pub rejected: i64,
+    pub corrected: i64,
+    pub rejection_rate: f64,
+}
+
+// Comment on a photo, parent_id points to the comment it answers
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "photo_comments")]
+pub struct PhotoComment {
+    pub id: i32,
+    pub image_metas_id: i32,
+    pub parent_id: Option<i32>,
+    pub users_id: Option<i32>,
+    pub username: Option<String>,
+    pub body: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateComment {
+    pub body: String,
+    pub parent_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "photo_flags")]
+pub struct PhotoFlag {
+    pub id: i32,
+    pub image_metas_id: i32,
+    pub album_id: i32,
+    pub users_id: Option<i32>,
+    pub flag_type: String,
+    pub note: String,
+    pub resolved: bool,
+    pub resolved_by: Option<i32>,
+    pub resolved_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateFlag {
+    pub flag_type: String,
+    pub note: Option<String>,
+}
+
+// TODO: Make this a Sql serializable enum
+pub const FLAG_TYPES: &'static [&'static str] = &["bad_image", "unsure", "duplicate", "nsfw"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkGold {
+    pub gold: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserAccuracy {
+    pub users_id: i32,
+    pub username: Option<String>,
+    pub answered: i64,
+    pub correct: i64,
+    pub accuracy: f64,
+}
+
+// Photos of the album that are in no other batch yet and match all given filters.
+// range_start and range_end are 1-based positions in upload order, both inclusive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateBatch {
+    pub name: String,
+    pub range_start: Option<i64>,
+    pub range_end: Option<i64>,
+    pub tagged: Option<bool>,
+    pub tag: Option<String>,
+    pub assigned_to: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssignBatch {
+    pub user_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskBatch {
+    pub id: i32,
+    pub album_id: i32,
+    pub name: String,
+    pub assigned_to: Option<i32>,
+    pub created_by: Option<i32>,
+    pub created_at: i64,
+    pub total: i64,
+    pub tagged: i64,
+    pub verified: i64,
+    pub completed: bool,
+}
+
+// What happens to an upload whose file is already in the album
+pub const DUPLICATES_REJECT: &str = "reject";
+// not stored again, the upload is recorded and answered with the existing photo
+pub const DUPLICATES_LINK: &str = "link";
+pub const DUPLICATES_ALLOW: &str = "allow";
+pub const DUPLICATE_POLICIES: &'static [&'static str] =
+    &[DUPLICATES_REJECT, DUPLICATES_LINK, DUPLICATES_ALLOW];
+
+// Answer of an upload per file, duplicate_of is set if the file was linked to an existing photo.
+// Files that were not stored have no id but the reason in rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadedPhoto {
+    pub id: Option<i32>,
+    pub original_filename: String,
+    pub duplicate_of: Option<i32>,
+    pub rejected: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateQuery {
+    // only files found in more than one album
+    pub across_albums: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicatePhoto {
+    pub id: i32,
+    pub album_id: i32,
+    pub album_title: String,
+    pub file_path: String,
+    pub original_filename: Option<String>,
+    pub linked_uploads: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub content_hash: String,
+    pub albums: i64,
+    pub photos: Vec<DuplicatePhoto>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NearDuplicateQuery {
+    // differing bits of the perceptual hashes
+    pub max_distance: Option<u32>,
+    // also compare with the other albums of the owner, or all albums for admins
+    pub across_albums: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NearDuplicatePhoto {
+    pub id: i32,
+    pub album_id: i32,
+    pub file_path: String,
+    pub original_filename: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    #[serde(skip)]
+    pub perceptual_hash: i64,
+    // to the first photo of the cluster
+    pub distance: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NearDuplicateCluster {
+    pub photos: Vec<NearDuplicatePhoto>,
+}
+
+// Order in which get_photos_for_tagging hands out photos
+pub const QUEUE_SEQUENTIAL: &str = "sequential";
+pub const QUEUE_RANDOM: &str = "random";
+pub const QUEUE_LEAST_RECENTLY_SERVED: &str = "least_recently_served";
+pub const QUEUE_PRIORITY: &str = "priority";
+pub const QUEUE_UNCERTAINTY: &str = "uncertainty";
+pub const QUEUE_STRATEGIES: &'static [&'static str] = &[
+    QUEUE_SEQUENTIAL,
+    QUEUE_RANDOM,
+    QUEUE_LEAST_RECENTLY_SERVED,
+    QUEUE_PRIORITY,
+    QUEUE_UNCERTAINTY,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaggingQuery {
+    pub batch_size: Option<i64>,
+}
+
+// size is "thumb", "medium" or "original"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhotoQuery {
+    pub size: Option<String>,
+}
+
+// crop is "x,y,width,height" in pixels of the upright photo, applied before rotate and resize
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformQuery {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // contain, cover or fill
+    pub fit: Option<String>,
+    pub crop: Option<String>,
+    // clockwise, multiple of 90
+    pub rotate: Option<u32>,
+    // jpeg, png or webp, defaults to the format of the photo
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    // turn the photo upright according to its EXIF orientation, default true
+    pub orient: Option<bool>,
+}
+
+// query of a signed url to a local photo file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedQuery {
+    pub expires: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhotoPriority {
+    pub id: i32,
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetPriorities {
+    pub photos: Vec<PhotoPriority>,
+}
+
+// A photo is matched by photo_id, or by file_path if no id is given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreEntry {
+    pub photo_id: Option<i32>,
+    pub file_path: Option<String>,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreUpload {
+    pub model: Option<String>,
+    pub kind: Option<String>,
+    pub scores: Vec<ScoreEntry>,
+}
+
+// Options of a score upload, model and kind are taken from here for CSV bodies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreQuery {
+    pub model: Option<String>,
+    /// "confidence" (default) or "uncertainty", uncertainties are stored as 1 - value
+    pub kind: Option<String>,
+    /// Switch the album to the uncertainty queue strategy, defaults to false
+    pub rerank: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "photo_scores")]
+pub struct PhotoScore {
+    pub id: i64,
+    pub image_metas_id: i32,
+    pub album_id: i32,
+    pub model: String,
+    pub score: f32,
+    pub verified: bool,
+    pub created_at: i64,
+}
+
+// Summary of one score upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreSnapshot {
+    pub created_at: i64,
+    pub model: String,
+    pub photos: i64,
+    pub verified: i64,
+    pub mean_score: f64,
+    pub mean_score_unverified: Option<f64>,
+    pub min_score: f32,
+}
+
+// A rejection needs a comment for the tagger, like a review
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VerifyPhoto {
     pub verified: bool,
+    pub comment: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -91,5 +475,25 @@ pub struct PhotoToTag {
     pub tagged: bool,
     pub tag: String,
     pub timestamp: DateTime<Utc>,
-    pub coordinates: String
+    pub coordinates: String,
+    // reviewer feedback if the photo was rejected and sent back to its tagger
+    pub review_comment: String,
+    pub suggestions: Vec<PhotoSuggestion>,
+    pub metadata: PhotoMetadata,
+    // signed download url, if enabled
+    pub url: Option<String>,
+}
+
+// Label proposed by the inference hook
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "photo_suggestions")]
+pub struct PhotoSuggestion {
+    pub id: i32,
+    pub image_metas_id: i32,
+    pub model: String,
+    pub label: String,
+    pub coordinates: String,
+    pub confidence: f32,
+    pub accepted: bool,
+    pub created_at: i64,
 }
@@ -0,0 +1,50 @@
+// Records who did what to which object in the audit_log table
+use crate::db;
+use crate::user_models::User;
+use crate::utils;
+use actix_web::HttpRequest;
+use log::error;
+use serde::Serialize;
+
+pub struct AuditEvent {
+    pub action: &'static str,
+    pub target_type: &'static str,
+    pub target_id: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+impl AuditEvent {
+    pub fn new<T: ToString>(action: &'static str, target_type: &'static str, target_id: T) -> Self {
+        AuditEvent {
+            action,
+            target_type,
+            target_id: Some(target_id.to_string()),
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn before<T: Serialize>(mut self, value: &T) -> Self {
+        self.before = serde_json::to_value(value).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, value: &T) -> Self {
+        self.after = serde_json::to_value(value).ok();
+        self
+    }
+}
+
+/// Writes the event. Failures are only logged, they never fail the audited request.
+pub async fn record(
+    client: &deadpool_postgres::Client,
+    req: &HttpRequest,
+    actor: Option<&User>,
+    event: AuditEvent,
+) {
+    let ip = utils::client_ip(req);
+    if let Err(e) = db::insert_audit_event(client, actor, &event, &ip).await {
+        error!("Writing audit log entry {} failed: {}", event.action, e);
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: Option<i32>,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: String,
+    pub created_at: i64,
+}
+
+// Filters of /api/admin/audit, all optional
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// Unix timestamps, inclusive
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditPage {
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub entries: Vec<AuditEntry>,
+}
@@ -0,0 +1,212 @@
+use crate::album_handlers::check_album_owner;
+use crate::album_models::{AssignBatch, CreateBatch};
+use crate::errors::HandlerError;
+use crate::my_identity_service::Identity;
+use crate::user_models::{User, STATUS_ACTIVE};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use deadpool_postgres::Pool;
+use log::error;
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+
+// Batches can only go to existing, active accounts
+async fn check_assignee(
+    client: &deadpool_postgres::Client,
+    user_id: Option<i32>,
+) -> Result<(), HandlerError> {
+    let user_id = match user_id {
+        Some(item) => item,
+        None => return Ok(()),
+    };
+    match db::get_user_status(client, user_id).await {
+        Ok(status) if status == STATUS_ACTIVE => Ok(()),
+        Ok(_) | Err(_) => Err(HandlerError::BadClientData {
+            field: "User does not exist".to_string(),
+        }),
+    }
+}
+
+pub async fn get_album_batches(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_task_batches(&client, Some(album_id.0), None, None).await {
+        Ok(batches) => Ok(HttpResponse::build(StatusCode::OK).json(batches)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn create_batch(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    data: web::Json<CreateBatch>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    if data.name.trim().is_empty() {
+        return Err(HandlerError::BadClientData {
+            field: "Batch name must not be empty".to_string(),
+        });
+    }
+    if let (Some(start), Some(end)) = (data.range_start, data.range_end) {
+        if start > end {
+            return Err(HandlerError::BadClientData {
+                field: "range_start must not be after range_end".to_string(),
+            });
+        }
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, album_id.0).await?;
+    check_assignee(&client, data.assigned_to).await?;
+
+    let batch = match db::create_task_batch(&client, album_id.0, user.id, &data).await {
+        Ok(Some(item)) => item,
+        Ok(None) => {
+            return Err(HandlerError::BadClientData {
+                field: "No unassigned photo matches the filter".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("batch.create", "batch", batch.id).after(&batch),
+    )
+    .await;
+    Ok(HttpResponse::build(StatusCode::CREATED).json(batch))
+}
+
+// hand a batch to another user, or take it back with user_id null
+pub async fn assign_batch(
+    pool: web::Data<Pool>,
+    path: web::Path<(i32, i32)>,
+    data: web::Json<AssignBatch>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let (album_id, batch_id) = (path.0, path.1);
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, album_id).await?;
+    check_assignee(&client, data.user_id).await?;
+
+    match db::assign_task_batch(&client, album_id, batch_id, data.user_id).await {
+        Ok(true) => {
+            audit::record(
+                &client,
+                &req,
+                Some(&user),
+                AuditEvent::new("batch.assign", "batch", batch_id).after(&*data),
+            )
+            .await;
+            Ok(HttpResponse::build(StatusCode::OK).finish())
+        }
+        Ok(false) => Err(HandlerError::BadClientData {
+            field: "Batch does not exist".to_string(),
+        }),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn delete_batch(
+    pool: web::Data<Pool>,
+    path: web::Path<(i32, i32)>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let (album_id, batch_id) = (path.0, path.1);
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, album_id).await?;
+
+    match db::delete_task_batch(&client, album_id, batch_id).await {
+        Ok(true) => {
+            audit::record(
+                &client,
+                &req,
+                Some(&user),
+                AuditEvent::new("batch.delete", "batch", batch_id),
+            )
+            .await;
+            Ok(HttpResponse::build(StatusCode::OK).finish())
+        }
+        Ok(false) => Err(HandlerError::BadClientData {
+            field: "Batch does not exist".to_string(),
+        }),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// batches assigned to the caller, over all albums
+pub async fn get_own_batches(
+    pool: web::Data<Pool>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    match db::get_task_batches(&client, None, Some(user.id), None).await {
+        Ok(batches) => Ok(HttpResponse::build(StatusCode::OK).json(batches)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
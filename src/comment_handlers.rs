@@ -0,0 +1,236 @@
+use crate::album_handlers::check_album_owner;
+use crate::album_models::{CreateComment, CreateFlag, PhotoTagState, FLAG_TYPES};
+use crate::errors::{DBError, HandlerError};
+use crate::my_identity_service::Identity;
+use crate::user_models::User;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use deadpool_postgres::Pool;
+use log::error;
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+
+const MAX_COMMENT_LENGTH: usize = 2000;
+
+async fn get_photo(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<PhotoTagState, HandlerError> {
+    match db::get_photo_tag_state(client, photo_id).await {
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::BadClientData {
+                field: "Photo does not exists in the database.".to_string(),
+            })
+        }
+        Ok(item) => Ok(item),
+    }
+}
+
+// all comments of a photo, oldest first; replies carry the id of their parent
+pub async fn get_comments(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    get_photo(&client, photo_id.0).await?;
+
+    match db::get_photo_comments(&client, photo_id.0).await {
+        Ok(comments) => Ok(HttpResponse::build(StatusCode::OK).json(comments)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn create_comment(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+    data: web::Json<CreateComment>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let body = data.body.trim();
+    if body.is_empty() || body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(HandlerError::BadClientData {
+            field: format!(
+                "Comment must have between 1 and {} characters",
+                MAX_COMMENT_LENGTH
+            ),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    get_photo(&client, photo_id.0).await?;
+
+    let user: User = id.identity();
+    let comment = CreateComment {
+        body: body.to_string(),
+        parent_id: data.parent_id,
+    };
+    match db::create_photo_comment(&client, photo_id.0, user.id, &comment).await {
+        Ok(comment_id) => Ok(HttpResponse::build(StatusCode::CREATED).json(comment_id)),
+        Err(DBError::BadArgs { err }) => Err(HandlerError::BadClientData { field: err }),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn get_photo_flags(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    get_photo(&client, photo_id.0).await?;
+
+    match db::get_photo_flags(&client, photo_id.0).await {
+        Ok(flags) => Ok(HttpResponse::build(StatusCode::OK).json(flags)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// report a problem with a photo, it leaves the tagging queue until the flag is resolved
+pub async fn create_flag(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+    data: web::Json<CreateFlag>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    if !FLAG_TYPES.contains(&data.flag_type.as_str()) {
+        return Err(HandlerError::BadClientData {
+            field: format!("Flag type must be one of {}", FLAG_TYPES.join(", ")),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let photo = get_photo(&client, photo_id.0).await?;
+
+    let user: User = id.identity();
+    let flag = match db::create_photo_flag(&client, photo.id, photo.album_id, user.id, &data).await
+    {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("photo.flag", "photo", photo.id).after(&flag),
+    )
+    .await;
+    Ok(HttpResponse::build(StatusCode::CREATED).json(flag))
+}
+
+// open flags of an album, for its owner
+pub async fn get_album_flags(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_open_album_flags(&client, album_id.0).await {
+        Ok(flags) => Ok(HttpResponse::build(StatusCode::OK).json(flags)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn resolve_flag(
+    pool: web::Data<Pool>,
+    flag_id: web::Path<(i32,)>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let flag = match db::get_photo_flag(&client, flag_id.0).await {
+        Ok(Some(item)) => item,
+        Ok(None) => {
+            return Err(HandlerError::BadClientData {
+                field: "Flag does not exist".to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, flag.album_id).await?;
+
+    let resolved = match db::resolve_photo_flag(&client, flag.id, user.id).await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("photo.flag_resolve", "photo", flag.image_metas_id)
+            .before(&flag)
+            .after(&resolved),
+    )
+    .await;
+    Ok(HttpResponse::build(StatusCode::OK).json(resolved))
+}
@@ -28,14 +28,323 @@ pub struct LetsEncrypt {
     pub activate: bool,
 }
 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Session {
+    /// Seconds without any request after which a session expires. 0 disables it.
+    pub idle_timeout: i64,
+    /// Seconds after login after which a session expires regardless of activity. 0 disables it.
+    pub max_lifetime: i64,
+    pub domain: Option<String>,
+    pub secure: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            idle_timeout: 0,
+            max_lifetime: 30 * 24 * 60 * 60,
+            domain: None,
+            secure: !cfg!(debug_assertions),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoginProtection {
+    /// Failed attempts after which the account is locked
+    pub max_failures: i32,
+    /// Seconds to wait after the first failure, doubled with every further failure
+    pub base_backoff: i64,
+    pub max_backoff: i64,
+    /// Seconds an account stays locked after `max_failures`
+    pub lockout_duration: i64,
+    /// Seconds after the last failure when the failure counter starts from zero again
+    pub reset_after: i64,
+}
+
+impl Default for LoginProtection {
+    fn default() -> Self {
+        LoginProtection {
+            max_failures: 5,
+            base_backoff: 1,
+            max_backoff: 300,
+            lockout_duration: 900,
+            reset_after: 3600,
+        }
+    }
+}
+
+impl LoginProtection {
+    /// Seconds no login is allowed after the given number of consecutive failures
+    pub fn lock_duration(&self, failures: i32, is_account: bool) -> i64 {
+        if is_account && failures >= self.max_failures {
+            return self.lockout_duration;
+        }
+        let exponent = (failures - 1).max(0).min(30) as u32;
+        self.base_backoff
+            .saturating_mul(2i64.pow(exponent))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Oidc {
+    pub enabled: bool,
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Must point to /api/oidc/callback and be registered at the identity provider
+    pub redirect_url: String,
+    pub scopes: String,
+    /// Claim deciding the role, either a string or a list of strings
+    pub role_claim: String,
+    /// Users whose role claim contains this value become admins, everyone else a user.
+    /// Leave it empty to only set the role of new accounts.
+    pub admin_claim_value: String,
+    /// Where the browser is sent after a successful login
+    pub post_login_redirect: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Mail {
+    /// "smtp" or "log"
+    pub backend: String,
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_tls: bool,
+    pub smtp_username: String,
+    pub smtp_password: String,
+    /// File the log backend appends mails to
+    pub log_file: String,
+    /// Base url used for links in mails
+    pub public_url: String,
+    pub reset_token_ttl: i64,
+    pub verification_token_ttl: i64,
+    /// Users with an unverified email address can not log in
+    pub require_email_verification: bool,
+}
+
+impl Default for Mail {
+    fn default() -> Self {
+        Mail {
+            backend: "log".to_string(),
+            from: "tagify@localhost".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: 25,
+            smtp_tls: true,
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            log_file: "./tagify_data/mails.log".to_string(),
+            public_url: "http://localhost:5000".to_string(),
+            reset_token_ttl: 60 * 60,
+            verification_token_ttl: 2 * 24 * 60 * 60,
+            require_email_verification: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Registration {
+    /// "closed", "open", "invite" or "approval"
+    pub policy: String,
+    /// Seconds an invite code stays valid
+    pub invite_ttl: i64,
+    /// Create default_admin and default_user on startup
+    pub seed_default_accounts: bool,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration {
+            policy: "closed".to_string(),
+            invite_ttl: 7 * 24 * 60 * 60,
+            seed_default_accounts: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Passwords containing the username are rejected
+    pub reject_username: bool,
+    /// One password per line, relative to the config directory. Empty disables the check.
+    pub common_passwords_file: String,
+    /// "argon2i", "argon2d" or "argon2id"
+    pub hash_variant: String,
+    /// Memory in KiB
+    pub hash_memory_cost: u32,
+    pub hash_time_cost: u32,
+    pub hash_lanes: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            reject_username: true,
+            common_passwords_file: "common_passwords.txt".to_string(),
+            // Parameters of argon2::Config::default(), existing hashes stay valid
+            hash_variant: "argon2i".to_string(),
+            hash_memory_cost: 4096,
+            hash_time_cost: 3,
+            hash_lanes: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Inference {
+    /// Send every uploaded image to the inference server
+    pub enabled: bool,
+    /// Receives the raw image as POST body and answers with predictions as JSON
+    pub url: String,
+    /// Sent as bearer token if not empty
+    pub api_key: String,
+    /// Seconds to wait for an answer
+    pub timeout: u64,
+    /// Predictions with a lower confidence are dropped
+    pub min_confidence: f32,
+}
+
+impl Default for Inference {
+    fn default() -> Self {
+        Inference {
+            enabled: false,
+            url: "http://127.0.0.1:8500/predict".to_string(),
+            api_key: String::new(),
+            timeout: 30,
+            min_confidence: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Renditions {
+    /// Store a thumbnail and a medium sized copy of every upload
+    pub enabled: bool,
+    /// Longest edge in pixels
+    pub thumb_size: u32,
+    pub medium_size: u32,
+    /// JPEG quality of the copies, 1 to 100
+    pub jpeg_quality: u8,
+}
+
+impl Default for Renditions {
+    fn default() -> Self {
+        Renditions {
+            enabled: true,
+            thumb_size: 256,
+            medium_size: 1024,
+            jpeg_quality: 85,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SignedUrls {
+    /// Photo lists carry a signed download url per photo, so clients fetch them directly
+    pub enabled: bool,
+    /// Lifetime of an url in seconds, google storage accepts at most 7 days
+    pub ttl: i64,
+    /// HMAC key of urls to local files, required if enabled without google storage
+    pub secret: String,
+}
+
+impl Default for SignedUrls {
+    fn default() -> Self {
+        SignedUrls {
+            enabled: false,
+            ttl: 300,
+            secret: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Transforms {
+    /// Resized, cropped, rotated and converted photos at .../photos/{photo_id}/transform
+    pub enabled: bool,
+    /// Size limit of the result cache in the data folder, least recently used results go first
+    pub cache_max_mb: u64,
+    /// Largest width or height a result may be resized to
+    pub max_dimension: u32,
+    /// Default quality of JPEG and WebP results, 1 to 100
+    pub quality: u8,
+}
+
+impl Default for Transforms {
+    fn default() -> Self {
+        Transforms {
+            enabled: true,
+            cache_max_mb: 512,
+            max_dimension: 4096,
+            quality: 85,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Uploads {
+    /// Largest accepted file in megabytes
+    pub max_file_mb: usize,
+    /// Largest accepted width times height, checked before an upload is decoded
+    pub max_pixels: u64,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Uploads {
+            max_file_mb: 50,
+            max_pixels: 50_000_000,
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct MyConfig {
     pub postgres: deadpool_postgres::Config,
     pub server: Server,
     pub cert: LetsEncrypt,
-    pub default_admin: CreateUser,
-    pub default_user: CreateUser,
+    pub default_admin: Option<CreateUser>,
+    pub default_user: Option<CreateUser>,
     pub tagify_data: TagifyData,
+    #[serde(default)]
+    pub session: Session,
+    #[serde(default)]
+    pub login_protection: LoginProtection,
+    #[serde(default)]
+    pub oidc: Oidc,
+    #[serde(default)]
+    pub mail: Mail,
+    #[serde(default)]
+    pub registration: Registration,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub inference: Inference,
+    #[serde(default)]
+    pub renditions: Renditions,
+    #[serde(default)]
+    pub signed_urls: SignedUrls,
+    #[serde(default)]
+    pub transforms: Transforms,
+    #[serde(default)]
+    pub uploads: Uploads,
 }
 
 impl MyConfig {
@@ -1,9 +1,28 @@
 use crate::album_models::{
     Album, AlbumPreview, AlbumsPreview, CreateAlbum, PhotoPreview, PhotoToTag, TagPhoto,
-    UpdateAlbum, PhotosTagsJson
+    UpdateAlbum, PhotosTagsJson, PhotoTagState, TagRevision, TAG_REVISION_REJECT,
+    TAG_REVISION_REVERT, TAG_REVISION_TAG, TAG_REVISION_VERIFY, TAG_REVISION_CORRECT,
+    PhotoToReview, ReviewPhoto, TaggerStats, REVIEW_ACCEPT, REVIEW_CORRECT, REVIEW_PENDING,
+    REVIEW_REJECT, PhotoComment, CreateComment, PhotoFlag, CreateFlag, UserAccuracy, CreateBatch,
+    TaskBatch, PhotoPriority, QUEUE_LEAST_RECENTLY_SERVED, QUEUE_PRIORITY, QUEUE_RANDOM,
+    QUEUE_UNCERTAINTY, PhotoSuggestion, ScoreEntry, PhotoScore, ScoreSnapshot, PhotoMetadata,
+    PhotoDimensions, DuplicateGroup, DuplicatePhoto, NearDuplicatePhoto
 };
+use crate::inference::Prediction;
+use crate::audit::AuditEvent;
+use crate::audit_models::{AuditEntry, AuditPage, AuditQuery};
+use crate::config::LoginProtection;
 use crate::errors::DBError;
-use crate::user_models::{CreateImageMeta, CreateUser, Hash, ImageMeta, SendUser, User};
+use crate::password::PasswordSettings;
+use crate::stats_models::{
+    AlbumContribution, AlbumStats, DailyContribution, DailyThroughput, LabelCount,
+    LeaderboardEntry, UserContribution, UserProductivity,
+};
+use crate::user_models::{
+    CreateImageMeta, CreateUser, Hash, ImageMeta, InviteCode, PendingUser, SendUser, TwoFactor,
+    TwoFactorPolicy, User, STATUS_ACTIVE, STATUS_PENDING,
+};
+use crate::utils;
 
 use actix_web::Result;
 use log::{debug, error, info};
@@ -13,9 +32,10 @@ use chrono::offset::Utc;
 
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
+use rand::Rng;
 
 pub async fn get_user_by_name(
-    client: deadpool_postgres::Client,
+    client: &deadpool_postgres::Client,
     username: &str,
 ) -> Result<User, DBError> {
     // Query data
@@ -26,516 +46,2412 @@ pub async fn get_user_by_name(
     Ok(User::from_row_ref(&result)?)
 }
 
-pub async fn get_user(client: &deadpool_postgres::Client, id: i32) -> Result<User, DBError> {
-    // Query data
-    let result = client
-        .query_one("SELECT * FROM users WHERE id = $1", &[&id])
-        .await?;
-
-    Ok(User::from_row_ref(&result)?)
+pub fn account_attempt_key(username: &str) -> String {
+    format!("user:{}", username)
 }
 
-pub async fn update_user(client: &deadpool_postgres::Client, user: &User) -> Result<User, DBError> {
-    if user.password.len() < 4 {
-        return Err(DBError::BadArgs {
-            err: "Password is too short".to_owned(),
-        });
-    }
-
-    let hashed_pwd = match user.get_hashed_password() {
-        Ok(item) => item,
-        Err(e) => return Err(DBError::ArgonError(e)),
-    };
+pub fn ip_attempt_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
 
-    let result = client
+// Returns the seconds until a login is allowed again, None if not locked
+pub async fn get_login_lock(
+    client: &deadpool_postgres::Client,
+    keys: &[String],
+) -> Result<Option<i64>, DBError> {
+    let now = Utc::now().timestamp();
+    let row = client
         .query_one(
-            "UPDATE users SET nickname=$1, password=$2, role=$3 WHERE id=$4 RETURNING *",
-            &[&user.nickname, &hashed_pwd, &user.role, &user.id],
+            "SELECT COALESCE(MAX(locked_until), 0) FROM login_attempts WHERE attempt_key = ANY($1)",
+            &[&keys],
         )
         .await?;
-    Ok(User::from_row_ref(&result)?)
+    let locked_until: i64 = row.get(0);
+    if locked_until > now {
+        Ok(Some(locked_until - now))
+    } else {
+        Ok(None)
+    }
 }
 
-pub async fn update_user_nickname(
+// Count a failed login for the username and the ip and extend their lock
+pub async fn record_failed_login(
     client: &deadpool_postgres::Client,
-    user: &User,
-) -> Result<User, DBError> {
-    let result = client
-        .query_one(
-            "UPDATE users SET nickname=$1 WHERE id=$2 RETURNING *",
-            &[&user.nickname, &user.id],
+    protection: &LoginProtection,
+    username: &str,
+    ip: &str,
+) -> Result<(), DBError> {
+    let now = Utc::now().timestamp();
+    client
+        .execute(
+            "INSERT INTO failed_logins (username, ip, attempted_at) VALUES ($1, $2, $3)",
+            &[&username, &ip, &now],
         )
         .await?;
-    Ok(User::from_row_ref(&result)?)
-}
 
-pub async fn update_user_password(
-    client: &deadpool_postgres::Client,
-    user: &User,
-) -> Result<User, DBError> {
-    if user.password.len() < 4 {
-        return Err(DBError::BadArgs {
-            err: "Password is too short".to_owned(),
-        });
+    for (key, is_account) in &[
+        (account_attempt_key(username), true),
+        (ip_attempt_key(ip), false),
+    ] {
+        let row = client
+            .query_one(
+                "INSERT INTO login_attempts (attempt_key, failures, last_failure) VALUES ($1, 1, $2)
+                ON CONFLICT (attempt_key) DO UPDATE SET
+                failures = CASE WHEN login_attempts.last_failure < $2 - $3 THEN 1 ELSE login_attempts.failures + 1 END,
+                last_failure = $2
+                RETURNING failures",
+                &[key, &now, &protection.reset_after],
+            )
+            .await?;
+        let failures: i32 = row.get(0);
+        let locked_until = now + protection.lock_duration(failures, *is_account);
+        client
+            .execute(
+                "UPDATE login_attempts SET locked_until = $1 WHERE attempt_key = $2",
+                &[&locked_until, key],
+            )
+            .await?;
     }
+    Ok(())
+}
 
-    let hashed_pwd = match user.get_hashed_password() {
-        Ok(item) => item,
-        Err(e) => return Err(DBError::ArgonError(e)),
-    };
+pub async fn reset_login_attempts(
+    client: &deadpool_postgres::Client,
+    keys: &[String],
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "DELETE FROM login_attempts WHERE attempt_key = ANY($1)",
+            &[&keys],
+        )
+        .await?)
+}
 
+pub async fn get_two_factor(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<TwoFactor, DBError> {
     let result = client
         .query_one(
-            "UPDATE users SET  password=$1 WHERE id=$2 RETURNING *",
-            &[&hashed_pwd, &user.id],
+            "SELECT totp_secret, totp_enabled, totp_last_step FROM users WHERE id = $1",
+            &[&user_id],
         )
         .await?;
-    Ok(User::from_row_ref(&result)?)
+    Ok(TwoFactor::from_row_ref(&result)?)
 }
 
-pub async fn create_user(
+// Store a new secret which is only used after it has been confirmed with a code
+pub async fn set_pending_totp_secret(
     client: &deadpool_postgres::Client,
-    user: &CreateUser,
-) -> Result<User, DBError> {
-    if user.password.len() < 4 {
-        return Err(DBError::BadArgs {
-            err: "Password is too short".to_owned(),
-        });
-    }
+    user_id: i32,
+    secret: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE users SET totp_secret = $1, totp_enabled = false, totp_last_step = 0 WHERE id = $2",
+            &[&secret, &user_id],
+        )
+        .await?)
+}
 
-    let hashed_pwd = user.get_hashed_password()?;
+pub async fn enable_totp(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    step: i64,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE users SET totp_enabled = true, totp_last_step = $1 WHERE id = $2",
+            &[&step, &user_id],
+        )
+        .await?)
+}
 
-    let result = client.query_one(
-      "INSERT INTO users (username, nickname, password, role) VAlUES ($1, $2, $3, $4) RETURNING *",
-      &[&user.username, &user.nickname, &hashed_pwd, &user.role]).await?;
-    Ok(User::from_row_ref(&result)?)
+pub async fn disable_totp(client: &deadpool_postgres::Client, user_id: i32) -> Result<(), DBError> {
+    client
+        .execute(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = false, totp_last_step = 0 WHERE id = $1",
+            &[&user_id],
+        )
+        .await?;
+    client
+        .execute("DELETE FROM recovery_codes WHERE users_id = $1", &[&user_id])
+        .await?;
+    Ok(())
 }
 
-pub async fn delete_user(
+// Returns false if the time step was already used, so a code can not be replayed
+pub async fn use_totp_step(
     client: &deadpool_postgres::Client,
     user_id: i32,
-) -> Result<User, DBError> {
-    
-    for row in client
-    .query(
-        "SELECT id FROM albums WHERE users_id = $1 ",
-        &[&user_id],
-    )
-    .await?
-    {
-        let id: i32 = row.get(0);
-        println!("album id is: {}", &id);
-        client
-            .query(
-                "DELETE FROM image_metas WHERE album_id = $1 ",
-                &[&id],
-            ).await?;
-            
-        client
-            .query(
-                "DELETE FROM albums WHERE id = $1 ",
-                &[&id],
-            ).await?;
-            
+    step: i64,
+) -> Result<bool, DBError> {
+    let updated = client
+        .execute(
+            "UPDATE users SET totp_last_step = $1 WHERE id = $2 AND totp_last_step < $1",
+            &[&step, &user_id],
+        )
+        .await?;
+    Ok(updated == 1)
+}
 
+pub async fn replace_recovery_codes(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    code_hashes: &[String],
+) -> Result<(), DBError> {
+    client
+        .execute("DELETE FROM recovery_codes WHERE users_id = $1", &[&user_id])
+        .await?;
+    for code_hash in code_hashes {
+        client
+            .execute(
+                "INSERT INTO recovery_codes (users_id, code_hash) VALUES ($1, $2)",
+                &[&user_id, code_hash],
+            )
+            .await?;
     }
+    Ok(())
+}
 
-    let result = client
-        .query_one("DELETE FROM users WHERE id=$1 RETURNING *", &[&user_id])
+// Returns (id, code_hash) of all recovery codes not used yet
+pub async fn get_unused_recovery_codes(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<Vec<(i32, String)>, DBError> {
+    let rows = client
+        .query(
+            "SELECT id, code_hash FROM recovery_codes WHERE users_id = $1 AND used = false",
+            &[&user_id],
+        )
         .await?;
-    Ok(User::from_row_ref(&result)?)
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
 }
 
-//albums
-pub async fn create_album(
+pub async fn use_recovery_code(
     client: &deadpool_postgres::Client,
-    album: &CreateAlbum,
-    id: i32,
-) -> Result<Album, DBError> {
-    let result = client.query_one(
-        "INSERT INTO albums (title, description, tags, users_id, first_photo) VAlUES ($1, $2, $3, $4, NULL) RETURNING *",
-        &[&album.title, &album.description, &album.tags, &id]).await?;
-    // println!("restlt: {:?}", result);
-    Ok(Album::from_row_ref(&result)?)
+    code_id: i32,
+) -> Result<bool, DBError> {
+    let updated = client
+        .execute(
+            "UPDATE recovery_codes SET used = true WHERE id = $1 AND used = false",
+            &[&code_id],
+        )
+        .await?;
+    Ok(updated == 1)
 }
 
-pub async fn create_image_meta(
+// Challenge handed out after a correct password when a second factor is needed
+pub async fn create_login_challenge(
     client: &deadpool_postgres::Client,
-    image_meta: &CreateImageMeta,
-) -> Result<ImageMeta, DBError> {
-    let result = client.query_one(
-        "insert into image_metas (album_id, file_path, coordinates, tag) values ($1, $2, $3, '') RETURNING *",
-        &[&image_meta.album_id, &image_meta.file_path, &image_meta.coordinates]).await?;
+    user_id: i32,
+    ttl: i64,
+) -> Result<String, DBError> {
+    let now = Utc::now().timestamp();
+    client
+        .execute(
+            "DELETE FROM login_challenges WHERE created_at < $1",
+            &[&(now - ttl)],
+        )
+        .await?;
 
+    let token = utils::random_token();
     client
+        .execute(
+            "INSERT INTO login_challenges (token, users_id, created_at) VALUES ($1, $2, $3)",
+            &[&token, &user_id, &now],
+        )
+        .await?;
+    Ok(token)
+}
+
+pub async fn get_login_challenge(
+    client: &deadpool_postgres::Client,
+    token: &str,
+    ttl: i64,
+) -> Result<Option<i32>, DBError> {
+    let min_created_at = Utc::now().timestamp() - ttl;
+    let rows = client
         .query(
-            "UPDATE albums SET image_number = image_number +1 WHERE id = $1",
-            &[&image_meta.album_id],
+            "SELECT users_id FROM login_challenges WHERE token = $1 AND created_at >= $2",
+            &[&token, &min_created_at],
         )
         .await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
 
-    // println!("restlt: {:?}", result);
-    Ok(ImageMeta::from_row_ref(&result)?)
+pub async fn delete_login_challenge(
+    client: &deadpool_postgres::Client,
+    token: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute("DELETE FROM login_challenges WHERE token = $1", &[&token])
+        .await?)
 }
 
-pub async fn update_image_meta(
+pub async fn get_two_factor_policies(
     client: &deadpool_postgres::Client,
-    image_meta: &CreateImageMeta,
-    image_id: &i32,
-) -> Result<ImageMeta, DBError> {
-    let result = client.query_one(
-        "UPDATE image_metas SET album_id=$1, file_path=$2, coordinates=$3 WHERE id=$4 RETURNING *",
-        &[&image_meta.album_id, &image_meta.file_path, &image_meta.coordinates, &image_id]).await?;
-    // println!("restlt: {:?}", result);
-    Ok(ImageMeta::from_row_ref(&result)?)
+) -> Result<Vec<TwoFactorPolicy>, DBError> {
+    let rows = client
+        .query("SELECT role, enforced FROM two_factor_policy", &[])
+        .await?;
+    let mut policies = Vec::new();
+    for row in rows.iter() {
+        policies.push(TwoFactorPolicy::from_row_ref(row)?);
+    }
+    Ok(policies)
 }
 
-pub async fn delete_image_meta(
+pub async fn set_two_factor_policy(
     client: &deadpool_postgres::Client,
-    image_meta_id: &i32,
+    policy: &TwoFactorPolicy,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "INSERT INTO two_factor_policy (role, enforced) VALUES ($1, $2)
+            ON CONFLICT (role) DO UPDATE SET enforced = EXCLUDED.enforced",
+            &[&policy.role, &policy.enforced],
+        )
+        .await?)
+}
+
+pub async fn two_factor_enforced(
+    client: &deadpool_postgres::Client,
+    role: &str,
 ) -> Result<bool, DBError> {
-    let result = client
-        .query_one(
-            "DELETE FROM image_metas WHERE id=$1 RETURNING album_id",
-            &[&image_meta_id],
+    let rows = client
+        .query(
+            "SELECT enforced FROM two_factor_policy WHERE role = $1",
+            &[&role],
         )
         .await?;
+    Ok(rows.first().map(|row| row.get(0)).unwrap_or(false))
+}
 
-    let album_id: i32 = result.get(0);
+pub async fn create_oidc_state(
+    client: &deadpool_postgres::Client,
+    state: &str,
+    nonce: &str,
+    ttl: i64,
+) -> Result<u64, DBError> {
+    let now = Utc::now().timestamp();
     client
+        .execute("DELETE FROM oidc_states WHERE created_at < $1", &[&(now - ttl)])
+        .await?;
+    Ok(client
+        .execute(
+            "INSERT INTO oidc_states (state, nonce, created_at) VALUES ($1, $2, $3)",
+            &[&state, &nonce, &now],
+        )
+        .await?)
+}
+
+// States are single use, returns the nonce belonging to a state that has not expired
+pub async fn take_oidc_state(
+    client: &deadpool_postgres::Client,
+    state: &str,
+    ttl: i64,
+) -> Result<Option<String>, DBError> {
+    let min_created_at = Utc::now().timestamp() - ttl;
+    let rows = client
         .query(
-            "UPDATE albums SET image_number = image_number -1 WHERE id = $1",
-            &[&album_id],
+            "DELETE FROM oidc_states WHERE state = $1 RETURNING nonce, created_at",
+            &[&state],
         )
         .await?;
-    Ok(true)
+    match rows.first() {
+        Some(row) if row.get::<_, i64>(1) >= min_created_at => Ok(Some(row.get(0))),
+        _ => Ok(None),
+    }
 }
 
-pub async fn check_album_exist_by_id(client: &deadpool_postgres::Client, album_id: &i32) -> bool {
-    let result = client
-        .query_one("SELECT * FROM albums WHERE id=$1", &[&album_id])
-        .await;
-    // println!("restlt: {:?}", result);
-    match result {
-        Ok(_row) => return true,
-        Err(e) => {
-            error!("Error check_album_exist_by_id: {:?}", e);
-            return false;
-        }
+pub async fn get_user_by_oidc_subject(
+    client: &deadpool_postgres::Client,
+    subject: &str,
+) -> Result<Option<User>, DBError> {
+    let rows = client
+        .query("SELECT * FROM users WHERE oidc_subject = $1", &[&subject])
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(User::from_row_ref(row)?)),
+        None => Ok(None),
     }
 }
 
-pub async fn get_image_filenames_of_album_with_id(
+pub async fn get_user_by_email(
     client: &deadpool_postgres::Client,
-    album_id: &i32,
-) -> Vec<String> {
-    let mut filenames_db: Vec<String> = Vec::new();
-    let result = client
+    email: &str,
+) -> Result<Option<User>, DBError> {
+    let rows = client
         .query(
-            "SELECT * FROM image_metas WHERE album_id = $1 ORDER BY file_path DESC",
-            &[&album_id],
+            "SELECT * FROM users WHERE lower(email) = lower($1)",
+            &[&email],
         )
-        .await;
-    match result {
-        Ok(rows) => {
-            if rows.len() == 0 {
-                info!("Album {} has no photo in db", album_id);
-            } else {
-                for row in rows {
-                    let filename: String = row.get(3);
-                    filenames_db.push(filename);
-                }
-            }
-        }
-        Err(e) => {
-            error!("Error get_image_filenames_of_album_with_id: {:?}", e);
-        }
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(User::from_row_ref(row)?)),
+        None => Ok(None),
     }
-    return filenames_db;
 }
 
-// get albums data to preview from DB
-pub async fn get_all_albums(client: deadpool_postgres::Client) -> Result<AlbumsPreview, DBError> {
-    let mut albums = AlbumsPreview { albums: Vec::new() };
+pub async fn check_username_exists(
+    client: &deadpool_postgres::Client,
+    username: &str,
+) -> Result<bool, DBError> {
+    let rows = client
+        .query("SELECT id FROM users WHERE username = $1", &[&username])
+        .await?;
+    Ok(!rows.is_empty())
+}
 
-    for row in client
+// Link an account to the identity provider and apply the role mapped from its claims if any,
+// an email the provider verified also counts as verified here. None if the account is bound to
+// another subject, or if verified_email is set and its email address was never verified.
+pub async fn set_oidc_identity(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    subject: &str,
+    email: Option<&str>,
+    role: Option<&str>,
+    verified_email: bool,
+) -> Result<Option<User>, DBError> {
+    let rows = client
         .query(
-            "SELECT id, title, description, first_photo  FROM albums ",
-            &[],
+            "UPDATE users SET oidc_subject = $1, email = COALESCE(email, $2), \
+             role = COALESCE($3, role), \
+             email_verified = email_verified OR COALESCE(lower(COALESCE(email, $2)) = lower($2), false) \
+             WHERE id = $4 AND (oidc_subject IS NULL OR oidc_subject = $1) AND (NOT $5 OR email_verified) \
+             RETURNING *",
+            &[&subject, &email, &role, &user_id, &verified_email],
         )
-        .await?
-    {
-        let album = AlbumPreview {
-            id: row.get(0),
-            title: row.get(1),
-            description: row.get(2),
-            first_photo: row.get(3),
-        };
-        albums.albums.push(album);
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(User::from_row_ref(row)?)),
+        None => Ok(None),
     }
-    Ok(albums)
 }
 
-pub async fn get_first_photo(
+// Create a token for a link sent by mail, returns the token which is only stored hashed
+pub async fn create_user_token(
     client: &deadpool_postgres::Client,
-    id: &i32,
-) -> Result<Option<i32>, DBError> {
-    let row = client
-        .query("SELECT id FROM image_metas WHERE album_id = $1", &[&id])
+    user_id: i32,
+    purpose: &str,
+    ttl: i64,
+) -> Result<String, DBError> {
+    let now = Utc::now().timestamp();
+    client
+        .execute("DELETE FROM user_tokens WHERE expires_at < $1", &[&now])
+        .await?;
+
+    let token = utils::random_token();
+    let expires_at = now + ttl;
+    client
+        .execute(
+            "INSERT INTO user_tokens (token_hash, users_id, purpose, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&utils::sha256_hex(token.as_bytes()), &user_id, &purpose, &expires_at],
+        )
+        .await?;
+    Ok(token)
+}
+
+// Returns the user of an unused and unexpired token
+pub async fn find_user_token(
+    client: &deadpool_postgres::Client,
+    token: &str,
+    purpose: &str,
+) -> Result<Option<i32>, DBError> {
+    let now = Utc::now().timestamp();
+    let rows = client
+        .query(
+            "SELECT users_id FROM user_tokens WHERE token_hash = $1 AND purpose = $2 AND used = false AND expires_at > $3",
+            &[&utils::sha256_hex(token.as_bytes()), &purpose, &now],
+        )
+        .await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+// Marks an unused and unexpired token as used and returns its user. Concurrent requests with
+// the same token can not both get it.
+pub async fn consume_user_token(
+    client: &deadpool_postgres::Client,
+    token: &str,
+    purpose: &str,
+) -> Result<Option<i32>, DBError> {
+    let now = Utc::now().timestamp();
+    let rows = client
+        .query(
+            "UPDATE user_tokens SET used = true WHERE token_hash = $1 AND purpose = $2 AND used = false AND expires_at > $3 RETURNING users_id",
+            &[&utils::sha256_hex(token.as_bytes()), &purpose, &now],
+        )
+        .await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+pub async fn invalidate_user_tokens(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    purpose: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE user_tokens SET used = true WHERE users_id = $1 AND purpose = $2",
+            &[&user_id, &purpose],
+        )
+        .await?)
+}
+
+// Returns the email address and whether it was verified
+pub async fn get_user_email(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<(Option<String>, bool), DBError> {
+    let row = client
+        .query_one(
+            "SELECT email, email_verified FROM users WHERE id = $1",
+            &[&user_id],
+        )
+        .await?;
+    Ok((row.get(0), row.get(1)))
+}
+
+pub async fn set_user_email(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    email: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE users SET email = $1, email_verified = false WHERE id = $2",
+            &[&email, &user_id],
+        )
+        .await?)
+}
+
+pub async fn set_email_verified(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE users SET email_verified = true WHERE id = $1",
+            &[&user_id],
+        )
+        .await?)
+}
+
+pub async fn get_user_status(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<String, DBError> {
+    let row = client
+        .query_one("SELECT status FROM users WHERE id = $1", &[&user_id])
+        .await?;
+    Ok(row.get(0))
+}
+
+pub async fn get_pending_users(
+    client: &deadpool_postgres::Client,
+) -> Result<Vec<PendingUser>, DBError> {
+    let rows = client
+        .query(
+            "SELECT id, username, nickname, email, status FROM users WHERE status = $1 ORDER BY id",
+            &[&STATUS_PENDING],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PendingUser::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+// Returns the approved user, None if there is no pending user with this id
+pub async fn approve_user(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<Option<User>, DBError> {
+    let rows = client
+        .query(
+            "UPDATE users SET status = $1 WHERE id = $2 AND status = $3 RETURNING *",
+            &[&STATUS_ACTIVE, &user_id, &STATUS_PENDING],
+        )
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(User::from_row_ref(row)?)),
+        None => Ok(None),
+    }
+}
+
+// Rejected registrations are deleted so the username can be used again
+pub async fn reject_user(client: &deadpool_postgres::Client, user_id: i32) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "DELETE FROM users WHERE id = $1 AND status = $2",
+            &[&user_id, &STATUS_PENDING],
+        )
+        .await?)
+}
+
+pub async fn create_invite_code(
+    client: &deadpool_postgres::Client,
+    created_by: i32,
+    ttl: i64,
+) -> Result<InviteCode, DBError> {
+    let expires_at = Utc::now().timestamp() + ttl;
+    let row = client
+        .query_one(
+            "INSERT INTO invite_codes (code, created_by, expires_at) VALUES ($1, $2, $3) RETURNING *",
+            &[&utils::random_token(), &created_by, &expires_at],
+        )
+        .await?;
+    Ok(InviteCode::from_row_ref(&row)?)
+}
+
+pub async fn get_invite_codes(
+    client: &deadpool_postgres::Client,
+) -> Result<Vec<InviteCode>, DBError> {
+    let rows = client
+        .query("SELECT * FROM invite_codes ORDER BY expires_at DESC", &[])
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(InviteCode::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+pub async fn delete_invite_code(
+    client: &deadpool_postgres::Client,
+    code: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute("DELETE FROM invite_codes WHERE code = $1", &[&code])
+        .await?)
+}
+
+// Reserve an unused and unexpired code, returns false if it can not be used
+pub async fn claim_invite_code(
+    client: &deadpool_postgres::Client,
+    code: &str,
+) -> Result<bool, DBError> {
+    let now = Utc::now().timestamp();
+    let result = client
+        .execute(
+            "UPDATE invite_codes SET used = true WHERE code = $1 AND used = false AND expires_at > $2",
+            &[&code, &now],
+        )
+        .await?;
+    Ok(result == 1)
+}
+
+// Give a claimed code back when the registration failed
+pub async fn release_invite_code(
+    client: &deadpool_postgres::Client,
+    code: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE invite_codes SET used = false WHERE code = $1 AND used_by IS NULL",
+            &[&code],
+        )
+        .await?)
+}
+
+pub async fn set_invite_code_user(
+    client: &deadpool_postgres::Client,
+    code: &str,
+    user_id: i32,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE invite_codes SET used_by = $1 WHERE code = $2",
+            &[&user_id, &code],
+        )
+        .await?)
+}
+
+// JSON is passed as text and cast, the postgres client is built without serde_json support
+pub async fn insert_audit_event(
+    client: &deadpool_postgres::Client,
+    actor: Option<&User>,
+    event: &AuditEvent,
+    ip: &str,
+) -> Result<u64, DBError> {
+    let actor_id = actor.map(|u| u.id);
+    let actor_name = actor.map(|u| u.username.clone());
+    let before = event.before.as_ref().map(|v| v.to_string());
+    let after = event.after.as_ref().map(|v| v.to_string());
+    let now = Utc::now().timestamp();
+    Ok(client
+        .execute(
+            "INSERT INTO audit_log (actor_id, actor_name, action, target_type, target_id, before, after, ip, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6::text::jsonb, $7::text::jsonb, $8, $9)",
+            &[
+                &actor_id,
+                &actor_name,
+                &event.action,
+                &event.target_type,
+                &event.target_id,
+                &before,
+                &after,
+                &ip,
+                &now,
+            ],
+        )
+        .await?)
+}
+
+pub async fn get_audit_log(
+    client: &deadpool_postgres::Client,
+    query: &AuditQuery,
+    page: i64,
+    per_page: i64,
+) -> Result<AuditPage, DBError> {
+    let filter = "WHERE ($1::INT IS NULL OR actor_id = $1) \
+                  AND ($2::TEXT IS NULL OR action = $2) \
+                  AND ($3::TEXT IS NULL OR target_type = $3) \
+                  AND ($4::TEXT IS NULL OR target_id = $4) \
+                  AND ($5::BIGINT IS NULL OR created_at >= $5) \
+                  AND ($6::BIGINT IS NULL OR created_at <= $6)";
+
+    let total: i64 = client
+        .query_one(
+            format!("SELECT COUNT(*) FROM audit_log {}", filter).as_str(),
+            &[
+                &query.actor_id,
+                &query.action,
+                &query.target_type,
+                &query.target_id,
+                &query.from,
+                &query.to,
+            ],
+        )
+        .await?
+        .get(0);
+
+    let offset = page * per_page;
+    let rows = client
+        .query(
+            format!(
+                "SELECT id, actor_id, actor_name, action, target_type, target_id, before::text, after::text, ip, created_at \
+                 FROM audit_log {} ORDER BY id DESC LIMIT $7 OFFSET $8",
+                filter
+            )
+            .as_str(),
+            &[
+                &query.actor_id,
+                &query.action,
+                &query.target_type,
+                &query.target_id,
+                &query.from,
+                &query.to,
+                &per_page,
+                &offset,
+            ],
+        )
+        .await?;
+
+    let parse = |value: Option<String>| value.and_then(|v| serde_json::from_str(&v).ok());
+    let entries = rows
+        .iter()
+        .map(|row| AuditEntry {
+            id: row.get(0),
+            actor_id: row.get(1),
+            actor_name: row.get(2),
+            action: row.get(3),
+            target_type: row.get(4),
+            target_id: row.get(5),
+            before: parse(row.get(6)),
+            after: parse(row.get(7)),
+            ip: row.get(8),
+            created_at: row.get(9),
+        })
+        .collect();
+
+    Ok(AuditPage {
+        total,
+        page,
+        per_page,
+        entries,
+    })
+}
+
+pub async fn get_user(client: &deadpool_postgres::Client, id: i32) -> Result<User, DBError> {
+    // Query data
+    let result = client
+        .query_one("SELECT * FROM users WHERE id = $1", &[&id])
+        .await?;
+
+    Ok(User::from_row_ref(&result)?)
+}
+
+pub async fn update_user(
+    client: &deadpool_postgres::Client,
+    user: &User,
+    passwords: &PasswordSettings,
+) -> Result<User, DBError> {
+    let hashed_pwd = match user.get_hashed_password(passwords) {
+        Ok(item) => item,
+        Err(e) => return Err(DBError::ArgonError(e)),
+    };
+
+    let result = client
+        .query_one(
+            "UPDATE users SET nickname=$1, password=$2, role=$3 WHERE id=$4 RETURNING *",
+            &[&user.nickname, &hashed_pwd, &user.role, &user.id],
+        )
+        .await?;
+    Ok(User::from_row_ref(&result)?)
+}
+
+// Store a hash computed by the caller, used to upgrade hashes after a login
+pub async fn set_password_hash(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    hashed_pwd: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE users SET password = $1 WHERE id = $2",
+            &[&hashed_pwd, &user_id],
+        )
+        .await?)
+}
+
+pub async fn update_user_nickname(
+    client: &deadpool_postgres::Client,
+    user: &User,
+) -> Result<User, DBError> {
+    let result = client
+        .query_one(
+            "UPDATE users SET nickname=$1 WHERE id=$2 RETURNING *",
+            &[&user.nickname, &user.id],
+        )
+        .await?;
+    Ok(User::from_row_ref(&result)?)
+}
+
+pub async fn update_user_password(
+    client: &deadpool_postgres::Client,
+    user: &User,
+    passwords: &PasswordSettings,
+) -> Result<User, DBError> {
+    let hashed_pwd = match user.get_hashed_password(passwords) {
+        Ok(item) => item,
+        Err(e) => return Err(DBError::ArgonError(e)),
+    };
+
+    let result = client
+        .query_one(
+            "UPDATE users SET  password=$1 WHERE id=$2 RETURNING *",
+            &[&hashed_pwd, &user.id],
+        )
+        .await?;
+    Ok(User::from_row_ref(&result)?)
+}
+
+pub async fn create_user(
+    client: &deadpool_postgres::Client,
+    user: &CreateUser,
+    passwords: &PasswordSettings,
+) -> Result<User, DBError> {
+    create_user_with_status(client, user, STATUS_ACTIVE, passwords).await
+}
+
+pub async fn create_user_with_status(
+    client: &deadpool_postgres::Client,
+    user: &CreateUser,
+    status: &str,
+    passwords: &PasswordSettings,
+) -> Result<User, DBError> {
+    let hashed_pwd = user.get_hashed_password(passwords)?;
+
+    let result = client.query_one(
+      "INSERT INTO users (username, nickname, password, role, email, status) VAlUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+      &[&user.username, &user.nickname, &hashed_pwd, &user.role, &user.email, &status]).await?;
+    Ok(User::from_row_ref(&result)?)
+}
+
+pub async fn delete_user(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<User, DBError> {
+    
+    for row in client
+    .query(
+        "SELECT id FROM albums WHERE users_id = $1 ",
+        &[&user_id],
+    )
+    .await?
+    {
+        let id: i32 = row.get(0);
+        println!("album id is: {}", &id);
+        client
+            .query(
+                "DELETE FROM image_metas WHERE album_id = $1 ",
+                &[&id],
+            ).await?;
+            
+        client
+            .query(
+                "DELETE FROM albums WHERE id = $1 ",
+                &[&id],
+            ).await?;
+            
+
+    }
+
+    let result = client
+        .query_one("DELETE FROM users WHERE id=$1 RETURNING *", &[&user_id])
+        .await?;
+    Ok(User::from_row_ref(&result)?)
+}
+
+//albums
+pub async fn create_album(
+    client: &deadpool_postgres::Client,
+    album: &CreateAlbum,
+    id: i32,
+) -> Result<Album, DBError> {
+    let result = client.query_one(
+        "INSERT INTO albums (title, description, tags, users_id, first_photo) VAlUES ($1, $2, $3, $4, NULL) RETURNING *",
+        &[&album.title, &album.description, &album.tags, &id]).await?;
+    // println!("restlt: {:?}", result);
+    Ok(Album::from_row_ref(&result)?)
+}
+
+// Inserts a photo. With unique_content nothing is inserted and None returned if the album already
+// has a photo with the same content, concurrent uploads of the same content wait for each other.
+pub async fn create_image_meta(
+    client: &mut deadpool_postgres::Client,
+    image_meta: &CreateImageMeta,
+    unique_content: bool,
+) -> Result<Option<ImageMeta>, DBError> {
+    let transaction = client.transaction().await?;
+    if unique_content {
+        transaction
+            .execute(
+                "SELECT pg_advisory_xact_lock($1, hashtext($2))",
+                &[&image_meta.album_id, &image_meta.content_hash],
+            )
+            .await?;
+        let rows = transaction
+            .query(
+                "SELECT id FROM image_metas WHERE album_id = $1 AND content_hash = $2 LIMIT 1",
+                &[&image_meta.album_id, &image_meta.content_hash],
+            )
+            .await?;
+        if !rows.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    let m = &image_meta.metadata;
+    let result = transaction.query_one(
+        "insert into image_metas (album_id, file_path, coordinates, tag, width, height, mime_type, byte_size, original_filename, \
+         taken_at, camera_make, camera_model, gps_latitude, gps_longitude, orientation, content_hash, perceptual_hash) \
+         values ($1, $2, $3, '', $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING *",
+        &[&image_meta.album_id, &image_meta.file_path, &image_meta.coordinates, &m.width, &m.height, &m.mime_type,
+          &m.byte_size, &m.original_filename, &m.taken_at, &m.camera_make, &m.camera_model, &m.gps_latitude,
+          &m.gps_longitude, &m.orientation, &image_meta.content_hash, &image_meta.perceptual_hash]).await?;
+
+    transaction
+        .query(
+            "UPDATE albums SET image_number = image_number +1 WHERE id = $1",
+            &[&image_meta.album_id],
+        )
+        .await?;
+    transaction.commit().await?;
+
+    // println!("restlt: {:?}", result);
+    Ok(Some(ImageMeta::from_row_ref(&result)?))
+}
+
+pub async fn update_image_meta(
+    client: &deadpool_postgres::Client,
+    image_meta: &CreateImageMeta,
+    image_id: &i32,
+) -> Result<ImageMeta, DBError> {
+    let m = &image_meta.metadata;
+    let result = client.query_one(
+        "UPDATE image_metas SET album_id=$1, file_path=$2, coordinates=$3, width=$5, height=$6, mime_type=$7, byte_size=$8, \
+         original_filename=$9, taken_at=$10, camera_make=$11, camera_model=$12, gps_latitude=$13, gps_longitude=$14, \
+         orientation=$15, content_hash=$16, perceptual_hash=$17 WHERE id=$4 RETURNING *",
+        &[&image_meta.album_id, &image_meta.file_path, &image_meta.coordinates, &image_id, &m.width, &m.height,
+          &m.mime_type, &m.byte_size, &m.original_filename, &m.taken_at, &m.camera_make, &m.camera_model,
+          &m.gps_latitude, &m.gps_longitude, &m.orientation, &image_meta.content_hash,
+          &image_meta.perceptual_hash]).await?;
+    // println!("restlt: {:?}", result);
+    Ok(ImageMeta::from_row_ref(&result)?)
+}
+
+pub async fn get_photo_metadata(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<Option<PhotoMetadata>, DBError> {
+    let rows = client
+        .query("SELECT * FROM image_metas WHERE id = $1", &[&photo_id])
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(PhotoMetadata::from_row_ref(row)?)),
+        None => Ok(None),
+    }
+}
+
+// oldest photo of the album with the same content, except the given photo
+pub async fn find_duplicate(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    content_hash: &str,
+    except: Option<i32>,
+) -> Result<Option<i32>, DBError> {
+    let rows = client
+        .query(
+            "SELECT id FROM image_metas WHERE album_id = $1 AND content_hash = $2 \
+             AND ($3::INT IS NULL OR id <> $3) ORDER BY id LIMIT 1",
+            &[&album_id, &content_hash, &except],
+        )
+        .await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+pub async fn link_duplicate_upload(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    album_id: i32,
+    user_id: i32,
+    original_filename: &str,
+) -> Result<(), DBError> {
+    client
+        .execute(
+            "INSERT INTO duplicate_uploads (image_metas_id, album_id, users_id, original_filename, created_at) \
+             VALUES ($1, $2, $3, $4, $5)",
+            &[&photo_id, &album_id, &user_id, &original_filename, &Utc::now().timestamp()],
+        )
+        .await?;
+    Ok(())
+}
+
+// photo id, album id and file name of photos uploaded before content or perceptual hashes were recorded
+pub async fn get_photos_without_hash(
+    client: &deadpool_postgres::Client,
+    perceptual: bool,
+    album_ids: &[i32],
+) -> Result<Vec<(i32, i32, String)>, DBError> {
+    let rows = client
+        .query(
+            "SELECT id, album_id, file_path FROM image_metas
+            WHERE (CASE WHEN $1 THEN perceptual_hash IS NULL ELSE content_hash IS NULL END)
+            AND (cardinality($2::INT[]) = 0 OR album_id = ANY($2))
+            ORDER BY album_id, id",
+            &[&perceptual, &album_ids],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect())
+}
+
+pub async fn set_content_hash(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    content_hash: &str,
+) -> Result<(), DBError> {
+    client
+        .execute(
+            "UPDATE image_metas SET content_hash = $2 WHERE id = $1",
+            &[&photo_id, &content_hash],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn set_perceptual_hash(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    perceptual_hash: i64,
+) -> Result<(), DBError> {
+    client
+        .execute(
+            "UPDATE image_metas SET perceptual_hash = $2 WHERE id = $1",
+            &[&photo_id, &perceptual_hash],
+        )
+        .await?;
+    Ok(())
+}
+
+// photos of the album with a perceptual hash, with across_albums also those of the other albums
+// of owner_id, or of all albums if owner_id is None
+pub async fn get_perceptual_hashes(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    across_albums: bool,
+    owner_id: Option<i32>,
+) -> Result<Vec<NearDuplicatePhoto>, DBError> {
+    let rows = client
+        .query(
+            "SELECT i.id, i.album_id, i.file_path, i.original_filename, i.width, i.height, i.perceptual_hash
+            FROM image_metas i JOIN albums a ON a.id = i.album_id
+            WHERE i.perceptual_hash IS NOT NULL
+            AND (i.album_id = $1 OR ($2 AND ($3::INT IS NULL OR a.users_id = $3)))
+            ORDER BY i.id",
+            &[&album_id, &across_albums, &owner_id],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| NearDuplicatePhoto {
+            id: row.get(0),
+            album_id: row.get(1),
+            file_path: row.get(2),
+            original_filename: row.get(3),
+            width: row.get(4),
+            height: row.get(5),
+            perceptual_hash: row.get(6),
+            distance: 0,
+        })
+        .collect())
+}
+
+// files stored more than once, over all albums
+pub async fn get_duplicate_report(
+    client: &deadpool_postgres::Client,
+    across_albums: bool,
+) -> Result<Vec<DuplicateGroup>, DBError> {
+    let rows = client
+        .query(
+            "WITH groups AS (
+                SELECT content_hash, COUNT(DISTINCT album_id) AS albums FROM image_metas
+                WHERE content_hash IS NOT NULL GROUP BY content_hash
+                HAVING COUNT(*) > 1 AND ($1 = false OR COUNT(DISTINCT album_id) > 1)
+            )
+            SELECT g.content_hash, g.albums, i.id, i.album_id, a.title, i.file_path, i.original_filename,
+                (SELECT COUNT(*) FROM duplicate_uploads d WHERE d.image_metas_id = i.id)
+            FROM groups g
+            JOIN image_metas i ON i.content_hash = g.content_hash
+            JOIN albums a ON a.id = i.album_id
+            ORDER BY g.albums DESC, g.content_hash, i.id",
+            &[&across_albums],
+        )
+        .await?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for row in rows.iter() {
+        let content_hash: String = row.get(0);
+        let photo = DuplicatePhoto {
+            id: row.get(2),
+            album_id: row.get(3),
+            album_title: row.get(4),
+            file_path: row.get(5),
+            original_filename: row.get(6),
+            linked_uploads: row.get(7),
+        };
+        match groups.last_mut() {
+            Some(group) if group.content_hash == content_hash => group.photos.push(photo),
+            _ => groups.push(DuplicateGroup {
+                content_hash,
+                albums: row.get(1),
+                photos: vec![photo],
+            }),
+        }
+    }
+    Ok(groups)
+}
+
+pub async fn delete_image_meta(
+    client: &deadpool_postgres::Client,
+    image_meta_id: &i32,
+) -> Result<bool, DBError> {
+    let result = client
+        .query_one(
+            "DELETE FROM image_metas WHERE id=$1 RETURNING album_id",
+            &[&image_meta_id],
+        )
+        .await?;
+
+    let album_id: i32 = result.get(0);
+    client
+        .query(
+            "UPDATE albums SET image_number = image_number -1 WHERE id = $1",
+            &[&album_id],
+        )
+        .await?;
+    Ok(true)
+}
+
+pub async fn check_album_exist_by_id(client: &deadpool_postgres::Client, album_id: &i32) -> bool {
+    let result = client
+        .query_one("SELECT * FROM albums WHERE id=$1", &[&album_id])
+        .await;
+    // println!("restlt: {:?}", result);
+    match result {
+        Ok(_row) => return true,
+        Err(e) => {
+            error!("Error check_album_exist_by_id: {:?}", e);
+            return false;
+        }
+    }
+}
+
+pub async fn get_image_filenames_of_album_with_id(
+    client: &deadpool_postgres::Client,
+    album_id: &i32,
+) -> Vec<String> {
+    let mut filenames_db: Vec<String> = Vec::new();
+    let result = client
+        .query(
+            "SELECT * FROM image_metas WHERE album_id = $1 ORDER BY file_path DESC",
+            &[&album_id],
+        )
+        .await;
+    match result {
+        Ok(rows) => {
+            if rows.len() == 0 {
+                info!("Album {} has no photo in db", album_id);
+            } else {
+                for row in rows {
+                    let filename: String = row.get(3);
+                    filenames_db.push(filename);
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error get_image_filenames_of_album_with_id: {:?}", e);
+        }
+    }
+    return filenames_db;
+}
+
+// album id and file name of every photo, restricted to the given albums if any
+pub async fn get_image_files(
+    client: &deadpool_postgres::Client,
+    album_ids: &[i32],
+) -> Result<Vec<(i32, String)>, DBError> {
+    let rows = client
+        .query(
+            "SELECT album_id, file_path FROM image_metas
+            WHERE cardinality($1::INT[]) = 0 OR album_id = ANY($1)
+            ORDER BY album_id, id",
+            &[&album_ids],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+// get albums data to preview from DB
+pub async fn get_all_albums(client: deadpool_postgres::Client) -> Result<AlbumsPreview, DBError> {
+    let mut albums = AlbumsPreview { albums: Vec::new() };
+
+    for row in client
+        .query(
+            "SELECT id, title, description, first_photo  FROM albums ",
+            &[],
+        )
+        .await?
+    {
+        let album = AlbumPreview {
+            id: row.get(0),
+            title: row.get(1),
+            description: row.get(2),
+            first_photo: row.get(3),
+        };
+        albums.albums.push(album);
+    }
+    Ok(albums)
+}
+
+pub async fn get_first_photo(
+    client: &deadpool_postgres::Client,
+    id: &i32,
+) -> Result<Option<i32>, DBError> {
+    let row = client
+        .query("SELECT id FROM image_metas WHERE album_id = $1", &[&id])
+        .await?;
+    if row.is_empty() {
+        Ok(None)
+    } else {
+        Ok(row[0].get(0))
+    }
+}
+
+// get all photos from certain album -> sort by date_created
+pub async fn get_photos_from_album(
+    client: &deadpool_postgres::Client,
+    id: &i32,
+    index: &i32,
+) -> Result<Vec<PhotoPreview>, DBError> {
+    let mut photos = Vec::new();
+
+    let start_position = index * 20;
+    let last_position = &start_position + 20;
+    let mut current_position = 0;
+
+    if check_album_exist_by_id(&client, &id).await == false {
+        let err_str = format!("Album with id {} does not exist", id);
+        return Err(DBError::BadArgs {
+            err: err_str.to_string(),
+        });
+    }
+
+    for row in client
+        .query(
+            "SELECT * FROM image_metas WHERE album_id = $1 ORDER BY id",
+            &[&id],
+        )
+        .await?
+    {
+        if &current_position >= &start_position {
+            let photo = PhotoPreview {
+                id: row.get("id"),
+                file_path: row.get("file_path"),
+                tag: row.get("tag"),
+                tagged: row.get("tagged"),
+                verified: row.get("verified"),
+                metadata: PhotoDimensions::from_row_ref(&row)?,
+                url: None,
+            };
+            
+            photos.push(photo);
+            current_position = current_position + 1;
+            if &current_position >= &last_position {
+                break;
+            }
+        } else {
+            current_position = current_position + 1;
+        }
+    }
+    Ok(photos)
+}
+
+pub async fn get_image_file_path_with_id_from_album(
+    client: &deadpool_postgres::Client,
+    album_id: &i32,
+    image_id: &i32,
+) -> String {
+    let mut file_path = "".to_string();
+    let result = client
+        .query(
+            "SELECT * FROM image_metas WHERE id = $1 AND album_id = $2",
+            &[&image_id, &album_id],
+        )
+        .await;
+    match result {
+        Ok(rows) => {
+            if rows.len() == 0 {
+                info!("Image with id {} not found in db", image_id);
+            } else {
+                for row in rows {
+                    file_path = row.get(3);
+                    break;
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error get_image_file_path_with_id: {:?}", e);
+        }
+    }
+    return file_path;
+}
+
+pub async fn get_users_albums(
+    client: &deadpool_postgres::Client,
+    id: i32,
+) -> Result<Vec<Album>, DBError> {
+    let result = client
+        .query("SELECT * FROM albums WHERE users_id = $1", &[&id])
+        .await
+        .expect("ERROR GETTING ALBUMS")
+        .iter()
+        .map(|row| Album::from_row_ref(row).unwrap())
+        .collect::<Vec<Album>>();
+
+    Ok(result)
+}
+
+pub async fn get_album_by_id(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Album, DBError> {
+    if check_album_exist_by_id(&client, &album_id).await == false {
+        let err_str = format!("Album with id {} does not exist", album_id);
+        return Err(DBError::BadArgs {
+            err: err_str.to_string(),
+        });
+    }
+
+    // Query data
+    let result = client
+        .query_one("SELECT * FROM albums WHERE id = $1", &[&album_id])
+        .await?;
+    println!("get_album_by_id {:?}", result);
+    Ok(Album::from_row_ref(&result)?)
+}
+
+pub async fn delete_album(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Album, DBError> {
+    client
+        .query("DELETE FROM image_metas WHERE album_id = $1", &[&album_id])
+        .await?; // need to delete all photos from the album firstly
+    let result = client
+        .query_one("DELETE FROM albums WHERE id=$1 RETURNING *", &[&album_id])
+        .await?;
+    Ok(Album::from_row_ref(&result)?)
+}
+
+pub async fn album_set_first_image(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    photo_id: Option<i32>,
+) -> Result<Album, DBError> {
+    let result = client
+        .query_one(
+            "UPDATE albums SET first_photo=$1 WHERE id=$2 RETURNING *",
+            &[&photo_id, &album_id],
+        )
+        .await?;
+    Ok(Album::from_row_ref(&result)?)
+}
+
+pub async fn update_album(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    album: &UpdateAlbum,
+) -> Result<Album, DBError> {
+    let result = client
+        .query_one(
+            "UPDATE albums SET title=$1, description=$2, gold_rate=COALESCE($4, gold_rate), leaderboard_enabled=COALESCE($5, leaderboard_enabled), \
+             assignment_mode=COALESCE($6, assignment_mode), queue_strategy=COALESCE($7, queue_strategy), \
+             duplicate_policy=COALESCE($8, duplicate_policy) WHERE id=$3 RETURNING *",
+            &[&album.title, &album.description, &album_id, &album.gold_rate, &album.leaderboard_enabled, &album.assignment_mode, &album.queue_strategy,
+              &album.duplicate_policy],
+        )
+        .await?;
+    Ok(Album::from_row_ref(&result)?)
+}
+
+pub async fn get_all_users(client: &deadpool_postgres::Client) -> Result<Vec<SendUser>, DBError> {
+    let result = client
+        .query("SELECT id, username, nickname, role FROM users ", &[])
+        .await
+        .expect("ERROR GETTING USERS")
+        .iter()
+        .map(|row| SendUser::from_row_ref(row).unwrap())
+        .collect::<Vec<SendUser>>();
+
+    Ok(result)
+}
+
+// tag photo + set coordinats
+pub async fn tag_photo_by_id(
+    client: &deadpool_postgres::Client,
+    photo_id: &i32,
+    photo_data: &TagPhoto,
+    user_id: i32,
+) -> Result<bool, DBError> {
+    let current_time = Utc::now().timestamp();
+    let offset: i64 = 900; // 30s timeout TODO: Change in prod to 15 min in sec
+
+    let result = client
+        .query_one("SELECT * FROM image_metas WHERE id = $1", &[&photo_id])
         .await?;
-    if row.is_empty() {
-        Ok(None)
+    let locked_at = result.get::<&str, i64>("locked_at");
+    let album_id = result.get::<&str, i32>("album_id");
+
+    debug!("Albumid: {}, Locked_at: {}", album_id, locked_at);
+    if (&locked_at + &offset) > current_time {
+        client
+        .query(
+            "UPDATE image_metas SET tag = $1, coordinates = $2, tagged = true, locked_at = 0, tagged_by = $4, review_status = $5, review_comment = '' WHERE id = $3 ", // reset timer if tagged
+            &[&photo_data.tag, &photo_data.coordinates, &photo_id, &user_id, &REVIEW_PENDING],
+        )
+        .await?;
+
+        // Re-tagging an already tagged photo must not count it twice
+        refresh_tagged_number(client, album_id).await?;
+        insert_tag_revision(client, *photo_id, user_id, TAG_REVISION_TAG).await?;
+
+        Ok(true)
     } else {
-        Ok(row[0].get(0))
+        Ok(false)
+    }
+}
+
+pub async fn get_photo_tag_state(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<PhotoTagState, DBError> {
+    let result = client
+        .query_one(
+            "SELECT id, album_id, tag, coordinates, tagged, verified, is_gold FROM image_metas WHERE id = $1",
+            &[&photo_id],
+        )
+        .await?;
+    Ok(PhotoTagState::from_row_ref(&result)?)
+}
+
+// tagged_number is derived from the photos instead of counted up and down
+pub async fn refresh_tagged_number(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE albums SET tagged_number = (SELECT COUNT(*) FROM image_metas WHERE album_id = $1 AND tagged = true) WHERE id = $1",
+            &[&album_id],
+        )
+        .await?)
+}
+
+// Append the current label of the photo to its history
+pub async fn insert_tag_revision(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    user_id: i32,
+    action: &str,
+) -> Result<u64, DBError> {
+    let now = Utc::now().timestamp();
+    Ok(client
+        .execute(
+            "INSERT INTO tag_revisions (image_metas_id, album_id, users_id, action, tag, coordinates, tagged, verified, created_at) \
+             SELECT id, album_id, $2, $3, COALESCE(tag, ''), coordinates, COALESCE(tagged, false), COALESCE(verified, false), $4 \
+             FROM image_metas WHERE id = $1",
+            &[&photo_id, &user_id, &action, &now],
+        )
+        .await?)
+}
+
+pub async fn get_tag_revisions(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<Vec<TagRevision>, DBError> {
+    let rows = client
+        .query(
+            "SELECT * FROM tag_revisions WHERE image_metas_id = $1 ORDER BY id DESC",
+            &[&photo_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(TagRevision::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+// Restore the label of an earlier revision, returns false if it is not a revision of the photo
+pub async fn revert_tag(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    revision_id: i32,
+    user_id: i32,
+) -> Result<bool, DBError> {
+    let rows = client
+        .query(
+            "UPDATE image_metas SET tag = r.tag, coordinates = r.coordinates, tagged = r.tagged, verified = r.verified, locked_at = 0 \
+             FROM tag_revisions r WHERE r.id = $2 AND r.image_metas_id = $1 AND image_metas.id = $1 \
+             RETURNING image_metas.album_id",
+            &[&photo_id, &revision_id],
+        )
+        .await?;
+    let album_id: i32 = match rows.first() {
+        Some(row) => row.get(0),
+        None => return Ok(false),
+    };
+
+    refresh_tagged_number(client, album_id).await?;
+    insert_tag_revision(client, photo_id, user_id, TAG_REVISION_REVERT).await?;
+    Ok(true)
+}
+
+// verify photo (true => accept, false => reject and send back to the tagger), only while the photo is locked
+pub async fn verify_photo_by_id(
+    client: &deadpool_postgres::Client,
+    id: &i32,
+    verified: bool,
+    comment: Option<String>,
+    user_id: i32,
+) -> Result<bool, DBError> {
+    let current_time = Utc::now().timestamp();
+    let offset: i64 = 900; //15 min in sec
+
+    let result = client
+        .query_one("SELECT locked_at FROM image_metas WHERE id = $1", &[&id])
+        .await?;
+    if (&result.get(0) + &offset) > current_time {
+        let review = ReviewPhoto {
+            decision: if verified { REVIEW_ACCEPT } else { REVIEW_REJECT }.to_string(),
+            comment,
+            tag: None,
+            coordinates: None,
+        };
+        review_photo(client, *id, user_id, &review).await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+// Apply a reviewer decision. A rejected photo keeps its label so the tagger sees what was wrong.
+pub async fn review_photo(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    reviewer_id: i32,
+    review: &ReviewPhoto,
+) -> Result<(), DBError> {
+    let result = client
+        .query_one("SELECT album_id, tagged_by FROM image_metas WHERE id = $1", &[&photo_id])
+        .await?;
+    let album_id: i32 = result.get(0);
+    let tagger_id: Option<i32> = result.get(1);
+    let comment = review.comment.clone().unwrap_or_default();
+
+    let revision_action = match review.decision.as_str() {
+        REVIEW_ACCEPT => {
+            client
+                .execute(
+                    "UPDATE image_metas SET verified = true, locked_at = 0, review_status = $2, review_comment = $3 WHERE id = $1",
+                    &[&photo_id, &REVIEW_ACCEPT, &comment],
+                )
+                .await?;
+            TAG_REVISION_VERIFY
+        }
+        REVIEW_REJECT => {
+            client
+                .execute(
+                    "UPDATE image_metas SET tagged = false, verified = false, locked_at = 0, review_status = $2, review_comment = $3 WHERE id = $1",
+                    &[&photo_id, &REVIEW_REJECT, &comment],
+                )
+                .await?;
+            TAG_REVISION_REJECT
+        }
+        REVIEW_CORRECT => {
+            let (tag, coordinates) = match (&review.tag, &review.coordinates) {
+                (Some(tag), Some(coordinates)) => (tag, coordinates),
+                _ => {
+                    return Err(DBError::BadArgs {
+                        err: "A correction needs tag and coordinates".to_string(),
+                    })
+                }
+            };
+            client
+                .execute(
+                    "UPDATE image_metas SET tag = $2, coordinates = $3, tagged = true, verified = true, locked_at = 0, review_status = $4, review_comment = $5 WHERE id = $1",
+                    &[&photo_id, tag, coordinates, &REVIEW_CORRECT, &comment],
+                )
+                .await?;
+            TAG_REVISION_CORRECT
+        }
+        _ => {
+            return Err(DBError::BadArgs {
+                err: format!("Unknown review decision {}", review.decision),
+            })
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    client
+        .execute(
+            "INSERT INTO photo_reviews (image_metas_id, album_id, reviewer_id, tagger_id, decision, comment, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[&photo_id, &album_id, &reviewer_id, &tagger_id, &review.decision, &comment, &now],
+        )
+        .await?;
+    refresh_tagged_number(client, album_id).await?;
+    insert_tag_revision(client, photo_id, reviewer_id, revision_action).await?;
+    Ok(())
+}
+
+// tagged photos waiting for a reviewer
+pub async fn get_review_queue(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Vec<PhotoToReview>, DBError> {
+    let rows = client
+        .query(
+            "SELECT id, file_path, COALESCE(tag, '') AS tag, coordinates, tagged_by FROM image_metas \
+             WHERE album_id = $1 AND tagged = true AND verified = false AND review_status = $2 ORDER BY id",
+            &[&album_id, &REVIEW_PENDING],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoToReview::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+pub async fn get_tagger_stats(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Vec<TaggerStats>, DBError> {
+    let rows = client
+        .query(
+            "SELECT r.tagger_id, u.username, COUNT(*), \
+             COUNT(*) FILTER (WHERE r.decision = $2), \
+             COUNT(*) FILTER (WHERE r.decision = $3), \
+             COUNT(*) FILTER (WHERE r.decision = $4) \
+             FROM photo_reviews r LEFT JOIN users u ON u.id = r.tagger_id \
+             WHERE r.album_id = $1 AND r.tagger_id IS NOT NULL \
+             GROUP BY r.tagger_id, u.username ORDER BY r.tagger_id",
+            &[&album_id, &REVIEW_ACCEPT, &REVIEW_REJECT, &REVIEW_CORRECT],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        let reviewed: i64 = row.get(2);
+        let rejected: i64 = row.get(4);
+        result.push(TaggerStats {
+            tagger_id: row.get(0),
+            username: row.get(1),
+            reviewed,
+            accepted: row.get(3),
+            rejected,
+            corrected: row.get(5),
+            rejection_rate: if reviewed > 0 {
+                rejected as f64 / reviewed as f64
+            } else {
+                0.0
+            },
+        });
+    }
+    Ok(result)
+}
+
+pub async fn get_photo_comments(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<Vec<PhotoComment>, DBError> {
+    let rows = client
+        .query(
+            "SELECT c.id, c.image_metas_id, c.parent_id, c.users_id, u.username, c.body, c.created_at \
+             FROM photo_comments c LEFT JOIN users u ON u.id = c.users_id \
+             WHERE c.image_metas_id = $1 ORDER BY c.id",
+            &[&photo_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoComment::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+// A reply must answer a comment on the same photo
+pub async fn create_photo_comment(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    user_id: i32,
+    comment: &CreateComment,
+) -> Result<i32, DBError> {
+    if let Some(parent_id) = comment.parent_id {
+        let parent = client
+            .query(
+                "SELECT id FROM photo_comments WHERE id = $1 AND image_metas_id = $2",
+                &[&parent_id, &photo_id],
+            )
+            .await?;
+        if parent.is_empty() {
+            return Err(DBError::BadArgs {
+                err: "Parent comment does not belong to this photo".to_string(),
+            });
+        }
+    }
+
+    let now = Utc::now().timestamp();
+    let row = client
+        .query_one(
+            "INSERT INTO photo_comments (image_metas_id, parent_id, users_id, body, created_at) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            &[&photo_id, &comment.parent_id, &user_id, &comment.body, &now],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+pub async fn create_photo_flag(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    album_id: i32,
+    user_id: i32,
+    flag: &CreateFlag,
+) -> Result<PhotoFlag, DBError> {
+    let now = Utc::now().timestamp();
+    let note = flag.note.clone().unwrap_or_default();
+    let row = client
+        .query_one(
+            "INSERT INTO photo_flags (image_metas_id, album_id, users_id, flag_type, note, created_at) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            &[&photo_id, &album_id, &user_id, &flag.flag_type, &note, &now],
+        )
+        .await?;
+    Ok(PhotoFlag::from_row_ref(&row)?)
+}
+
+pub async fn get_photo_flags(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<Vec<PhotoFlag>, DBError> {
+    let rows = client
+        .query(
+            "SELECT * FROM photo_flags WHERE image_metas_id = $1 ORDER BY id",
+            &[&photo_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoFlag::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+pub async fn get_open_album_flags(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Vec<PhotoFlag>, DBError> {
+    let rows = client
+        .query(
+            "SELECT * FROM photo_flags WHERE album_id = $1 AND resolved = false ORDER BY id",
+            &[&album_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoFlag::from_row_ref(row)?);
+    }
+    Ok(result)
+}
+
+pub async fn get_photo_flag(
+    client: &deadpool_postgres::Client,
+    flag_id: i32,
+) -> Result<Option<PhotoFlag>, DBError> {
+    let rows = client
+        .query("SELECT * FROM photo_flags WHERE id = $1", &[&flag_id])
+        .await?;
+    match rows.first() {
+        Some(row) => Ok(Some(PhotoFlag::from_row_ref(row)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn resolve_photo_flag(
+    client: &deadpool_postgres::Client,
+    flag_id: i32,
+    user_id: i32,
+) -> Result<PhotoFlag, DBError> {
+    let now = Utc::now().timestamp();
+    let row = client
+        .query_one(
+            "UPDATE photo_flags SET resolved = true, resolved_by = $2, resolved_at = $3 WHERE id = $1 RETURNING *",
+            &[&flag_id, &user_id, &now],
+        )
+        .await?;
+    Ok(PhotoFlag::from_row_ref(&row)?)
+}
+
+// Returns false if the photo is not verified yet
+pub async fn set_gold(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    gold: bool,
+) -> Result<bool, DBError> {
+    let updated = if gold {
+        client
+            .execute(
+                "UPDATE image_metas SET is_gold = true, gold_tag = COALESCE(tag, ''), gold_coordinates = coordinates WHERE id = $1 AND verified = true",
+                &[&photo_id],
+            )
+            .await?
+    } else {
+        client
+            .execute("UPDATE image_metas SET is_gold = false WHERE id = $1", &[&photo_id])
+            .await?
+    };
+    Ok(updated > 0)
+}
+
+// Score an answer on a gold item against its known label, the item itself stays untouched.
+// Only the tag is compared, drawn coordinates are kept for later inspection.
+pub async fn score_gold_answer(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    user_id: i32,
+    answer: &TagPhoto,
+) -> Result<bool, DBError> {
+    let row = client
+        .query_one(
+            "SELECT album_id, gold_tag FROM image_metas WHERE id = $1 AND is_gold = true",
+            &[&photo_id],
+        )
+        .await?;
+    let album_id: i32 = row.get(0);
+    let gold_tag: String = row.get(1);
+    let correct = answer.tag.trim().to_lowercase() == gold_tag.trim().to_lowercase();
+
+    let now = Utc::now().timestamp();
+    client
+        .execute(
+            "INSERT INTO gold_answers (image_metas_id, album_id, users_id, tag, coordinates, correct, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (image_metas_id, users_id) DO NOTHING",
+            &[&photo_id, &album_id, &user_id, &answer.tag, &answer.coordinates, &correct, &now],
+        )
+        .await?;
+    Ok(correct)
+}
+
+// accuracy on gold items, optionally limited to one album and/or one user
+pub async fn get_gold_accuracy(
+    client: &deadpool_postgres::Client,
+    album_id: Option<i32>,
+    user_id: Option<i32>,
+) -> Result<Vec<UserAccuracy>, DBError> {
+    let rows = client
+        .query(
+            "SELECT g.users_id, u.username, COUNT(*), COUNT(*) FILTER (WHERE g.correct) \
+             FROM gold_answers g LEFT JOIN users u ON u.id = g.users_id \
+             WHERE ($1::INT IS NULL OR g.album_id = $1) AND ($2::INT IS NULL OR g.users_id = $2) \
+             GROUP BY g.users_id, u.username ORDER BY g.users_id",
+            &[&album_id, &user_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        let answered: i64 = row.get(2);
+        let correct: i64 = row.get(3);
+        result.push(UserAccuracy {
+            users_id: row.get(0),
+            username: row.get(1),
+            answered,
+            correct,
+            accuracy: if answered > 0 {
+                correct as f64 / answered as f64
+            } else {
+                0.0
+            },
+        });
+    }
+    Ok(result)
+}
+
+// Album progress computed from the photos and their history, not from the albums counters
+pub async fn get_album_stats(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    days: i64,
+) -> Result<AlbumStats, DBError> {
+    let now = Utc::now().timestamp();
+    let lease_start = now - 900; // photos are locked for 15 min
+
+    let counts = client
+        .query_one(
+            "SELECT COUNT(*), COUNT(*) FILTER (WHERE m.tagged), COUNT(*) FILTER (WHERE m.verified), \
+             COUNT(*) FILTER (WHERE m.review_status = $2), COUNT(*) FILTER (WHERE f.image_metas_id IS NOT NULL), \
+             COUNT(*) FILTER (WHERE m.locked_at > $3 AND NOT m.verified), COUNT(*) FILTER (WHERE m.is_gold) \
+             FROM image_metas m \
+             LEFT JOIN (SELECT DISTINCT image_metas_id FROM photo_flags WHERE resolved = false) f ON f.image_metas_id = m.id \
+             WHERE m.album_id = $1",
+            &[&album_id, &REVIEW_REJECT, &lease_start],
+        )
+        .await?;
+
+    let mut labels = Vec::new();
+    for row in client
+        .query(
+            "SELECT tag, COUNT(*) AS count FROM image_metas WHERE album_id = $1 AND tagged = true \
+             GROUP BY tag ORDER BY count DESC, tag",
+            &[&album_id],
+        )
+        .await?
+    {
+        labels.push(LabelCount {
+            tag: row.get::<_, Option<String>>(0).unwrap_or_default(),
+            count: row.get(1),
+        });
+    }
+
+    let mut per_day = Vec::new();
+    let since = now - days * 86400;
+    for row in client
+        .query(
+            "SELECT to_char(to_timestamp(created_at) AT TIME ZONE 'UTC', 'YYYY-MM-DD') AS day, \
+             COUNT(*) FILTER (WHERE action = $3), \
+             COUNT(*) FILTER (WHERE action = $4 OR action = $5), \
+             COUNT(*) FILTER (WHERE action = $6) \
+             FROM tag_revisions WHERE album_id = $1 AND created_at >= $2 \
+             GROUP BY day ORDER BY day",
+            &[
+                &album_id,
+                &since,
+                &TAG_REVISION_TAG,
+                &TAG_REVISION_VERIFY,
+                &TAG_REVISION_CORRECT,
+                &TAG_REVISION_REJECT,
+            ],
+        )
+        .await?
+    {
+        per_day.push(DailyThroughput {
+            day: row.get(0),
+            tagged: row.get(1),
+            verified: row.get(2),
+            rejected: row.get(3),
+        });
+    }
+
+    // time per image is the gap to the previous tag of the same user
+    let mut users = Vec::new();
+    for row in client
+        .query(
+            "WITH events AS ( \
+                 SELECT users_id, action, \
+                 created_at - LAG(created_at) OVER (PARTITION BY users_id, action ORDER BY created_at, id) AS gap \
+                 FROM tag_revisions WHERE album_id = $1 AND users_id IS NOT NULL) \
+             SELECT e.users_id, u.username, \
+             COUNT(*) FILTER (WHERE e.action = $2), \
+             COUNT(*) FILTER (WHERE e.action IN ($3, $4, $5)), \
+             percentile_cont(0.5) WITHIN GROUP (ORDER BY e.gap) FILTER (WHERE e.action = $2 AND e.gap <= 900) \
+             FROM events e LEFT JOIN users u ON u.id = e.users_id \
+             GROUP BY e.users_id, u.username ORDER BY e.users_id",
+            &[
+                &album_id,
+                &TAG_REVISION_TAG,
+                &TAG_REVISION_VERIFY,
+                &TAG_REVISION_REJECT,
+                &TAG_REVISION_CORRECT,
+            ],
+        )
+        .await?
+    {
+        users.push(UserProductivity {
+            users_id: row.get(0),
+            username: row.get(1),
+            tagged: row.get(2),
+            reviewed: row.get(3),
+            median_seconds_per_image: row.get(4),
+        });
     }
+
+    Ok(AlbumStats {
+        album_id,
+        total: counts.get(0),
+        tagged: counts.get(1),
+        verified: counts.get(2),
+        rejected: counts.get(3),
+        flagged: counts.get(4),
+        leased: counts.get(5),
+        gold: counts.get(6),
+        labels,
+        per_day,
+        users,
+    })
 }
 
-// get all photos from certain album -> sort by date_created
-pub async fn get_photos_from_album(
-    client: &deadpool_postgres::Client,
-    id: &i32,
-    index: &i32,
-) -> Result<Vec<PhotoPreview>, DBError> {
-    let mut photos = Vec::new();
+// tags and reviews a user did, and reviews their tags passed
+const CONTRIBUTION_EVENTS: &str = "WITH events AS ( \
+    SELECT album_id, created_at, CASE WHEN action = $3 THEN 'tagged' ELSE 'verified' END AS kind \
+    FROM tag_revisions WHERE users_id = $1 AND created_at >= $2 AND action IN ($3, $4, $5) \
+    UNION ALL \
+    SELECT album_id, created_at, 'accepted' FROM photo_reviews \
+    WHERE tagger_id = $1 AND created_at >= $2 AND decision = $6) ";
 
-    let start_position = index * 20;
-    let last_position = &start_position + 20;
-    let mut current_position = 0;
+pub async fn get_user_contribution(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    days: i64,
+) -> Result<UserContribution, DBError> {
+    let since = Utc::now().timestamp() - days * 86400;
+    let params: [&(dyn tokio_postgres::types::ToSql + Sync); 6] = [
+        &user_id,
+        &since,
+        &TAG_REVISION_TAG,
+        &TAG_REVISION_VERIFY,
+        &TAG_REVISION_CORRECT,
+        &REVIEW_ACCEPT,
+    ];
 
-    if check_album_exist_by_id(&client, &id).await == false {
-        let err_str = format!("Album with id {} does not exist", id);
-        return Err(DBError::BadArgs {
-            err: err_str.to_string(),
+    let mut albums = Vec::new();
+    for row in client
+        .query(
+            format!(
+                "{} SELECT e.album_id, a.title, COUNT(*) FILTER (WHERE e.kind = 'tagged'), \
+                 COUNT(*) FILTER (WHERE e.kind = 'verified'), COUNT(*) FILTER (WHERE e.kind = 'accepted') \
+                 FROM events e LEFT JOIN albums a ON a.id = e.album_id \
+                 GROUP BY e.album_id, a.title ORDER BY e.album_id",
+                CONTRIBUTION_EVENTS
+            )
+            .as_str(),
+            &params,
+        )
+        .await?
+    {
+        albums.push(AlbumContribution {
+            album_id: row.get(0),
+            title: row.get(1),
+            tagged: row.get(2),
+            verified: row.get(3),
+            accepted: row.get(4),
         });
     }
 
+    let mut per_day = Vec::new();
     for row in client
         .query(
-            "SELECT id, file_path, tag, tagged, verified FROM image_metas WHERE album_id = $1 ORDER BY id",
-            &[&id],
+            format!(
+                "{} SELECT to_char(to_timestamp(created_at) AT TIME ZONE 'UTC', 'YYYY-MM-DD') AS day, \
+                 COUNT(*) FILTER (WHERE kind = 'tagged'), COUNT(*) FILTER (WHERE kind = 'verified'), \
+                 COUNT(*) FILTER (WHERE kind = 'accepted') \
+                 FROM events GROUP BY day ORDER BY day",
+                CONTRIBUTION_EVENTS
+            )
+            .as_str(),
+            &params,
         )
         .await?
     {
-        if &current_position >= &start_position {
-            let photo = PhotoPreview {
-                id: row.get(0),
-                file_path: row.get(1),
-                tag: row.get(2),
-                tagged: row.get(3),
-                verified: row.get(4)
-            };
-            
-            photos.push(photo);
-            current_position = current_position + 1;
-            if &current_position >= &last_position {
-                break;
-            }
-        } else {
-            current_position = current_position + 1;
-        }
+        per_day.push(DailyContribution {
+            day: row.get(0),
+            tagged: row.get(1),
+            verified: row.get(2),
+            accepted: row.get(3),
+        });
     }
-    Ok(photos)
+
+    Ok(UserContribution {
+        users_id: user_id,
+        albums,
+        per_day,
+    })
 }
 
-pub async fn get_image_file_path_with_id_from_album(
+// Ranked by accepted tags. Without album_id only albums with an enabled leaderboard count.
+pub async fn get_leaderboard(
     client: &deadpool_postgres::Client,
-    album_id: &i32,
-    image_id: &i32,
-) -> String {
-    let mut file_path = "".to_string();
-    let result = client
+    album_id: Option<i32>,
+    days: i64,
+    limit: i64,
+) -> Result<Vec<LeaderboardEntry>, DBError> {
+    let since = Utc::now().timestamp() - days * 86400;
+    let rows = client
         .query(
-            "SELECT * FROM image_metas WHERE id = $1 AND album_id = $2",
-            &[&image_id, &album_id],
+            "WITH tags AS ( \
+                 SELECT r.users_id, COUNT(*) AS tagged FROM tag_revisions r JOIN albums a ON a.id = r.album_id \
+                 WHERE r.action = $3 AND r.created_at >= $2 AND r.users_id IS NOT NULL \
+                 AND (r.album_id = $1 OR ($1::INT IS NULL AND a.leaderboard_enabled)) \
+                 GROUP BY r.users_id), \
+             accepted AS ( \
+                 SELECT p.tagger_id AS users_id, COUNT(*) AS accepted FROM photo_reviews p JOIN albums a ON a.id = p.album_id \
+                 WHERE p.decision = $4 AND p.created_at >= $2 AND p.tagger_id IS NOT NULL \
+                 AND (p.album_id = $1 OR ($1::INT IS NULL AND a.leaderboard_enabled)) \
+                 GROUP BY p.tagger_id) \
+             SELECT u.id, u.nickname, COALESCE(t.tagged, 0), COALESCE(ac.accepted, 0) AS accepted_count \
+             FROM users u LEFT JOIN tags t ON t.users_id = u.id LEFT JOIN accepted ac ON ac.users_id = u.id \
+             WHERE t.users_id IS NOT NULL OR ac.users_id IS NOT NULL \
+             ORDER BY accepted_count DESC, 3 DESC, u.id LIMIT $5",
+            &[&album_id, &since, &TAG_REVISION_TAG, &REVIEW_ACCEPT, &limit],
         )
-        .await;
-    match result {
-        Ok(rows) => {
-            if rows.len() == 0 {
-                info!("Image with id {} not found in db", image_id);
-            } else {
-                for row in rows {
-                    file_path = row.get(3);
-                    break;
-                }
-            }
-        }
-        Err(e) => {
-            error!("Error get_image_file_path_with_id: {:?}", e);
-        }
+        .await?;
+
+    let mut result = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        result.push(LeaderboardEntry {
+            rank: i as i64 + 1,
+            users_id: row.get(0),
+            nickname: row.get(1),
+            tagged: row.get(2),
+            accepted: row.get(3),
+        });
     }
-    return file_path;
+    Ok(result)
 }
 
-pub async fn get_users_albums(
+// Puts the matching photos into a new batch, returns None if no photo matched
+pub async fn create_task_batch(
     client: &deadpool_postgres::Client,
-    id: i32,
-) -> Result<Vec<Album>, DBError> {
-    let result = client
-        .query("SELECT * FROM albums WHERE users_id = $1", &[&id])
-        .await
-        .expect("ERROR GETTING ALBUMS")
-        .iter()
-        .map(|row| Album::from_row_ref(row).unwrap())
-        .collect::<Vec<Album>>();
+    album_id: i32,
+    user_id: i32,
+    batch: &CreateBatch,
+) -> Result<Option<TaskBatch>, DBError> {
+    let now = Utc::now().timestamp();
+    let batch_id: i32 = client
+        .query_one(
+            "INSERT INTO task_batches (album_id, name, assigned_to, created_by, created_at) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            &[&album_id, &batch.name, &batch.assigned_to, &user_id, &now],
+        )
+        .await?
+        .get(0);
 
-    Ok(result)
+    let added = client
+        .execute(
+            "UPDATE image_metas SET task_batch_id = $1 WHERE id IN ( \
+                 SELECT id FROM (SELECT id, ROW_NUMBER() OVER (ORDER BY id) AS position, tagged, tag, is_gold, task_batch_id \
+                                 FROM image_metas WHERE album_id = $2) p \
+                 WHERE p.task_batch_id IS NULL AND NOT p.is_gold \
+                 AND ($3::BIGINT IS NULL OR p.position >= $3) AND ($4::BIGINT IS NULL OR p.position <= $4) \
+                 AND ($5::BOOL IS NULL OR p.tagged = $5) AND ($6::TEXT IS NULL OR p.tag = $6))",
+            &[&batch_id, &album_id, &batch.range_start, &batch.range_end, &batch.tagged, &batch.tag],
+        )
+        .await?;
+    if added == 0 {
+        client
+            .execute("DELETE FROM task_batches WHERE id = $1", &[&batch_id])
+            .await?;
+        return Ok(None);
+    }
+
+    Ok(get_task_batches(client, Some(album_id), None, Some(batch_id))
+        .await?
+        .pop())
 }
 
-pub async fn get_album_by_id(
+// batches with their progress, every filter is optional
+pub async fn get_task_batches(
     client: &deadpool_postgres::Client,
-    album_id: i32,
-) -> Result<Album, DBError> {
-    if check_album_exist_by_id(&client, &album_id).await == false {
-        let err_str = format!("Album with id {} does not exist", album_id);
-        return Err(DBError::BadArgs {
-            err: err_str.to_string(),
+    album_id: Option<i32>,
+    assigned_to: Option<i32>,
+    batch_id: Option<i32>,
+) -> Result<Vec<TaskBatch>, DBError> {
+    let rows = client
+        .query(
+            "SELECT b.id, b.album_id, b.name, b.assigned_to, b.created_by, b.created_at, \
+             COUNT(m.id), COUNT(m.id) FILTER (WHERE m.tagged), COUNT(m.id) FILTER (WHERE m.verified) \
+             FROM task_batches b LEFT JOIN image_metas m ON m.task_batch_id = b.id \
+             WHERE ($1::INT IS NULL OR b.album_id = $1) AND ($2::INT IS NULL OR b.assigned_to = $2) \
+             AND ($3::INT IS NULL OR b.id = $3) \
+             GROUP BY b.id ORDER BY b.id",
+            &[&album_id, &assigned_to, &batch_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        let total: i64 = row.get(6);
+        let tagged: i64 = row.get(7);
+        result.push(TaskBatch {
+            id: row.get(0),
+            album_id: row.get(1),
+            name: row.get(2),
+            assigned_to: row.get(3),
+            created_by: row.get(4),
+            created_at: row.get(5),
+            total,
+            tagged,
+            verified: row.get(8),
+            completed: total > 0 && tagged == total,
         });
     }
-
-    // Query data
-    let result = client
-        .query_one("SELECT * FROM albums WHERE id = $1", &[&album_id])
-        .await?;
-    println!("get_album_by_id {:?}", result);
-    Ok(Album::from_row_ref(&result)?)
+    Ok(result)
 }
 
-pub async fn delete_album(
+pub async fn assign_task_batch(
     client: &deadpool_postgres::Client,
     album_id: i32,
-) -> Result<Album, DBError> {
-    client
-        .query("DELETE FROM image_metas WHERE album_id = $1", &[&album_id])
-        .await?; // need to delete all photos from the album firstly
-    let result = client
-        .query_one("DELETE FROM albums WHERE id=$1 RETURNING *", &[&album_id])
+    batch_id: i32,
+    user_id: Option<i32>,
+) -> Result<bool, DBError> {
+    let updated = client
+        .execute(
+            "UPDATE task_batches SET assigned_to = $3 WHERE id = $2 AND album_id = $1",
+            &[&album_id, &batch_id, &user_id],
+        )
         .await?;
-    Ok(Album::from_row_ref(&result)?)
+    Ok(updated > 0)
 }
 
-pub async fn album_set_first_image(
+// the photos of a deleted batch become unassigned again
+pub async fn delete_task_batch(
     client: &deadpool_postgres::Client,
     album_id: i32,
-    photo_id: Option<i32>,
-) -> Result<Album, DBError> {
-    let result = client
-        .query_one(
-            "UPDATE albums SET first_photo=$1 WHERE id=$2 RETURNING *",
-            &[&photo_id, &album_id],
+    batch_id: i32,
+) -> Result<bool, DBError> {
+    let deleted = client
+        .execute(
+            "DELETE FROM task_batches WHERE id = $2 AND album_id = $1",
+            &[&album_id, &batch_id],
         )
         .await?;
-    Ok(Album::from_row_ref(&result)?)
+    Ok(deleted > 0)
 }
 
-pub async fn update_album(
+// priorities of photos from other albums are ignored
+pub async fn set_photo_priorities(
     client: &deadpool_postgres::Client,
     album_id: i32,
-    album: &UpdateAlbum,
-) -> Result<Album, DBError> {
-    let result = client
-        .query_one(
-            "UPDATE albums SET title=$1, description=$2 WHERE id=$3 RETURNING *",
-            &[&album.title, &album.description, &album_id],
+    priorities: &[PhotoPriority],
+) -> Result<u64, DBError> {
+    let ids: Vec<i32> = priorities.iter().map(|p| p.id).collect();
+    let values: Vec<i32> = priorities.iter().map(|p| p.priority).collect();
+    Ok(client
+        .execute(
+            "UPDATE image_metas m SET priority = p.priority \
+             FROM unnest($2::INT[], $3::INT[]) AS p(id, priority) \
+             WHERE m.id = p.id AND m.album_id = $1",
+            &[&album_id, &ids, &values],
         )
-        .await?;
-    Ok(Album::from_row_ref(&result)?)
+        .await?)
 }
 
-pub async fn get_all_users(client: &deadpool_postgres::Client) -> Result<Vec<SendUser>, DBError> {
-    let result = client
-        .query("SELECT id, username, nickname, role FROM users ", &[])
-        .await
-        .expect("ERROR GETTING USERS")
-        .iter()
-        .map(|row| SendUser::from_row_ref(row).unwrap())
-        .collect::<Vec<SendUser>>();
+// Stores the predictions of the inference server, the photo's score becomes the best confidence
+pub async fn insert_photo_suggestions(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    model: &str,
+    predictions: &[Prediction],
+) -> Result<(), DBError> {
+    let now = Utc::now().timestamp();
+    for prediction in predictions {
+        client
+            .execute(
+                "INSERT INTO photo_suggestions (image_metas_id, model, label, coordinates, confidence, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&photo_id, &model, &prediction.label, &prediction.coordinates_text(), &prediction.confidence, &now],
+            )
+            .await?;
+    }
+    if !predictions.is_empty() {
+        let best = predictions.iter().map(|p| p.confidence).fold(0.0, f32::max);
+        let album_id: i32 = client
+            .query_one("SELECT album_id FROM image_metas WHERE id = $1", &[&photo_id])
+            .await?
+            .get(0);
+        let score = ScoreEntry {
+            photo_id: Some(photo_id),
+            file_path: None,
+            score: best,
+        };
+        upload_photo_scores(client, album_id, model, &[score]).await?;
+    }
+    Ok(())
+}
 
+pub async fn get_photo_suggestions(
+    client: &deadpool_postgres::Client,
+    photo_ids: &[i32],
+) -> Result<Vec<PhotoSuggestion>, DBError> {
+    let rows = client
+        .query(
+            "SELECT * FROM photo_suggestions WHERE image_metas_id = ANY($1) ORDER BY confidence DESC",
+            &[&photo_ids],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoSuggestion::from_row_ref(row)?);
+    }
     Ok(result)
 }
 
-// tag photo + set coordinats
-pub async fn tag_photo_by_id(
-    client: deadpool_postgres::Client,
-    photo_id: &i32,
-    photo_data: &TagPhoto,
-) -> Result<bool, DBError> {
-    let current_time = Utc::now().timestamp();
-    let offset: i64 = 900; // 30s timeout TODO: Change in prod to 15 min in sec
-
-    let result = client
-        .query_one("SELECT * FROM image_metas WHERE id = $1", &[&photo_id])
+pub async fn get_photo_suggestion(
+    client: &deadpool_postgres::Client,
+    suggestion_id: i32,
+) -> Result<Option<PhotoSuggestion>, DBError> {
+    let rows = client
+        .query("SELECT * FROM photo_suggestions WHERE id = $1", &[&suggestion_id])
         .await?;
-    let locked_at = result.get::<&str, i64>("locked_at");
-    let album_id = result.get::<&str, i32>("album_id");
+    match rows.first() {
+        Some(row) => Ok(Some(PhotoSuggestion::from_row_ref(row)?)),
+        None => Ok(None),
+    }
+}
 
-    debug!("Albumid: {}, Locked_at: {}", album_id, locked_at);
-    if (&locked_at + &offset) > current_time {
-        client
-        .query(
-            "UPDATE image_metas SET tag = $1, coordinates = $2, tagged = true, locked_at = 0 WHERE id = $3 ", // reset timer if tagged
-            &[&photo_data.tag, &photo_data.coordinates, &photo_id],
+pub async fn set_suggestion_accepted(
+    client: &deadpool_postgres::Client,
+    suggestion_id: i32,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE photo_suggestions SET accepted = true WHERE id = $1",
+            &[&suggestion_id],
         )
-        .await?;
+        .await?)
+}
 
-        client
-            .query(
-                "UPDATE albums SET tagged_number = tagged_number +1 WHERE id = $1",
-                &[&album_id],
-            )
-            .await?;
+// Sets the score of the matched photos and keeps it in their history, returns the number of matched photos
+pub async fn upload_photo_scores(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    model: &str,
+    scores: &[ScoreEntry],
+) -> Result<u64, DBError> {
+    let photo_ids: Vec<Option<i32>> = scores.iter().map(|s| s.photo_id).collect();
+    let file_paths: Vec<Option<String>> = scores.iter().map(|s| s.file_path.clone()).collect();
+    let values: Vec<f32> = scores.iter().map(|s| s.score).collect();
+    let now = Utc::now().timestamp();
+    Ok(client
+        .execute(
+            "WITH input AS (SELECT * FROM unnest($2::INT[], $3::TEXT[], $4::REAL[]) AS i(photo_id, file_path, score)), \
+             matched AS ( \
+                 SELECT DISTINCT ON (m.id) m.id, i.score, COALESCE(m.verified, false) AS verified FROM input i \
+                 JOIN image_metas m ON m.album_id = $1 AND (m.id = i.photo_id OR (i.photo_id IS NULL AND m.file_path = i.file_path))), \
+             updated AS (UPDATE image_metas m SET score = matched.score FROM matched WHERE m.id = matched.id) \
+             INSERT INTO photo_scores (image_metas_id, album_id, model, score, verified, created_at) \
+             SELECT id, $1, $5, score, verified, $6 FROM matched",
+            &[&album_id, &photo_ids, &file_paths, &values, &model, &now],
+        )
+        .await?)
+}
 
-        Ok(true)
-    } else {
-        Ok(false)
+pub async fn get_photo_score_history(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+) -> Result<Vec<PhotoScore>, DBError> {
+    let rows = client
+        .query(
+            "SELECT * FROM photo_scores WHERE image_metas_id = $1 ORDER BY id",
+            &[&photo_id],
+        )
+        .await?;
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(PhotoScore::from_row_ref(row)?);
     }
+    Ok(result)
 }
 
-// verify photo ( if true => set verify true, else delete tag and coordinates & set both verified and tagged as false)
-pub async fn verify_photo_by_id(
-    client: deadpool_postgres::Client,
-    id: &i32,
-    verified: bool,
-) -> Result<bool, DBError> {
-    let current_time = Utc::now().timestamp();
-    let offset: i64 = 900; //15 min in sec
-
-    let result = client
-        .query_one("SELECT locked_at, album_id FROM image_metas WHERE id = $1", &[&id])
+// one entry per upload, oldest first
+pub async fn get_album_score_history(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+) -> Result<Vec<ScoreSnapshot>, DBError> {
+    let rows = client
+        .query(
+            "SELECT created_at, model, COUNT(*), COUNT(*) FILTER (WHERE verified), AVG(score)::FLOAT8, \
+             (AVG(score) FILTER (WHERE NOT verified))::FLOAT8, MIN(score) \
+             FROM photo_scores WHERE album_id = $1 \
+             GROUP BY created_at, model ORDER BY created_at, model",
+            &[&album_id],
+        )
         .await?;
-    let album_id: i32 = result.get(1);
-    if (&result.get(0) + &offset) > current_time {
-        if verified {
-            client
-                .query(
-                    "UPDATE image_metas SET verified = true, locked_at = 0 WHERE id = $1 ", // reset timer
-                    &[&id],
-                )
-                .await?;
-        } else {
-            client
-            .query(
-                "UPDATE image_metas SET tag = '', coordinates = '', tagged = false, verified = false, locked_at = 0 WHERE id = $1 ", // reset timer
-                &[ &id],
-            )
-            .await?;
-            client
-            .query(
-                "UPDATE albums SET tagged_number = tagged_number -1 WHERE id = $1",
-                &[&album_id],
-            )
-            .await?;
-        }
-        Ok(true)
-    } else {
-        Ok(false)
+    let mut result = Vec::new();
+    for row in rows.iter() {
+        result.push(ScoreSnapshot {
+            created_at: row.get(0),
+            model: row.get(1),
+            photos: row.get(2),
+            verified: row.get(3),
+            mean_score: row.get(4),
+            mean_score_unverified: row.get(5),
+            min_score: row.get(6),
+        });
     }
+    Ok(result)
+}
+
+pub async fn set_queue_strategy(
+    client: &deadpool_postgres::Client,
+    album_id: i32,
+    strategy: &str,
+) -> Result<u64, DBError> {
+    Ok(client
+        .execute(
+            "UPDATE albums SET queue_strategy = $2 WHERE id = $1",
+            &[&album_id, &strategy],
+        )
+        .await?)
 }
 
 //get photos for tagging
 pub async fn get_photos_for_tagging(
     client: deadpool_postgres::Client,
     id: &i32,
+    user_id: i32,
+    batch_size: usize,
 ) -> Result<Vec<PhotoToTag>, DBError> {
     let mut photos = Vec::new();
 
@@ -543,23 +2459,85 @@ pub async fn get_photos_for_tagging(
     let offset: i64 = 900; // 15 min in sec
     let time_after_offset: i64 = &current_time - &offset;
 
-    for row in client.query("SELECT id, file_path, tagged, tag, coordinates  FROM image_metas WHERE album_id = $1 AND verified = false AND locked_at <= $2", &[&id, &time_after_offset]).await? {
+    let album = client
+        .query_one("SELECT gold_rate, assignment_mode, queue_strategy FROM albums WHERE id = $1", &[&id])
+        .await?;
+    let gold_rate: f32 = album.get(0);
+    let assignment_mode: bool = album.get(1);
+    let queue_strategy: String = album.get(2);
+    let gold_number = ((batch_size as f32 * gold_rate.max(0.0).min(1.0)).round() as usize)
+        .min(batch_size.saturating_sub(1));
+    let regular_number = (batch_size - gold_number) as i64;
+
+    let order = match queue_strategy.as_str() {
+        QUEUE_RANDOM => "random()",
+        QUEUE_LEAST_RECENTLY_SERVED => "served_at, id",
+        // weighted random sampling, a photo with priority p is drawn p + 1 times as often
+        QUEUE_PRIORITY => "-ln(1.0 - random()) / (GREATEST(priority, 0) + 1)",
+        // lowest model confidence first, unscored photos last
+        QUEUE_UNCERTAINTY => "score ASC NULLS LAST, id",
+        _ => "id",
+    };
+
+    // rejected photos only go back to the user who tagged them, and come first in their queue
+    for row in client.query(format!("SELECT * FROM image_metas WHERE album_id = $1 AND verified = false AND is_gold = false AND locked_at <= $2 \
+                             AND (review_status <> $4 OR tagged_by IS NULL OR tagged_by = $3) \
+                             AND NOT EXISTS (SELECT 1 FROM photo_flags f WHERE f.image_metas_id = image_metas.id AND f.resolved = false) \
+                             AND ($5 = false OR task_batch_id IN (SELECT id FROM task_batches WHERE assigned_to = $3)) \
+                             ORDER BY (review_status = $4 AND tagged_by = $3) DESC, {} LIMIT $6", order).as_str(), &[&id, &time_after_offset, &user_id, &REVIEW_REJECT, &assignment_mode, &regular_number]).await? {
             let photo_timestamp = Utc::now();
 
             let photo = PhotoToTag {
-                id: row.get(0),
-                file_path: row.get(1),
-                tagged: row.get(2),
-                tag: row.get(3),
-                coordinates: row.get(4),
-                timestamp: photo_timestamp
+                id: row.get("id"),
+                file_path: row.get("file_path"),
+                tagged: row.get("tagged"),
+                tag: row.get("tag"),
+                coordinates: row.get("coordinates"),
+                timestamp: photo_timestamp,
+                review_comment: row.get("review_comment"),
+                suggestions: Vec::new(),
+                metadata: PhotoMetadata::from_row_ref(&row)?,
+                url: None,
             };
-            client.query("UPDATE image_metas SET locked_at = $2 WHERE id = $1 ", &[&&photo.id, &photo.timestamp.timestamp()]).await?;
+            client.query("UPDATE image_metas SET locked_at = $2, served_at = $2 WHERE id = $1 ", &[&&photo.id, &photo.timestamp.timestamp()]).await?;
 
             photos.push(photo);
-            if photos.len() >= 20 {
-                break;
-            }
+    }
+
+    // gold items look like untagged photos and are not locked, every user answers each one once
+    if !photos.is_empty() && gold_number > 0 {
+        let gold_photos = client
+            .query(
+                "SELECT * FROM image_metas WHERE album_id = $1 AND is_gold = true \
+                 AND NOT EXISTS (SELECT 1 FROM gold_answers g WHERE g.image_metas_id = image_metas.id AND g.users_id = $2) \
+                 ORDER BY random() LIMIT $3",
+                &[&id, &user_id, &(gold_number as i64)],
+            )
+            .await?;
+        let mut rng = rand::thread_rng();
+        for row in gold_photos.iter() {
+            let photo = PhotoToTag {
+                id: row.get("id"),
+                file_path: row.get("file_path"),
+                tagged: false,
+                tag: String::new(),
+                coordinates: String::new(),
+                timestamp: Utc::now(),
+                review_comment: String::new(),
+                suggestions: Vec::new(),
+                metadata: PhotoMetadata::from_row_ref(row)?,
+                url: None,
+            };
+            let position = rng.gen_range(0, photos.len() + 1);
+            photos.insert(position, photo);
+        }
+    }
+
+    let photo_ids: Vec<i32> = photos.iter().map(|p| p.id).collect();
+    for suggestion in get_photo_suggestions(&client, &photo_ids).await? {
+        if let Some(photo) = photos.iter_mut().find(|p| p.id == suggestion.image_metas_id) {
+            photo.suggestions.push(suggestion);
+        }
     }
     Ok(photos)
 }
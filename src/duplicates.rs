@@ -0,0 +1,257 @@
+// Content and perceptual hashes, and the clustering of near duplicates
+use crate::album_models::{NearDuplicateCluster, NearDuplicatePhoto};
+use crate::db;
+use crate::errors::DBError;
+use crate::gg_storage;
+use crate::storage::{self, Store};
+use crate::utils;
+use actix_web::web;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use log::{error, warn};
+use std::collections::HashMap;
+
+/// dHash: one bit per pair of neighbouring pixels of a 9x8 grayscale copy, set if the left one
+/// is darker. Survives resizing and re-encoding, unlike the content hash.
+pub fn dhash(image: &DynamicImage) -> i64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash as i64
+}
+
+pub fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    root
+}
+
+/// Groups photos whose hashes differ in at most max_distance bits, transitively. Only clusters
+/// with more than one photo are returned, each with its distances to the first photo.
+/// CPU bound on large albums, call it through web::block.
+pub fn cluster(photos: Vec<NearDuplicatePhoto>, max_distance: u32) -> Vec<NearDuplicateCluster> {
+    let mut parent: Vec<usize> = (0..photos.len()).collect();
+
+    // Two hashes within max_distance bits are equal in at least one of max_distance + 1 blocks,
+    // so only photos sharing a block value have to be compared
+    let blocks = max_distance as usize + 1;
+    for block in 0..blocks {
+        let start = 64 * block / blocks;
+        let bits = 64 * (block + 1) / blocks - start;
+        let mask = if bits == 64 {
+            u64::MAX
+        } else {
+            (1u64 << bits) - 1
+        };
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, photo) in photos.iter().enumerate() {
+            let key = (photo.perceptual_hash as u64 >> start) & mask;
+            buckets.entry(key).or_insert_with(Vec::new).push(i);
+        }
+        for bucket in buckets.values() {
+            for (k, &i) in bucket.iter().enumerate() {
+                for &j in &bucket[k + 1..] {
+                    let (root_i, root_j) = (find(&mut parent, i), find(&mut parent, j));
+                    if root_i != root_j
+                        && distance(photos[i].perceptual_hash, photos[j].perceptual_hash)
+                            <= max_distance
+                    {
+                        parent[root_j] = root_i;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<NearDuplicatePhoto>> = HashMap::new();
+    for (i, photo) in photos.into_iter().enumerate() {
+        let root = find(&mut parent, i);
+        clusters.entry(root).or_insert_with(Vec::new).push(photo);
+    }
+    let mut result: Vec<NearDuplicateCluster> = clusters
+        .into_iter()
+        .map(|(_, photos)| photos)
+        .filter(|photos| photos.len() > 1)
+        .map(|mut photos| {
+            let first = photos[0].perceptual_hash;
+            for photo in photos.iter_mut() {
+                photo.distance = distance(first, photo.perceptual_hash);
+            }
+            NearDuplicateCluster { photos }
+        })
+        .collect();
+    result.sort_by_key(|c| c.photos[0].id);
+    result
+}
+
+/// Hashes all photos without a content hash, or without a perceptual hash if perceptual is set.
+/// Restricted to the given albums if any, returns how many photos were hashed.
+pub async fn backfill(
+    client: &deadpool_postgres::Client,
+    tagify_albums_path: &str,
+    google_storage_enable: bool,
+    perceptual: bool,
+    album_ids: &[i32],
+) -> Result<usize, DBError> {
+    let client_r = reqwest::Client::new();
+    let bearer_string = match storage::read_bearer_string(google_storage_enable) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error reading oauth_key.txt  : {}", e);
+            return Ok(0);
+        }
+    };
+
+    let mut hashed = 0;
+    for (photo_id, album_id, file_path) in
+        db::get_photos_without_hash(client, perceptual, album_ids).await?
+    {
+        let album_path = format!("{}{}/", tagify_albums_path, album_id);
+        let bucket_name = format!("{}{}", gg_storage::PREFIX_BUCKET, album_id);
+        let store = Store::new(
+            google_storage_enable,
+            &client_r,
+            &bearer_string,
+            &bucket_name,
+            &album_path,
+        );
+        let image = match store.get(&file_path).await {
+            Some(item) => item,
+            None => {
+                warn!("Album {}: {} not found in storage", album_id, file_path);
+                continue;
+            }
+        };
+        if !perceptual {
+            db::set_content_hash(client, photo_id, &utils::sha256_hex(&image)).await?;
+            hashed += 1;
+            continue;
+        }
+        match web::block(move || image::load_from_memory(&image).map(|i| dhash(&i))).await {
+            Ok(hash) => {
+                db::set_perceptual_hash(client, photo_id, hash).await?;
+                hashed += 1;
+            }
+            Err(e) => warn!("Album {}: could not decode {}: {}", album_id, file_path, e),
+        }
+    }
+    Ok(hashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn photo(id: i32, perceptual_hash: i64) -> NearDuplicatePhoto {
+        NearDuplicatePhoto {
+            id,
+            album_id: 1,
+            file_path: format!("{}.png", id),
+            original_filename: None,
+            width: None,
+            height: None,
+            perceptual_hash,
+            distance: 0,
+        }
+    }
+
+    fn ids(clusters: &[NearDuplicateCluster]) -> Vec<Vec<i32>> {
+        clusters
+            .iter()
+            .map(|c| {
+                let mut ids: Vec<i32> = c.photos.iter().map(|p| p.id).collect();
+                ids.sort();
+                ids
+            })
+            .collect()
+    }
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, _| {
+            Luma([(x * 255 / (width - 1)) as u8])
+        }))
+    }
+
+    #[test]
+    fn distance_counts_differing_bits() {
+        assert_eq!(distance(0, 0), 0);
+        assert_eq!(distance(0b1011, 0b0001), 2);
+        assert_eq!(distance(0, -1), 64);
+        assert_eq!(distance(i64::MIN, 0), 1);
+    }
+
+    #[test]
+    fn dhash_survives_resizing() {
+        assert_eq!(dhash(&gradient(90, 80)), -1);
+        assert_eq!(dhash(&gradient(900, 80)), dhash(&gradient(90, 800)));
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(50, 50, Luma([128])));
+        assert_eq!(dhash(&flat), 0);
+    }
+
+    #[test]
+    fn cluster_respects_the_threshold() {
+        // 1 is 3 bits from 2 and from 3, which are 6 bits apart
+        let photos = vec![photo(1, 0), photo(2, 0b111), photo(3, 0b111_000)];
+        assert_eq!(ids(&cluster(photos.clone(), 2)), Vec::<Vec<i32>>::new());
+        assert_eq!(ids(&cluster(photos.clone(), 3)), vec![vec![1, 2, 3]]);
+        assert_eq!(ids(&cluster(photos, 4)), vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn cluster_is_transitive() {
+        // 1 and 3 differ in 4 bits, but are both within 2 bits of 2
+        let photos = vec![photo(1, 0), photo(2, 0b11), photo(3, 0b1111)];
+        let clusters = cluster(photos, 2);
+        assert_eq!(ids(&clusters), vec![vec![1, 2, 3]]);
+        let distances: Vec<u32> = clusters[0].photos.iter().map(|p| p.distance).collect();
+        assert_eq!(distances, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn cluster_finds_differences_in_any_block() {
+        // One differing bit at each end of the hash, in the first and the last block
+        let photos = vec![
+            photo(1, 0x0f0f_0f0f_0f0f_0f0f),
+            photo(2, 0x0f0f_0f0f_0f0f_0f0e),
+            photo(3, 0x8f0f_0f0f_0f0f_0f0f_u64 as i64),
+            photo(4, 0x7070_7070_7070_7070),
+        ];
+        assert_eq!(ids(&cluster(photos, 1)), vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn clusters_are_sorted_and_exact_matches_need_distance_zero() {
+        let photos = vec![
+            photo(5, 42),
+            photo(1, -7),
+            photo(3, 42),
+            photo(2, -7),
+            photo(4, 43),
+        ];
+        let clusters = cluster(photos, 0);
+        assert_eq!(ids(&clusters), vec![vec![1, 2], vec![3, 5]]);
+        assert!(clusters
+            .iter()
+            .all(|c| c.photos.iter().all(|p| p.distance == 0)));
+    }
+}
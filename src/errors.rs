@@ -21,12 +21,23 @@ pub enum HandlerError {
     Timeout,
     #[fail(display = "Storage failed with: {}", err)]
     StorageError { err: String },
+    #[fail(display = "Session expired: {}", reason)]
+    SessionExpired { reason: String },
+    #[fail(display = "Too many failed logins, retry in {} seconds", retry_after)]
+    TooManyAttempts { retry_after: i64 },
+    #[fail(display = "Two-factor authentication has to be enabled for this account")]
+    TwoFactorRequired,
+    #[fail(display = "Upload too large: {}", field)]
+    TooLarge { field: String },
 }
 
 impl ResponseError for HandlerError {
     fn error_response(&self) -> HttpResponse {
-        ResponseBuilder::new(self.status_code())
-            .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        let mut resp = ResponseBuilder::new(self.status_code());
+        if let HandlerError::TooManyAttempts { retry_after } = self {
+            resp.set_header(header::RETRY_AFTER, retry_after.to_string());
+        }
+        resp.set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
             .body(self.to_string())
     }
 
@@ -38,6 +49,10 @@ impl ResponseError for HandlerError {
             HandlerError::BadClientData { .. } => StatusCode::BAD_REQUEST,
             HandlerError::AuthFail => StatusCode::UNAUTHORIZED,
             HandlerError::PermissionDenied { .. } => StatusCode::UNAUTHORIZED,
+            HandlerError::SessionExpired { .. } => StatusCode::UNAUTHORIZED,
+            HandlerError::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+            HandlerError::TwoFactorRequired => StatusCode::FORBIDDEN,
+            HandlerError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 }
@@ -21,33 +21,24 @@ pub fn create_error(response: &str) -> HandlerError {
     };
 }
 
-fn construct_headers_image(ext: String) -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    // TODO: this is stupid, but I couldn't findd a way to convert string to 'static string'
-    let mut l_ext = ext;
-    l_ext.make_ascii_lowercase();
-    if l_ext == "png" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
-    } else if l_ext == "bmp" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/bmp"));
-    } else if l_ext == "jpg" || l_ext == "jpeg" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
-    } else if l_ext == "gif" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/gif"));
-    } else if l_ext == "ico" {
-        headers.insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("image/vnd.microsoft.icon"),
-        );
-    } else if l_ext == "svg" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/svg+xml"));
-    } else if l_ext == "tif" || l_ext == "tiff" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/tiff"));
-    } else if l_ext == "webp" {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/webp"));
-    } else {
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+// MIME type guessed from the file extension, for files without recorded metadata
+pub fn mime_type(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "bmp" => "image/bmp",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/vnd.microsoft.icon",
+        "svg" => "image/svg+xml",
+        "tif" | "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        _ => "image/png",
     }
+}
+
+pub fn construct_headers_image(ext: String) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(mime_type(&ext)));
     headers
 }
 
@@ -184,24 +175,58 @@ pub async fn upload_buffer_with_name_to_bucket(
     Ok(body)
 }
 
-/* Retrieves object bytes. Mind the "?alt=media" URL parameter! */
+/* Checks the object metadata, true if the object exists */
+// https://cloud.google.com/storage/docs/json_api/v1/objects/get?hl=en_US
+pub async fn object_exists_in_bucket(
+    client: &reqwest::Client,
+    bearer_string: &String,
+    bucket_name: &String,
+    object_name: &String,
+) -> Result<bool, reqwest::Error> {
+    let url = format!(
+        "https://storage.googleapis.com/storage/v1/b/{}/o/{}",
+        &bucket_name, &object_name
+    );
+    let res = client.get(&url).bearer_auth(&bearer_string).send().await?;
+    Ok(res.status().is_success())
+}
+
+/* Retrieves object bytes, None if the object is missing. Mind the "?alt=media" URL parameter! */
 // https://cloud.google.com/storage/docs/json_api/v1/objects/get?hl=en_US
-pub async fn download_object_bytes_from_bucket(
+pub async fn download_object_bytes_if_exists(
     client: &reqwest::Client,
     bearer_string: &String,
     bucket_name: &String,
     object_name: &String,
-) -> Result<Bytes, reqwest::Error> {
+) -> Result<Option<Bytes>, reqwest::Error> {
     let url = format!(
         "https://storage.googleapis.com/storage/v1/b/{}/o/{}?alt=media",
         &bucket_name, &object_name
     );
-    let res = client
+    let res = client.get(&url).bearer_auth(&bearer_string).send().await?;
+    if !res.status().is_success() {
+        return Ok(None);
+    }
+    Ok(Some(res.bytes().await?))
+}
+
+/* Object media for streaming to the client. Range and conditional headers are forwarded, so the
+status may also be 206 or 304 */
+pub async fn stream_object_from_bucket(
+    client: &reqwest::Client,
+    bearer_string: &String,
+    bucket_name: &String,
+    object_name: &String,
+    headers: HeaderMap,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let url = format!(
+        "https://storage.googleapis.com/storage/v1/b/{}/o/{}?alt=media",
+        &bucket_name, &object_name
+    );
+    client
         .get(&url)
         .bearer_auth(&bearer_string)
-        .headers(construct_headers_image(utils::get_file_ext(object_name)))
+        .headers(headers)
         .send()
-        .await?;
-    let bytes = res.bytes().await?;
-    Ok(bytes)
+        .await
 }
@@ -1,28 +1,40 @@
 use crate::errors::HandlerError;
 use crate::gg_storage;
 use crate::user_models::{
-    CreateImageMeta, Hash, LoginData, SendUser, Status, UpdateUserNickname, UpdateUserPassword,
-    User,
+    CreateImageMeta, Hash, LoginData, SendUser, Status, TwoFactorChallenge, UpdateUserNickname,
+    UpdateUserPassword, User, STATUS_ACTIVE,
 };
+use actix_web::error::BlockingError;
 use actix_web::http::StatusCode;
 use actix_web::{web, HttpRequest, HttpResponse, Result};
 use deadpool_postgres::Pool;
 
 extern crate reqwest;
-use crate::album_models::Album;
+use crate::album_models::{
+    Album, PhotoQuery, SignedQuery, TransformQuery, UploadedPhoto, DUPLICATES_ALLOW,
+    DUPLICATES_LINK,
+};
+use crate::audit::{self, AuditEvent};
+use crate::config::{Inference, LoginProtection, Mail, Renditions, Uploads};
+use crate::image_info;
+use crate::inference;
+use crate::renditions;
+use crate::signed_urls::UrlSigner;
+use crate::storage;
+use crate::transforms::{self, TransformCache};
 use crate::db;
 use crate::errors;
 use crate::my_cookie_policy::MyCookieIdentityPolicy;
 use crate::my_identity_service::{login_user, Identity};
+use crate::password::PasswordSettings;
 
 use crate::utils;
 use std::fs;
-use std::io::Write;
 
-// use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use actix_multipart::Multipart;
 use futures::{StreamExt, TryStreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 pub async fn status() -> Result<HttpResponse, HandlerError> {
     let status = String::from("server is working!");
@@ -44,17 +56,102 @@ pub async fn get_user(id: Identity) -> Result<HttpResponse, HandlerError> {
     Ok(HttpResponse::build(StatusCode::OK).json(send_user))
 }
 
-pub async fn logout(id: Identity) -> Result<HttpResponse, HandlerError> {
+pub async fn logout(
+    id: Identity,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
     id.logout();
 
+    match pool.get().await {
+        Ok(client) => {
+            audit::record(
+                &client,
+                &req,
+                Some(&user),
+                AuditEvent::new("user.logout", "user", user.id),
+            )
+            .await
+        }
+        Err(e) => error!("Error occured: {}", e),
+    }
+
     Ok(HttpResponse::new(StatusCode::OK))
 }
 
+// Seconds a password login may be completed with a second factor
+pub const LOGIN_CHALLENGE_TTL: i64 = 5 * 60;
+
+pub async fn record_failed_login(
+    client: &deadpool_postgres::Client,
+    protection: &LoginProtection,
+    username: &str,
+    ip: &str,
+) {
+    warn!("Failed login for user {} from {}", username, ip);
+    if let Err(e) = db::record_failed_login(client, protection, username, ip).await {
+        error!("Recording failed login failed: {}", e);
+    }
+    let event = AuditEvent::new("user.login_failed", "user", username);
+    if let Err(e) = db::insert_audit_event(client, None, &event, ip).await {
+        error!("Writing audit log entry {} failed: {}", event.action, e);
+    }
+}
+
+// Fails while the username or ip is locked
+pub async fn check_login_lock(
+    client: &deadpool_postgres::Client,
+    username: &str,
+    ip: &str,
+) -> Result<(), HandlerError> {
+    let attempt_keys = vec![db::account_attempt_key(username), db::ip_attempt_key(ip)];
+    match db::get_login_lock(client, &attempt_keys).await {
+        Ok(Some(retry_after)) => {
+            info!(
+                "Login for user {} from {} rejected, locked for {}s",
+                username, ip, retry_after
+            );
+            Err(HandlerError::TooManyAttempts { retry_after })
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// Self registered accounts can only log in after an admin approved them
+pub async fn check_account_active(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+) -> Result<(), HandlerError> {
+    match db::get_user_status(client, user_id).await {
+        Ok(status) => {
+            if status == STATUS_ACTIVE {
+                Ok(())
+            } else {
+                Err(HandlerError::PermissionDenied {
+                    err_message: "Account is awaiting approval".to_string(),
+                })
+            }
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
 pub async fn login(
     data: web::Json<LoginData>,
     pool: web::Data<Pool>,
     req: HttpRequest,
     cookie_factory: web::Data<MyCookieIdentityPolicy>,
+    protection: web::Data<LoginProtection>,
+    mail_conf: web::Data<Mail>,
+    passwords: web::Data<PasswordSettings>,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -64,11 +161,15 @@ pub async fn login(
         }
     };
 
-    let user: User = match db::get_user_by_name(client, &data.username).await {
+    let ip = utils::client_ip(&req);
+    check_login_lock(&client, &data.username, &ip).await?;
+
+    let user: User = match db::get_user_by_name(&client, &data.username).await {
         Ok(user) => user,
         Err(e) => match e {
             errors::DBError::PostgresError(e) => {
                 error!("Getting user failed: {}", e);
+                record_failed_login(&client, &protection, &data.username, &ip).await;
                 return Err(HandlerError::AuthFail);
             }
             errors::DBError::MapperError(e) => {
@@ -91,6 +192,7 @@ pub async fn login(
     match user.verify_password(data.password.as_bytes()) {
         Ok(correct) => {
             if !correct {
+                record_failed_login(&client, &protection, &data.username, &ip).await;
                 return Err(HandlerError::AuthFail);
             }
         }
@@ -100,7 +202,72 @@ pub async fn login(
         }
     }
 
+    // The ip keeps its failures, other accounts guessed from it stay throttled
+    let attempt_keys = vec![db::account_attempt_key(&data.username)];
+    if let Err(e) = db::reset_login_attempts(&client, &attempt_keys).await {
+        error!("Resetting login attempts failed: {}", e);
+    }
+
+    check_account_active(&client, user.id).await?;
+
+    // The plain password is only known now, upgrade hashes made with older parameters
+    if passwords.needs_rehash(&user.password) {
+        match passwords.hash(&data.password) {
+            Ok(hashed_pwd) => match db::set_password_hash(&client, user.id, &hashed_pwd).await {
+                Ok(_) => info!("Upgraded password hash of user {}", user.username),
+                Err(e) => error!("Upgrading password hash failed: {}", e),
+            },
+            Err(e) => error!("Error occured: {}", e),
+        }
+    }
+
+    if mail_conf.require_email_verification {
+        match db::get_user_email(&client, user.id).await {
+            Ok((Some(_), false)) => {
+                return Err(HandlerError::PermissionDenied {
+                    err_message: "Email address is not verified".to_string(),
+                })
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        }
+    }
+
+    // With two factor authentication the password only unlocks the second step
+    let two_factor = match db::get_two_factor(&client, user.id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    if two_factor.totp_enabled {
+        let challenge =
+            match db::create_login_challenge(&client, user.id, LOGIN_CHALLENGE_TTL).await {
+                Ok(i) => i,
+                Err(e) => {
+                    error!("Error occured: {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            };
+        debug!("User {} needs a second factor", user.username);
+        return Ok(HttpResponse::build(StatusCode::ACCEPTED).json(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge,
+        }));
+    }
+
     debug!("User {} logged in successfully", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.login", "user", user.id),
+    )
+    .await;
     Ok(login_user(req, cookie_factory.get_ref(), user).await)
 }
 
@@ -108,10 +275,16 @@ pub async fn update_user_password(
     pool: web::Data<Pool>,
     id: Identity,
     data: web::Json<UpdateUserPassword>,
+    passwords: web::Data<PasswordSettings>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     // Get user identity
     let user: User = id.identity();
 
+    if let Err(field) = passwords.check(&data.password, &user.username) {
+        return Err(HandlerError::BadClientData { field });
+    }
+
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -128,9 +301,9 @@ pub async fn update_user_password(
         role: user.role,
     };
 
-    let result = db::update_user_password(&client, &new_user).await;
+    let result = db::update_user_password(&client, &new_user, &passwords).await;
 
-    match result {
+    let result = match result {
         Err(e) => match e {
             errors::DBError::PostgresError(e) => {
                 error!("Getting user failed: {}", e);
@@ -153,6 +326,13 @@ pub async fn update_user_password(
         },
         Ok(num_updated) => num_updated,
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&result),
+        AuditEvent::new("user.password_change", "user", result.id),
+    )
+    .await;
 
     Ok(HttpResponse::new(StatusCode::OK))
 }
@@ -161,6 +341,7 @@ pub async fn update_user_nickname(
     pool: web::Data<Pool>,
     id: Identity,
     data: web::Json<UpdateUserNickname>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     // Get user identity
     let user: User = id.identity();
@@ -173,6 +354,7 @@ pub async fn update_user_nickname(
         }
     };
 
+    let old_nickname = user.nickname.clone();
     let new_user = User {
         id: user.id,
         username: user.username,
@@ -183,7 +365,7 @@ pub async fn update_user_nickname(
 
     let result = db::update_user_nickname(&client, &new_user).await;
 
-    match result {
+    let result = match result {
         Err(e) => match e {
             errors::DBError::PostgresError(e) => {
                 error!("Getting user failed: {}", e);
@@ -206,6 +388,15 @@ pub async fn update_user_nickname(
         },
         Ok(num_updated) => num_updated,
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&result),
+        AuditEvent::new("user.update", "user", result.id)
+            .before(&serde_json::json!({ "nickname": old_nickname }))
+            .after(&serde_json::json!({ "nickname": result.nickname })),
+    )
+    .await;
 
     Ok(HttpResponse::new(StatusCode::OK))
 }
@@ -213,6 +404,7 @@ pub async fn update_user_nickname(
 pub async fn delete_user(
     pool: web::Data<Pool>,
     id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     // Get user identity
     let user: User = id.identity();
@@ -234,19 +426,131 @@ pub async fn delete_user(
         }
         Ok(num_updated) => num_updated,
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.delete", "user", user.id).before(&serde_json::json!({
+            "username": user.username,
+            "nickname": user.nickname,
+            "role": user.role,
+        })),
+    )
+    .await;
 
     Ok(HttpResponse::new(StatusCode::OK))
 }
 
+// Collects a multipart field, uploads are stored in one piece
+async fn read_field(
+    field: &mut actix_multipart::Field,
+    upload_conf: &Uploads,
+) -> Result<Bytes, HandlerError> {
+    let max_bytes = upload_conf.max_file_mb * 1024 * 1024;
+    let mut image = BytesMut::new();
+    while let Some(chunk) = field.next().await {
+        match chunk {
+            Ok(data) => {
+                if image.len() + data.len() > max_bytes {
+                    return Err(HandlerError::TooLarge {
+                        field: format!("files may have at most {} MB", upload_conf.max_file_mb),
+                    });
+                }
+                image.extend_from_slice(&data);
+            }
+            Err(e) => {
+                error!("Error reading upload : {}", e);
+                return Err(HandlerError::BadClientData {
+                    field: "Upload interrupted".to_string(),
+                });
+            }
+        }
+    }
+    Ok(image.freeze())
+}
+
+// Answer for a file with the content of an existing photo, linked to it or rejected by policy
+async fn duplicate_upload(
+    client: &deadpool_postgres::Client,
+    album: &Album,
+    user_id: i32,
+    existing: i32,
+    filename_original: String,
+) -> Result<UploadedPhoto, HandlerError> {
+    if album.duplicate_policy != DUPLICATES_LINK {
+        info!("{} is a duplicate of photo {}, rejected", filename_original, existing);
+        return Ok(UploadedPhoto {
+            id: None,
+            rejected: Some(format!("duplicate of photo {}", existing)),
+            original_filename: filename_original,
+            duplicate_of: Some(existing),
+        });
+    }
+    if let Err(e) =
+        db::link_duplicate_upload(client, existing, album.id, user_id, &filename_original).await
+    {
+        error!("Error occured : {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    info!("{} is a duplicate of photo {}, linked", filename_original, existing);
+    Ok(UploadedPhoto {
+        id: Some(existing),
+        original_filename: filename_original,
+        duplicate_of: Some(existing),
+        rejected: None,
+    })
+}
+
+// Decodes the upload, anything that is not an image is rejected
+async fn inspect_upload(
+    image: &Bytes,
+    filename: &str,
+    upload_conf: &Uploads,
+) -> Result<image_info::Inspected, HandlerError> {
+    let data = image.clone();
+    let name = filename.to_string();
+    let max_pixels = upload_conf.max_pixels;
+    match web::block(move || image_info::inspect(&data, &name, max_pixels)).await {
+        Ok(item) => Ok(item),
+        Err(BlockingError::Error(image::ImageError::Limits(_))) => {
+            info!("Rejected upload {}: more than {} pixels", filename, max_pixels);
+            Err(HandlerError::TooLarge {
+                field: format!("images may have at most {} pixels", max_pixels),
+            })
+        }
+        Err(e) => {
+            info!("Rejected upload {}: {}", filename, e);
+            Err(HandlerError::BadClientData {
+                field: format!("{} is not a supported image", filename),
+            })
+        }
+    }
+}
+
+async fn write_file(filepath: String, data: Bytes) -> Result<(), HandlerError> {
+    // filesystem operations are blocking, we have to use threadpool
+    match web::block(move || std::fs::write(filepath, &data)).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
 pub async fn post_photo(
     pool: web::Data<Pool>,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    inference_conf: web::Data<Inference>,
+    rendition_conf: web::Data<Renditions>,
+    upload_conf: web::Data<Uploads>,
     parameters: web::Path<(i32,)>,
     mut payload: Multipart,
     id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
-    let client = match pool.get().await {
+    let mut client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
             error!("Error occured : {}", e);
@@ -321,6 +625,7 @@ pub async fn post_photo(
         });
     }
 
+    let mut uploaded: Vec<UploadedPhoto> = Vec::new();
     while let Ok(Some(mut field)) = payload.try_next().await {
         // Get list of names from db and storage
         let mut filenames_storage: Vec<String> = Vec::new();
@@ -356,18 +661,46 @@ pub async fn post_photo(
         let new_filename = utils::calculate_next_filename_image(&filenames_storage, &filenames_db);
 
         let content_type = field.content_disposition().unwrap();
-        let filename_original = content_type.get_filename().unwrap();
-        let filename_clean = sanitize_filename::sanitize(&filename_original);
-        let vec: Vec<&str> = filename_clean.split(".").collect();
-        if vec.len() < 2 {
-            info!(
-                "Filename {} in payload has no extension. Skip.",
-                filename_original
-            );
-            continue;
+        let filename_original = content_type.get_filename().unwrap_or("").to_string();
+        // Whole image, needed for the checks, the renditions and the inference server.
+        // Google storage also takes an object only in one piece.
+        let image = read_field(&mut field, &upload_conf).await?;
+        let content_hash = utils::sha256_hex(&image);
+        let unique_content = result.duplicate_policy != DUPLICATES_ALLOW;
+        // Saves storing a known file, create_image_meta checks again under a lock
+        if unique_content {
+            match db::find_duplicate(&client, album_id, &content_hash, None).await {
+                Ok(Some(existing)) => {
+                    uploaded.push(
+                        duplicate_upload(&client, &result, user.id, existing, filename_original)
+                            .await?,
+                    );
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error occured : {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            }
         }
-        let file_extension = vec[vec.len() - 1];
-        let new_filename_with_ext = format!("{}.{}", new_filename, file_extension);
+        // One bad file does not fail the others of the request
+        let inspected = match inspect_upload(&image, &filename_original, &upload_conf).await {
+            Ok(item) => item,
+            Err(HandlerError::BadClientData { field }) => {
+                uploaded.push(UploadedPhoto {
+                    id: None,
+                    original_filename: filename_original,
+                    duplicate_of: None,
+                    rejected: Some(field),
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        // The stored type follows the content, whatever the client named the file
+        let new_filename_with_ext = format!("{}.{}", new_filename, inspected.extension);
 
         // Save file to storage
         if gg_storage_data.google_storage_enable {
@@ -375,76 +708,114 @@ pub async fn post_photo(
                 "new_filename_with_ext gg storage: {}",
                 &new_filename_with_ext
             );
-            // TODO: multipart is not really understand here
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                match gg_storage::upload_buffer_with_name_to_bucket(
-                    &client_r,
-                    &bearer_string,
-                    &bucket_name,
-                    &new_filename_with_ext,
-                    data,
-                )
-                .await
-                {
-                    Err(e) => {
-                        error!("Error uploading object to google storage {:?}", &e);
+            match gg_storage::upload_buffer_with_name_to_bucket(
+                &client_r,
+                &bearer_string,
+                &bucket_name,
+                &new_filename_with_ext,
+                image.clone(),
+            )
+            .await
+            {
+                Err(e) => {
+                    error!("Error uploading object to google storage {:?}", &e);
+                    return Err(HandlerError::InternalError);
+                }
+                Ok(response) => {
+                    if response.contains("error") {
+                        error!(
+                            "Error occured : error uploading object to google storage {:?}",
+                            &response
+                        );
                         return Err(HandlerError::InternalError);
                     }
-                    Ok(response) => {
-                        if response.contains("error") {
-                            error!(
-                                "Error occured : error uploading object to google storage {:?}",
-                                &response
-                            );
-                            return Err(HandlerError::InternalError);
-                        }
-                    }
                 }
             }
         } else {
             let filepath = format!("{}{}", album_path, new_filename_with_ext);
-            // File::create is blocking operation, use threadpool
-            // Write file
-            let mut f = web::block(|| std::fs::File::create(filepath))
-                .await
-                .unwrap();
-            // Field in turn is stream of *Bytes* object
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                // filesystem operations are blocking, we have to use threadpool
-                f = match web::block(move || f.write_all(&data).map(|_| f)).await {
-                    Ok(item) => item,
-                    Err(e) => {
-                        error!("Error occured : {}", e);
-                        return Err(HandlerError::InternalError);
-                    }
-                }
-            }
+            write_file(filepath, image.clone()).await?;
         }
+        let store = storage::Store::new(
+            gg_storage_data.google_storage_enable,
+            &client_r,
+            &bearer_string,
+            &bucket_name,
+            &album_path,
+        );
         // Write to db
         let image_meta = match db::create_image_meta(
-            &client,
+            &mut client,
             &CreateImageMeta {
                 album_id: album_id.clone(),
                 coordinates: "".to_string(),
                 file_path: new_filename_with_ext.clone(),
+                content_hash: content_hash.clone(),
+                perceptual_hash: inspected.perceptual_hash,
+                metadata: inspected.metadata,
             },
+            unique_content,
         )
         .await
         {
-            Ok(i) => {
+            Ok(Some(i)) => {
                 info!(
                     "Write meta data for {} to db success under {}",
                     filename_original, &new_filename_with_ext
                 );
                 i
             }
+            Ok(None) => {
+                // The same content was uploaded concurrently and got in first
+                store.delete(&new_filename_with_ext).await;
+                let duplicate = db::find_duplicate(&client, album_id, &content_hash, None).await;
+                let existing = match duplicate {
+                    Ok(Some(i)) => i,
+                    Ok(None) => {
+                        error!("Duplicate of {} vanished", filename_original);
+                        return Err(HandlerError::InternalError);
+                    }
+                    Err(e) => {
+                        error!("Error occured : {}", e);
+                        return Err(HandlerError::InternalError);
+                    }
+                };
+                uploaded.push(
+                    duplicate_upload(&client, &result, user.id, existing, filename_original)
+                        .await?,
+                );
+                continue;
+            }
             Err(e) => {
                 error!("Write file meta to db failed: {:?}", e);
                 return Err(HandlerError::InternalError);
             }
         };
+        audit::record(
+            &client,
+            &req,
+            Some(&user),
+            AuditEvent::new("photo.upload", "photo", image_meta.id).after(&image_meta),
+        )
+        .await;
+        uploaded.push(UploadedPhoto {
+            id: Some(image_meta.id),
+            original_filename: filename_original,
+            duplicate_of: None,
+            rejected: None,
+        });
+        if rendition_conf.enabled {
+            renditions::create(&rendition_conf, &store, &new_filename_with_ext, inspected.image)
+                .await;
+        }
+        if inference_conf.enabled {
+            inference::spawn(
+                inference_conf.get_ref().clone(),
+                pool.get_ref().clone(),
+                image_meta.id,
+                new_filename_with_ext.clone(),
+                image,
+            );
+        }
         if result.first_photo.is_none() {
             result = match db::album_set_first_image(&client, album_id, Some(image_meta.id)).await {
                 Ok(i) => i,
@@ -455,16 +826,20 @@ pub async fn post_photo(
             }
         }
     }
-    Ok(HttpResponse::build(StatusCode::OK).finish())
+    Ok(HttpResponse::build(StatusCode::OK).json(uploaded))
 }
 
 pub async fn put_photo(
     pool: web::Data<Pool>,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    rendition_conf: web::Data<Renditions>,
+    upload_conf: web::Data<Uploads>,
+    transform_cache: web::Data<TransformCache>,
     parameters: web::Path<(i32, i32)>,
     mut payload: Multipart,
     id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -563,18 +938,27 @@ pub async fn put_photo(
         }
 
         let content_type = field.content_disposition().unwrap();
-        let filename_original = content_type.get_filename().unwrap();
-        let filename_clean = sanitize_filename::sanitize(&filename_original);
-        let vec: Vec<&str> = filename_clean.split(".").collect();
-        if vec.len() < 2 {
-            info!(
-                "Filename {} in payload has no extension. Skip.",
-                filename_original
-            );
-            continue;
+        let filename_original = content_type.get_filename().unwrap_or("").to_string();
+        let image = read_field(&mut field, &upload_conf).await?;
+        // A replacement can't be linked, it would leave this photo without a file
+        let content_hash = utils::sha256_hex(&image);
+        if result.duplicate_policy != DUPLICATES_ALLOW {
+            match db::find_duplicate(&client, album_id, &content_hash, Some(image_id)).await {
+                Ok(Some(existing)) => {
+                    return Err(HandlerError::BadClientData {
+                        field: format!("{} is a duplicate of photo {}", filename_original, existing),
+                    })
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error occured : {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            }
         }
-        let file_extension = vec[vec.len() - 1];
-        let new_filename_with_ext = format!("{}.{}", new_filename, file_extension);
+        let inspected = inspect_upload(&image, &filename_original, &upload_conf).await?;
+
+        let new_filename_with_ext = format!("{}.{}", new_filename, inspected.extension);
         let filepath = format!("{}{}", album_path, new_filename_with_ext);
 
         // Save file to storage
@@ -596,31 +980,27 @@ pub async fn put_photo(
             // No need to delete object if existing in bucket.
 
             // Upload new object
-            // TODO: multipart is not really understand here
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                match gg_storage::upload_buffer_with_name_to_bucket(
-                    &client_r,
-                    &bearer_string,
-                    &bucket_name,
-                    &new_filename_with_ext,
-                    data,
-                )
-                .await
-                {
-                    Err(e) => {
-                        error!("Error PUTting object to google storage {:?}", &e);
+            match gg_storage::upload_buffer_with_name_to_bucket(
+                &client_r,
+                &bearer_string,
+                &bucket_name,
+                &new_filename_with_ext,
+                image.clone(),
+            )
+            .await
+            {
+                Err(e) => {
+                    error!("Error PUTting object to google storage {:?}", &e);
+                    return Err(HandlerError::InternalError);
+                }
+                Ok(response) => {
+                    if response.contains("error") {
+                        error!(
+                            "Error occured : error PUTting object to google storage {:?}",
+                            &response
+                        );
                         return Err(HandlerError::InternalError);
                     }
-                    Ok(response) => {
-                        if response.contains("error") {
-                            error!(
-                                "Error occured : error PUTting object to google storage {:?}",
-                                &response
-                            );
-                            return Err(HandlerError::InternalError);
-                        }
-                    }
                 }
             }
         } else {
@@ -644,23 +1024,22 @@ pub async fn put_photo(
                     }
                 }
             }
-            // File::create is blocking operation, use threadpool
-            // Write file
-            let mut f = web::block(|| std::fs::File::create(new_file_path))
-                .await
-                .unwrap();
-            // Field in turn is stream of *Bytes* object
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                // filesystem operations are blocking, we have to use threadpool
-                f = match web::block(move || f.write_all(&data).map(|_| f)).await {
-                    Ok(item) => item,
-                    Err(e) => {
-                        error!("Error occured : {}", e);
-                        return Err(HandlerError::InternalError);
-                    }
-                }
-            }
+            write_file(new_file_path, image.clone()).await?;
+        }
+
+        // Copies of the old image are outdated, even if the new one can't be rendered
+        let store = storage::Store::new(
+            gg_storage_data.google_storage_enable,
+            &client_r,
+            &bearer_string,
+            &bucket_name,
+            &album_path,
+        );
+        renditions::delete(&store, &file_path_db).await;
+        transforms::purge(&transform_cache, image_id).await;
+        if rendition_conf.enabled {
+            renditions::create(&rendition_conf, &store, &new_filename_with_ext, inspected.image)
+                .await;
         }
 
         // Update to db
@@ -670,15 +1049,29 @@ pub async fn put_photo(
                 album_id: album_id.clone(),
                 coordinates: "".to_string(),
                 file_path: new_filename_with_ext.clone(),
+                content_hash,
+                perceptual_hash: inspected.perceptual_hash,
+                metadata: inspected.metadata,
             },
             &image_id,
         )
         .await
         {
-            Ok(_) => info!(
-                "Update meta data for {} to db success under {}",
-                filename_original, &new_filename_with_ext
-            ),
+            Ok(image_meta) => {
+                info!(
+                    "Update meta data for {} to db success under {}",
+                    filename_original, &new_filename_with_ext
+                );
+                audit::record(
+                    &client,
+                    &req,
+                    Some(&user),
+                    AuditEvent::new("photo.replace", "photo", image_id)
+                        .before(&serde_json::json!({ "file_path": file_path_db }))
+                        .after(&image_meta),
+                )
+                .await;
+            }
             Err(e) => {
                 error!("Update file meta to db failed: {:?}", e);
                 return Err(HandlerError::InternalError);
@@ -693,7 +1086,10 @@ pub async fn get_photo(
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
     parameters: web::Path<(i32, i32)>,
+    query: web::Query<PhotoQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
+    let size = renditions::check_size(&query.size)?;
     let client = match pool.get().await {
         Ok(item) => item,
         Err(e) => {
@@ -766,52 +1162,160 @@ pub async fn get_photo(
         });
     }
 
-    let filepath = format!("{}{}", album_path, file_path_db);
-    let vec: Vec<&str> = file_path_db.split(".").collect();
-    let file_ext: &str = vec[1];
-    // Get image
-    if gg_storage_data.google_storage_enable {
-        let bytes = gg_storage::download_object_bytes_from_bucket(
-            &client_r,
-            &bearer_string,
-            &bucket_name,
-            &file_path_db,
-        )
-        .await;
-        let bb = match bytes {
-            Err(e) => {
-                error!("Error downloading object from google storage {:?}", &e);
-                return Err(HandlerError::InternalError);
-            }
-            Ok(b) => b,
-        };
-        Ok(HttpResponse::build(StatusCode::OK)
-            .content_type(format!("image/{}", file_ext))
-            .body(bb))
-    } else {
-        // Check file exist
-        if !std::path::Path::new(&filepath).exists() {
+    let store = storage::Store::new(
+        gg_storage_data.google_storage_enable,
+        &client_r,
+        &bearer_string,
+        &bucket_name,
+        &album_path,
+    );
+    // Photos without a rendition, e.g. not yet backfilled, are served in full size
+    if let Some(size) = size {
+        let rendition = renditions::rendition_name(&file_path_db, size);
+        if let Some(response) = store.serve(&req, &rendition, "image/jpeg").await? {
+            return Ok(response);
+        }
+    }
+
+    let content_type = image_info::content_type(&client, image_id, &file_path_db).await;
+    match store.serve(&req, &file_path_db, &content_type).await? {
+        Some(response) => Ok(response),
+        None => {
             error!(
-                "Error occured : Image file with id={} not found on disk",
-                &filepath
+                "Error occured : Image file {} of album id={} not found in storage",
+                &file_path_db, &album_id
             );
+            Err(HandlerError::BadClientData {
+                field: format!("File {} not found in storage", file_path_db),
+            })
+        }
+    }
+}
+
+// photo file behind a signed url, needs no session
+pub async fn get_signed_photo(
+    tagify_albums_path: web::Data<String>,
+    signer: web::Data<UrlSigner>,
+    parameters: web::Path<(i32, String)>,
+    query: web::Query<SignedQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let (album_id, file_path) = (parameters.0, &parameters.1);
+    if !signer.verify_local(album_id, file_path, query.expires, &query.signature) {
+        return Err(HandlerError::PermissionDenied {
+            err_message: "Invalid or expired url".to_string(),
+        });
+    }
+
+    let album_path = format!("{}{}/", tagify_albums_path.to_string(), album_id);
+    let store = storage::Store::Local {
+        album_path: &album_path,
+    };
+    let content_type = gg_storage::mime_type(&utils::get_file_ext(file_path));
+    match store.serve(&req, file_path, content_type).await? {
+        Some(response) => Ok(response),
+        None => Err(HandlerError::BadClientData {
+            field: format!("File {} not found in storage", file_path),
+        }),
+    }
+}
+
+// resized, cropped, rotated or converted photo, cached on disk
+pub async fn get_photo_transform(
+    pool: web::Data<Pool>,
+    tagify_albums_path: web::Data<String>,
+    gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    cache: web::Data<TransformCache>,
+    parameters: web::Path<(i32, i32)>,
+    query: web::Query<TransformQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    if !cache.conf.enabled {
+        return Err(HandlerError::BadClientData {
+            field: "Image transformations are disabled".to_string(),
+        });
+    }
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let album_id = parameters.0;
+    let image_id = parameters.1;
+    let file_path_db =
+        db::get_image_file_path_with_id_from_album(&client, &album_id, &image_id).await;
+    if file_path_db.is_empty() {
+        return Err(HandlerError::BadClientData {
+            field: format!(
+                "Image with id={} of album id={} not found in db",
+                &image_id, &album_id
+            ),
+        });
+    }
+    let transform = transforms::parse(&query, &cache.conf, &file_path_db)?;
+    let name = transform.cache_name(image_id, &file_path_db);
+    let cached = storage::Store::Local {
+        album_path: cache.path(),
+    };
+    if cache.touch(&name) {
+        if let Some(response) = cached.serve(&req, &name, transform.content_type()).await? {
+            return Ok(response);
+        }
+    }
+
+    let bearer_string = match storage::read_bearer_string(gg_storage_data.google_storage_enable) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error reading oauth_key.txt  : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let client_r = reqwest::Client::new();
+    let bucket_name = format!("{}{}", gg_storage::PREFIX_BUCKET, &album_id);
+    let album_path = format!("{}{}/", tagify_albums_path.to_string(), &album_id);
+    let store = storage::Store::new(
+        gg_storage_data.google_storage_enable,
+        &client_r,
+        &bearer_string,
+        &bucket_name,
+        &album_path,
+    );
+    let original = match store.get(&file_path_db).await {
+        Some(item) => item,
+        None => {
             return Err(HandlerError::BadClientData {
-                field: format!("File {} not found on disk", filepath).to_string(),
-            });
+                field: format!("File {} not found in storage", file_path_db),
+            })
         }
+    };
 
-        // let mut bb: Vec<u8> = Vec::new();
-        let bb: Vec<u8> = match std::fs::read(filepath) {
-            Err(e) => {
-                error!("Error openning local file {:?}", &e);
-                return Err(HandlerError::InternalError);
+    let apply = transform.clone();
+    let data = match web::block(move || apply.apply(&original)).await {
+        Ok(item) => item,
+        Err(BlockingError::Error(e)) => return Err(e),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    // Served from the cache for ETag and Range support, directly if it could not be stored
+    let store_cache = cache.clone();
+    let (store_name, store_data) = (name.clone(), data.clone());
+    match web::block(move || store_cache.store(&store_name, &store_data)).await {
+        Ok(_) => {
+            if let Some(response) = cached.serve(&req, &name, transform.content_type()).await? {
+                return Ok(response);
             }
-            Ok(bytes) => bytes,
-        };
-        Ok(HttpResponse::build(StatusCode::OK)
-            .content_type(format!("image/{}", file_ext))
-            .body(bb))
+        }
+        Err(e) => error!("Error caching transform {}: {}", name, e),
     }
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type(transform.content_type())
+        .body(data))
 }
 
 #[allow(unused_assignments)]
@@ -819,8 +1323,10 @@ pub async fn delete_photo(
     pool: web::Data<Pool>,
     tagify_albums_path: web::Data<String>,
     gg_storage_data: web::Data<gg_storage::GoogleStorage>,
+    transform_cache: web::Data<TransformCache>,
     parameters: web::Path<(i32, i32)>,
     id: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse, HandlerError> {
     let client = match pool.get().await {
         Ok(item) => item,
@@ -909,6 +1415,19 @@ pub async fn delete_photo(
         });
     }
 
+    renditions::delete(
+        &storage::Store::new(
+            gg_storage_data.google_storage_enable,
+            &client_r,
+            &bearer_string,
+            &bucket_name,
+            &album_path,
+        ),
+        &file_path_db,
+    )
+    .await;
+    transforms::purge(&transform_cache, image_id).await;
+
     // Delete file from storage
     if gg_storage_data.google_storage_enable {
         match gg_storage::delete_object_from_bucket(
@@ -960,6 +1479,16 @@ pub async fn delete_photo(
             return Err(HandlerError::InternalError);
         }
     };
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("photo.delete", "photo", image_id).before(&serde_json::json!({
+            "album_id": album_id,
+            "file_path": file_path_db,
+        })),
+    )
+    .await;
 
     if let Some(first_photo) = result.first_photo {
         debug!("first_photo: {} == image_id: {}", first_photo, image_id);
@@ -0,0 +1,151 @@
+// Verifies uploads by decoding them and reads their properties and EXIF fields
+use crate::album_models::PhotoMetadata;
+use crate::db;
+use crate::duplicates;
+use crate::gg_storage;
+use crate::utils;
+use exif::{Exif, In, Tag, Value};
+use image::error::{ImageFormatHint, LimitError, LimitErrorKind};
+use image::io::Reader;
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat};
+use std::io::Cursor;
+
+pub struct Inspected {
+    pub metadata: PhotoMetadata,
+    /// Extension matching the decoded format, stored files get this one and not the client's
+    pub extension: &'static str,
+    pub perceptual_hash: i64,
+    pub image: DynamicImage,
+}
+
+fn format_names(format: ImageFormat) -> Option<(&'static str, &'static str)> {
+    match format {
+        ImageFormat::Png => Some(("image/png", "png")),
+        ImageFormat::Jpeg => Some(("image/jpeg", "jpg")),
+        ImageFormat::Gif => Some(("image/gif", "gif")),
+        ImageFormat::Bmp => Some(("image/bmp", "bmp")),
+        ImageFormat::Tiff => Some(("image/tiff", "tiff")),
+        ImageFormat::WebP => Some(("image/webp", "webp")),
+        _ => None,
+    }
+}
+
+/// Fails for anything that is not a fully decodable image, or has more than max_pixels.
+/// Blocking, call it through web::block.
+pub fn inspect(
+    data: &[u8],
+    original_filename: &str,
+    max_pixels: u64,
+) -> Result<Inspected, ImageError> {
+    let format = image::guess_format(data)?;
+    let (mime_type, extension) = format_names(format)
+        .ok_or_else(|| ImageError::Unsupported(ImageFormatHint::Exact(format).into()))?;
+    // Only the header is read here, so a small file can't make us allocate a huge image
+    let (width, height) = Reader::with_format(Cursor::new(data), format).into_dimensions()?;
+    if u64::from(width) * u64::from(height) > max_pixels {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    let image = image::load_from_memory_with_format(data, format)?;
+
+    let mut metadata = PhotoMetadata {
+        width: Some(image.width() as i32),
+        height: Some(image.height() as i32),
+        mime_type: Some(mime_type.to_string()),
+        byte_size: Some(data.len() as i64),
+        original_filename: Some(original_filename.to_string()),
+        ..PhotoMetadata::default()
+    };
+    // Most images carry no EXIF at all, that is no reason to reject them
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        read_exif(&exif, &mut metadata);
+    }
+
+    Ok(Inspected {
+        metadata,
+        extension,
+        perceptual_hash: duplicates::dhash(&image),
+        image,
+    })
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|v| {
+                String::from_utf8_lossy(v)
+                    .trim_matches(char::from(0))
+                    .trim()
+                    .to_string()
+            })
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    }
+}
+
+// Degrees, minutes and seconds to signed decimal degrees
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+    let parts = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) if parts.len() == 3 => parts.clone(),
+        _ => return None,
+    };
+    let degrees = parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0;
+    if !degrees.is_finite() {
+        return None;
+    }
+    match ascii(exif, ref_tag) {
+        Some(ref r) if r.eq_ignore_ascii_case(negative_ref) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+fn read_exif(exif: &Exif, metadata: &mut PhotoMetadata) {
+    metadata.taken_at = [Tag::DateTimeOriginal, Tag::DateTime]
+        .iter()
+        .filter_map(|tag| match &exif.get_field(*tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok(),
+            _ => None,
+        })
+        .next()
+        .map(|t| {
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                t.year, t.month, t.day, t.hour, t.minute, t.second
+            )
+        });
+    metadata.camera_make = ascii(exif, Tag::Make);
+    metadata.camera_model = ascii(exif, Tag::Model);
+    metadata.gps_latitude = coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    metadata.gps_longitude = coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    metadata.orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .map(|v| v as i32);
+}
+
+/// EXIF orientation, 1 to 8, None if the image has none
+pub fn orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+    exif.get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Recorded MIME type of a photo, guessed from the file name for older photos
+pub async fn content_type(
+    client: &deadpool_postgres::Client,
+    photo_id: i32,
+    file_path: &str,
+) -> String {
+    match db::get_photo_metadata(client, photo_id).await {
+        Ok(Some(PhotoMetadata {
+            mime_type: Some(mime_type),
+            ..
+        })) => mime_type,
+        _ => gg_storage::mime_type(&utils::get_file_ext(file_path)).to_string(),
+    }
+}
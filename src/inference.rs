@@ -0,0 +1,98 @@
+// Sends uploaded images to an external inference server and keeps its predictions as suggestions
+use crate::config::Inference;
+use crate::db;
+use crate::gg_storage;
+use crate::utils;
+use bytes::Bytes;
+use deadpool_postgres::Pool;
+use log::{error, info};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+pub struct Prediction {
+    pub label: String,
+    pub confidence: f32,
+    /// Same format as image_metas.coordinates, either a string or any JSON value
+    #[serde(default)]
+    pub coordinates: serde_json::Value,
+}
+
+impl Prediction {
+    pub fn coordinates_text(&self) -> String {
+        match &self.coordinates {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Expected answer of the inference server
+#[derive(Debug, Deserialize)]
+pub struct InferenceResponse {
+    #[serde(default)]
+    pub model: String,
+    pub predictions: Vec<Prediction>,
+}
+
+async fn request(
+    conf: &Inference,
+    photo_id: i32,
+    file_name: &str,
+    image: Bytes,
+) -> Result<InferenceResponse, reqwest::Error> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(conf.timeout))
+        .build()?;
+    let mut request = client
+        .post(&conf.url)
+        .headers(gg_storage::construct_headers_image(utils::get_file_ext(
+            file_name,
+        )))
+        .header("X-Tagify-Photo-Id", photo_id.to_string())
+        .body(image);
+    if !conf.api_key.is_empty() {
+        request = request.bearer_auth(&conf.api_key);
+    }
+    request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<InferenceResponse>()
+        .await
+}
+
+/// Runs in the background, the upload does not wait for the inference server.
+pub fn spawn(conf: Inference, pool: Pool, photo_id: i32, file_name: String, image: Bytes) {
+    actix_rt::spawn(async move {
+        let response = match request(&conf, photo_id, &file_name, image).await {
+            Ok(item) => item,
+            Err(e) => {
+                error!("Inference for photo {} failed: {}", photo_id, e);
+                return;
+            }
+        };
+        let predictions: Vec<Prediction> = response
+            .predictions
+            .into_iter()
+            .filter(|p| p.confidence >= conf.min_confidence)
+            .collect();
+
+        let client = match pool.get().await {
+            Ok(item) => item,
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return;
+            }
+        };
+        match db::insert_photo_suggestions(&client, photo_id, &response.model, &predictions).await {
+            Ok(()) => info!(
+                "Stored {} suggestions for photo {}",
+                predictions.len(),
+                photo_id
+            ),
+            Err(e) => error!("Storing suggestions for photo {} failed: {}", photo_id, e),
+        }
+    });
+}
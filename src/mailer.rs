@@ -0,0 +1,96 @@
+// Outgoing mail, SMTP in production and a log file during development
+use crate::config::Mail;
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::ClientSecurity;
+use lettre::{SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use log::info;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Sends plain text mails. Implementations may block, call them from `web::block`.
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    tls: bool,
+    username: String,
+    password: String,
+    from: String,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let email = EmailBuilder::new()
+            .to(to)
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(body)
+            .build()
+            .map_err(|e| format!("Could not build mail: {}", e))?;
+
+        // TLS uses the submission port, without it any port (e.g. a local mailhog) works
+        let client = if self.tls {
+            SmtpClient::new_simple(&self.host)
+        } else {
+            SmtpClient::new((self.host.as_str(), self.port), ClientSecurity::None)
+        }
+        .map_err(|e| format!("Could not connect to {}: {}", self.host, e))?;
+        let client = if self.username.is_empty() {
+            client
+        } else {
+            client.credentials(Credentials::new(
+                self.username.clone(),
+                self.password.clone(),
+            ))
+        };
+
+        let mut transport = client.transport();
+        let result = transport.send(email.into());
+        transport.close();
+        result
+            .map(|_| ())
+            .map_err(|e| format!("Sending mail to {} failed: {}", to, e))
+    }
+}
+
+// Appends every mail to a file instead of sending it
+pub struct LogMailer {
+    path: String,
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        info!(
+            "Mail to {} with subject '{}' written to {}",
+            to, subject, self.path
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Could not open {}: {}", self.path, e))?;
+        writeln!(file, "To: {}\nSubject: {}\n\n{}\n----", to, subject, body)
+            .map_err(|e| format!("Could not write {}: {}", self.path, e))
+    }
+}
+
+pub fn from_config(conf: &Mail) -> Arc<dyn Mailer> {
+    match conf.backend.as_str() {
+        "smtp" => Arc::new(SmtpMailer {
+            host: conf.smtp_host.clone(),
+            port: conf.smtp_port,
+            tls: conf.smtp_tls,
+            username: conf.smtp_username.clone(),
+            password: conf.smtp_password.clone(),
+            from: conf.from.clone(),
+        }),
+        _ => Arc::new(LogMailer {
+            path: conf.log_file.clone(),
+        }),
+    }
+}
@@ -19,23 +19,44 @@ mod config;
 mod db;
 mod errors;
 mod handlers;
+mod mailer;
 
 #[cfg(not(debug_assertions))]
 mod letsencrypt;
 #[cfg(not(debug_assertions))]
 use letsencrypt::LetsEncrypt;
 
+mod account_handlers;
 mod admin_handlers;
 mod album_handlers;
+mod audit;
+mod batch_handlers;
+mod comment_handlers;
+mod duplicates;
 mod gg_storage;
+mod image_info;
+mod inference;
 mod my_cookie_policy;
 mod my_identity_service;
+mod renditions;
+mod score_handlers;
+mod signed_urls;
+mod stats_handlers;
+mod storage;
+mod transforms;
 mod utils;
 
 mod album_models;
+mod audit_models;
+mod stats_models;
 mod user_models;
 
 mod oauth;
+mod oidc;
+mod oidc_handlers;
+mod password;
+mod totp;
+mod two_factor_handlers;
 
 use crate::handlers::{login, logout, status};
 use crate::oauth::Oauth;
@@ -54,6 +75,37 @@ async fn admin_index(data: web::Data<DistPath>) -> Result<NamedFile> {
     Ok(NamedFile::open(data.admin.clone())?)
 }
 
+fn cookie_policy(
+    key: &[u8],
+    name: &str,
+    session: &config::Session,
+) -> my_cookie_policy::MyCookieIdentityPolicy {
+    // Without an absolute lifetime keep the cookie for 30 days in the browser
+    let max_age = if session.max_lifetime > 0 {
+        session.max_lifetime
+    } else {
+        30 * 24 * 60 * 60
+    };
+
+    let mut policy = my_cookie_policy::MyCookieIdentityPolicy::new(key)
+        .name(name)
+        .path("/")
+        .secure(session.secure)
+        .max_age(max_age)
+        .same_site(actix_http::cookie::SameSite::Strict);
+    if let Some(domain) = &session.domain {
+        policy = policy.domain(domain.clone());
+    }
+    // Sliding session, the visit timestamp is refreshed on every request
+    if session.idle_timeout > 0 {
+        policy = policy.visit_deadline(time::Duration::seconds(session.idle_timeout));
+    }
+    if session.max_lifetime > 0 {
+        policy = policy.login_deadline(time::Duration::seconds(session.max_lifetime));
+    }
+    policy
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     if cfg!(debug_assertions) {
@@ -150,24 +202,43 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    let passwords = web::Data::new(password::PasswordSettings::load(
+        &conf.password_policy,
+        &conf_path,
+    ));
+
     // Build server address
     let ip = conf.server.hostname.clone() + ":" + &conf.server.port;
     println!("Server is reachable at http://{}", ip);
 
-    // Create default admin accounts
-    match db::create_user(&client, &conf.default_admin).await {
-        Ok(_item) => info!("Created default admin account"),
-        Err(_e) => info!("Default user already exists"),
-    }
+    if conf.registration.seed_default_accounts {
+        // Create default admin accounts
+        if let Some(default_admin) = &conf.default_admin {
+            if let Err(e) = passwords.check(&default_admin.password, &default_admin.username) {
+                error!("Default admin account not created: {}", e);
+            } else {
+                match db::create_user(&client, default_admin, &passwords).await {
+                    Ok(_item) => info!("Created default admin account"),
+                    Err(_e) => info!("Default user already exists"),
+                }
+            }
+        }
 
-    // // Create default user accounts
-    match db::create_user(&client, &conf.default_user).await {
-        Ok(_item) => info!("Created default user"),
-        Err(_e) => info!("Default user already exists"),
+        // // Create default user accounts
+        if let Some(default_user) = &conf.default_user {
+            if let Err(e) = passwords.check(&default_user.password, &default_user.username) {
+                error!("Default user account not created: {}", e);
+            } else {
+                match db::create_user(&client, default_user, &passwords).await {
+                    Ok(_item) => info!("Created default user"),
+                    Err(_e) => info!("Default user already exists"),
+                }
+            }
+        }
     }
 
     // Create data folder tagify_data. Default: in code base folder
-    let tagify_data_path = conf.tagify_data.path;
+    let tagify_data_path = conf.tagify_data.path.clone();
     let tagify_albums_path = format!("{}/albums/", &tagify_data_path);
 
     match std::fs::create_dir_all(&tagify_albums_path) {
@@ -180,6 +251,54 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    // Maintenance commands run instead of the server, all take optional album ids:
+    // backfill-renditions, backfill-hashes, backfill-phashes
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        let album_ids: Vec<i32> = match args[1..].iter().map(|a| a.parse()).collect() {
+            Ok(i) => i,
+            Err(e) => {
+                error!("Album ids must be numbers: {}", e);
+                std::process::exit(2);
+            }
+        };
+        let google_storage_enable = gg_storage_data.google_storage_enable;
+        let result = match command.as_str() {
+            "backfill-renditions" => {
+                renditions::backfill(
+                    &client,
+                    &conf.renditions,
+                    &tagify_albums_path,
+                    google_storage_enable,
+                    &album_ids,
+                )
+                .await
+            }
+            "backfill-hashes" | "backfill-phashes" => {
+                duplicates::backfill(
+                    &client,
+                    &tagify_albums_path,
+                    google_storage_enable,
+                    command == "backfill-phashes",
+                    &album_ids,
+                )
+                .await
+            }
+            _ => {
+                error!("Unknown command {}", command);
+                std::process::exit(2);
+            }
+        };
+        match result {
+            Ok(count) => info!("{}: {} photos done", command, count),
+            Err(e) => {
+                error!("{} failed: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     #[cfg(not(debug_assertions))]
     let (encrypter, ssl_builder) = if conf.cert.activate {
         error!("Setup encrypter and ssl_builder!");
@@ -218,13 +337,31 @@ async fn main() -> std::io::Result<()> {
     };
 
     let temp = conf.server.key.clone();
+    let session_conf = conf.session.clone();
+    let login_protection = conf.login_protection.clone();
+    let oidc_conf = conf.oidc.clone();
+    let mail_conf = conf.mail.clone();
+    let registration_conf = conf.registration.clone();
+    let mailer = mailer::from_config(&conf.mail);
+    let inference_conf = conf.inference.clone();
+    let rendition_conf = conf.renditions.clone();
+    let upload_conf = conf.uploads.clone();
+    let url_signer = match signed_urls::UrlSigner::new(&conf.signed_urls, &conf.tagify_data) {
+        Ok(signer) => signer,
+        Err(e) => {
+            error!("Invalid signed_urls settings: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let transform_cache = web::Data::new(transforms::TransformCache::new(
+        conf.transforms.clone(),
+        format!("{}/transform_cache/", &tagify_data_path),
+    ));
 
     // Register http routes
     let mut server = HttpServer::new(move || {
         let serve_file_service: fs::Files;
         let path_arg: DistPath;
-        let secure_cookie: bool;
-        let max_age = 30 * 24 * 60 * 60; // days
 
         #[cfg(not(debug_assertions))]
         let nonce_req =
@@ -243,7 +380,6 @@ async fn main() -> std::io::Result<()> {
                 user: PathBuf::from("../frontend/debug_dist/index.html"),
                 admin: PathBuf::from("../frontend/debug_dist/index_admin.html"),
             };
-            secure_cookie = false;
         } else {
             // If release binary use DIST env var
             let dist = std::env::var("DIST").expect("Could not find environment variable DIST");
@@ -258,29 +394,30 @@ async fn main() -> std::io::Result<()> {
                 );
             }
             serve_file_service = fs::Files::new("/app/frontend/dist", dist).show_files_listing();
-            secure_cookie = false;
         }
         let cookie_key = temp.as_bytes();
 
-        let cookie_factory_user = my_cookie_policy::MyCookieIdentityPolicy::new(cookie_key)
-            .name(ROLES[1])
-            .path("/")
-            .secure(secure_cookie)
-            .max_age(max_age)
-            .same_site(actix_http::cookie::SameSite::Strict);
-
-        let cookie_factory_admin = my_cookie_policy::MyCookieIdentityPolicy::new(cookie_key)
-            .name(ROLES[0])
-            .path("/")
-            .secure(secure_cookie)
-            .max_age(max_age)
-            .same_site(actix_http::cookie::SameSite::Strict);
+        let cookie_factory_user = cookie_policy(cookie_key, ROLES[1], &session_conf);
+
+        let cookie_factory_admin = cookie_policy(cookie_key, ROLES[0], &session_conf);
         App::new()
             // Compress middlware
             .wrap(middleware::Compress::default())
             .data(path_arg)
             // Give login handler access to cookie factory
             .data(cookie_factory_user.clone())
+            .data(login_protection.clone())
+            .data(session_conf.clone())
+            .data(oidc_conf.clone())
+            .data(mail_conf.clone())
+            .data(registration_conf.clone())
+            .app_data(passwords.clone())
+            .data(mailer.clone())
+            .data(inference_conf.clone())
+            .data(rendition_conf.clone())
+            .data(upload_conf.clone())
+            .data(url_signer.clone())
+            .app_data(transform_cache.clone())
             // Serve every file in directory from ../dist
             .service(serve_file_service)
             // Serve index.html
@@ -305,6 +442,30 @@ async fn main() -> std::io::Result<()> {
                     //all admin endpoints
                     .service(web::resource("/status").route(web::get().to(status)))
                     .service(web::resource("/login").route(web::post().to(login)))
+                    .service(
+                        web::resource("/login/2fa")
+                            .route(web::post().to(two_factor_handlers::login_two_factor)),
+                    )
+                    // single sign-on
+                    .route("/oidc/login", web::get().to(oidc_handlers::login))
+                    .route("/oidc/callback", web::get().to(oidc_handlers::callback))
+                    // password reset and email verification
+                    .route(
+                        "/password/forgot",
+                        web::post().to(account_handlers::forgot_password),
+                    )
+                    .route(
+                        "/password/reset",
+                        web::post().to(account_handlers::reset_password),
+                    )
+                    .route("/email/verify", web::get().to(account_handlers::verify_email))
+                    // self registration
+                    .route("/register", web::post().to(account_handlers::register))
+                    // photo files behind signed urls
+                    .route(
+                        "/signed/{album_id}/{file_path}",
+                        web::get().to(handlers::get_signed_photo),
+                    )
                     .service(
                         web::scope("/admin")
                             .wrap(my_identity_service::IdentityService::new(
@@ -316,9 +477,60 @@ async fn main() -> std::io::Result<()> {
                             .route("/users", web::get().to(admin_handlers::get_all_users))
                             //create new user account
                             .route("/users", web::post().to(admin_handlers::create_user))
+                            // registrations waiting for approval
+                            .route(
+                                "/users/pending",
+                                web::get().to(admin_handlers::get_pending_users),
+                            )
+                            .route(
+                                "/users/pending/{user_id}/approve",
+                                web::post().to(admin_handlers::approve_user),
+                            )
+                            .route(
+                                "/users/pending/{user_id}/reject",
+                                web::post().to(admin_handlers::reject_user),
+                            )
+                            // audit log
+                            .route("/audit", web::get().to(admin_handlers::get_audit_log))
+                            // identical files, within and across albums
+                            .route(
+                                "/duplicates",
+                                web::get().to(admin_handlers::get_duplicates),
+                            )
+                            // invite codes for registration
+                            .route(
+                                "/invites",
+                                web::get().to(admin_handlers::get_invite_codes),
+                            )
+                            .route(
+                                "/invites",
+                                web::post().to(admin_handlers::create_invite_code),
+                            )
+                            .route(
+                                "/invites/{code}",
+                                web::delete().to(admin_handlers::delete_invite_code),
+                            )
                             //get user by id
                             .route("/user/{user_id}", web::get().to(status))
                             .route("/me", web::get().to(handlers::get_user))
+                            // two-factor authentication
+                            .route("/me/2fa", web::get().to(two_factor_handlers::get_status))
+                            .route("/me/2fa", web::post().to(two_factor_handlers::enroll))
+                            .route("/me/2fa", web::delete().to(two_factor_handlers::disable))
+                            .route("/me/2fa/verify", web::post().to(two_factor_handlers::verify))
+                            .route(
+                                "/me/2fa/recovery_codes",
+                                web::post().to(two_factor_handlers::regenerate_recovery_codes),
+                            )
+                            // enforce two-factor authentication per role
+                            .route(
+                                "/2fa/policy",
+                                web::get().to(two_factor_handlers::get_policies),
+                            )
+                            .route(
+                                "/2fa/policy",
+                                web::put().to(two_factor_handlers::set_policy),
+                            )
                             //change user password
                             .route(
                                 "/user/{user_id}",
@@ -329,6 +541,11 @@ async fn main() -> std::io::Result<()> {
                                 "/user/{user_id}",
                                 web::delete().to(admin_handlers::delete_user),
                             )
+                            // unlock account after failed logins
+                            .route(
+                                "/user/{user_id}/lock",
+                                web::delete().to(admin_handlers::unlock_user),
+                            )
                             .service(
                                 web::scope("/albums")
                                     //get all albums
@@ -348,6 +565,10 @@ async fn main() -> std::io::Result<()> {
                                         "/{album_id}/photos/{photo_id}",
                                         web::get().to(admin_handlers::get_photo),
                                     )
+                                    .route(
+                                        "/{album_id}/photos/{photo_id}/transform",
+                                        web::get().to(handlers::get_photo_transform),
+                                    )
                                     .route(
                                         "/{album_id}/photos/{photo_id}",
                                         web::delete().to(admin_handlers::delete_photo),
@@ -381,11 +602,37 @@ async fn main() -> std::io::Result<()> {
                             .route("/me", web::delete().to(handlers::delete_user))
                             //update only nickname
                             .route("/me", web::put().to(handlers::update_user_nickname))
+                            // two-factor authentication
+                            .route("/me/2fa", web::get().to(two_factor_handlers::get_status))
+                            .route("/me/2fa", web::post().to(two_factor_handlers::enroll))
+                            .route("/me/2fa", web::delete().to(two_factor_handlers::disable))
+                            .route("/me/2fa/verify", web::post().to(two_factor_handlers::verify))
+                            .route(
+                                "/me/2fa/recovery_codes",
+                                web::post().to(two_factor_handlers::regenerate_recovery_codes),
+                            )
                             //update password
                             .route(
                                 "/me/password",
                                 web::put().to(handlers::update_user_password),
                             )
+                            .route(
+                                "/me/email",
+                                web::put().to(account_handlers::update_email),
+                            )
+                            .route(
+                                "/me/accuracy",
+                                web::get().to(album_handlers::get_own_accuracy),
+                            )
+                            .route("/me/stats", web::get().to(stats_handlers::get_own_stats))
+                            .route(
+                                "/me/batches",
+                                web::get().to(batch_handlers::get_own_batches),
+                            )
+                            .route(
+                                "/leaderboard",
+                                web::get().to(stats_handlers::get_leaderboard),
+                            )
                             .service(
                                 web::scope("/albums")
                                     //download json file with tags
@@ -415,6 +662,70 @@ async fn main() -> std::io::Result<()> {
                                     //     web::delete().to(status),
                                     // ),
                                     /////////////////////////////////////
+                                    //photos waiting for review
+                                    .route(
+                                        "/{album_id}/review",
+                                        web::get().to(album_handlers::get_review_queue),
+                                    )
+                                    //review results per tagger
+                                    .route(
+                                        "/{album_id}/review/stats",
+                                        web::get().to(album_handlers::get_tagger_stats),
+                                    )
+                                    //progress and productivity of own album
+                                    .route(
+                                        "/{album_id}/stats",
+                                        web::get().to(stats_handlers::get_album_stats),
+                                    )
+                                    .route(
+                                        "/{album_id}/leaderboard",
+                                        web::get().to(stats_handlers::get_album_leaderboard),
+                                    )
+                                    //split the album into batches for single users
+                                    .route(
+                                        "/{album_id}/batches",
+                                        web::get().to(batch_handlers::get_album_batches),
+                                    )
+                                    .route(
+                                        "/{album_id}/batches",
+                                        web::post().to(batch_handlers::create_batch),
+                                    )
+                                    .route(
+                                        "/{album_id}/batches/{batch_id}",
+                                        web::delete().to(batch_handlers::delete_batch),
+                                    )
+                                    .route(
+                                        "/{album_id}/batches/{batch_id}/assign",
+                                        web::put().to(batch_handlers::assign_batch),
+                                    )
+                                    //bulk upload of model confidences (JSON or CSV)
+                                    .route(
+                                        "/{album_id}/scores",
+                                        web::post()
+                                            .to(score_handlers::upload_scores)
+                                            .data(web::PayloadConfig::new(
+                                                score_handlers::MAX_SCORE_UPLOAD,
+                                            )),
+                                    )
+                                    .route(
+                                        "/{album_id}/scores/history",
+                                        web::get().to(score_handlers::get_album_score_history),
+                                    )
+                                    //weights for the priority queue
+                                    .route(
+                                        "/{album_id}/priorities",
+                                        web::put().to(album_handlers::set_priorities),
+                                    )
+                                    //gold item accuracy per user
+                                    .route(
+                                        "/{album_id}/gold/accuracy",
+                                        web::get().to(album_handlers::get_album_accuracy),
+                                    )
+                                    //open flags of own album
+                                    .route(
+                                        "/{album_id}/flags",
+                                        web::get().to(comment_handlers::get_album_flags),
+                                    )
                                     .route(
                                         "/{album_id}/photos",
                                         web::post().to(handlers::post_photo),
@@ -423,6 +734,18 @@ async fn main() -> std::io::Result<()> {
                                         "/{album_id}/photos/{photo_id}",
                                         web::get().to(handlers::get_photo),
                                     )
+                                    .route(
+                                        "/{album_id}/photos/{photo_id}/metadata",
+                                        web::get().to(album_handlers::get_photo_metadata),
+                                    )
+                                    .route(
+                                        "/{album_id}/photos/{photo_id}/transform",
+                                        web::get().to(handlers::get_photo_transform),
+                                    )
+                                    .route(
+                                        "/{album_id}/near_duplicates",
+                                        web::get().to(album_handlers::get_near_duplicates),
+                                    )
                                     .route(
                                         "/{album_id}/photos/{photo_id}",
                                         web::put().to(handlers::put_photo),
@@ -434,7 +757,7 @@ async fn main() -> std::io::Result<()> {
                             )
                             .service(
                                 web::scope("/tag")
-                                    //get 15 photos for tagging
+                                    //get next photos for tagging, ?batch_size= defaults to 20
                                     .route(
                                         "/{album_id}",
                                         web::get().to(album_handlers::get_photos_for_tagging),
@@ -448,6 +771,62 @@ async fn main() -> std::io::Result<()> {
                                     .route(
                                         "/verify/{photo_id}",
                                         web::put().to(album_handlers::verify_photo_by_id),
+                                    )
+                                    //list earlier labels of a photo
+                                    .route(
+                                        "/history/{photo_id}",
+                                        web::get().to(album_handlers::get_tag_history),
+                                    )
+                                    //restore an earlier label
+                                    .route(
+                                        "/revert/{photo_id}",
+                                        web::post().to(album_handlers::revert_tag),
+                                    )
+                                    //accept, reject or correct a tagged photo
+                                    .route(
+                                        "/review/{photo_id}",
+                                        web::put().to(album_handlers::review_photo),
+                                    )
+                                    //tag a photo with a suggestion of the inference server
+                                    .route(
+                                        "/suggestions/{suggestion_id}/accept",
+                                        web::post().to(album_handlers::accept_suggestion),
+                                    )
+                                    //mark a verified photo as gold item
+                                    .route(
+                                        "/gold/{photo_id}",
+                                        web::put().to(album_handlers::set_gold),
+                                    ),
+                            )
+                            .service(
+                                web::scope("/photos")
+                                    .route(
+                                        "/{photo_id}/comments",
+                                        web::get().to(comment_handlers::get_comments),
+                                    )
+                                    .route(
+                                        "/{photo_id}/comments",
+                                        web::post().to(comment_handlers::create_comment),
+                                    )
+                                    .route(
+                                        "/{photo_id}/flags",
+                                        web::get().to(comment_handlers::get_photo_flags),
+                                    )
+                                    .route(
+                                        "/{photo_id}/suggestions",
+                                        web::get().to(album_handlers::get_suggestions),
+                                    )
+                                    .route(
+                                        "/{photo_id}/scores",
+                                        web::get().to(score_handlers::get_photo_score_history),
+                                    )
+                                    .route(
+                                        "/{photo_id}/flags",
+                                        web::post().to(comment_handlers::create_flag),
+                                    )
+                                    .route(
+                                        "/flags/{flag_id}/resolve",
+                                        web::put().to(comment_handlers::resolve_flag),
                                     ),
                             ),
                     )
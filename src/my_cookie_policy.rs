@@ -5,12 +5,13 @@ use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::error::{Error, Result};
 use actix_web::HttpMessage;
-use futures::future::{ok, Ready};
+use futures::future::{err, ok, Ready};
 use serde::{Deserialize, Serialize};
 use std::rc::Rc;
 use std::time::SystemTime;
 use time::Duration;
 
+use crate::errors::HandlerError;
 use crate::user_models::User;
 
 struct MyCookieIdentityInner {
@@ -114,40 +115,49 @@ impl MyCookieIdentityInner {
         Ok(())
     }
 
-    fn load(&self, req: &ServiceRequest) -> Option<CookieValue> {
-        let cookie = req.cookie(&self.name)?;
+    fn load(&self, req: &ServiceRequest) -> Result<Option<CookieValue>, HandlerError> {
+        let cookie = match req.cookie(&self.name) {
+            Some(cookie) => cookie,
+            None => return Ok(None),
+        };
         let mut jar = CookieJar::new();
         jar.add_original(cookie.clone());
-        let res = if self.legacy_supported() {
-            jar.private(&self.key).get(&self.name).map(|n| CookieValue {
-                identity: n.value().to_string(),
-                login_timestamp: None,
-                visit_timestamp: None,
-            })
-        } else {
-            None
-        };
-        res.or_else(|| {
-            jar.private(&self.key_v2)
-                .get(&self.name)
-                .and_then(|c| self.parse(c))
-        })
+        if self.legacy_supported() {
+            if let Some(n) = jar.private(&self.key).get(&self.name) {
+                return Ok(Some(CookieValue {
+                    identity: n.value().to_string(),
+                    login_timestamp: None,
+                    visit_timestamp: None,
+                }));
+            }
+        }
+        match jar.private(&self.key_v2).get(&self.name) {
+            Some(c) => self.parse(c),
+            None => Ok(None),
+        }
     }
 
-    fn parse(&self, cookie: Cookie) -> Option<CookieValue> {
-        let value: CookieValue = serde_json::from_str(cookie.value()).ok()?;
+    fn parse(&self, cookie: Cookie) -> Result<Option<CookieValue>, HandlerError> {
+        let value: CookieValue = match serde_json::from_str(cookie.value()) {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        };
         let now = SystemTime::now();
         if let Some(visit_deadline) = self.visit_deadline {
-            if now.duration_since(value.visit_timestamp?).ok()? > visit_deadline.to_std().ok()? {
-                return None;
+            if deadline_exceeded(now, value.visit_timestamp, visit_deadline) {
+                return Err(HandlerError::SessionExpired {
+                    reason: "no activity for too long, please log in again".to_owned(),
+                });
             }
         }
         if let Some(login_deadline) = self.login_deadline {
-            if now.duration_since(value.login_timestamp?).ok()? > login_deadline.to_std().ok()? {
-                return None;
+            if deadline_exceeded(now, value.login_timestamp, login_deadline) {
+                return Err(HandlerError::SessionExpired {
+                    reason: "maximum session lifetime reached, please log in again".to_owned(),
+                });
             }
         }
-        Some(value)
+        Ok(Some(value))
     }
 
     fn legacy_supported(&self) -> bool {
@@ -163,6 +173,20 @@ impl MyCookieIdentityInner {
     }
 }
 
+// A missing timestamp means the cookie was issued before the deadline was configured
+fn deadline_exceeded(now: SystemTime, timestamp: Option<SystemTime>, deadline: Duration) -> bool {
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return true,
+    };
+    match (now.duration_since(timestamp), deadline.to_std()) {
+        (Ok(elapsed), Ok(deadline)) => elapsed > deadline,
+        // Timestamp lies in the future (clock skew), accept it
+        (Err(_), _) => false,
+        (_, Err(_)) => true,
+    }
+}
+
 impl MyCookieIdentityPolicy {
     /// Construct new `MyCookieIdentityPolicy` instance.
     ///
@@ -237,7 +261,11 @@ impl IdentityPolicy for MyCookieIdentityPolicy {
     type ResponseFuture = Ready<Result<(), Error>>;
 
     fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
-        ok(self.0.load(req).map(
+        let value = match self.0.load(req) {
+            Ok(value) => value,
+            Err(e) => return err(e.into()),
+        };
+        ok(value.map(
             |CookieValue {
                  identity,
                  login_timestamp,
@@ -272,11 +300,12 @@ impl IdentityPolicy for MyCookieIdentityPolicy {
             )
         } else if self.0.always_update_cookie() && id.is_some() {
             let visit_timestamp = SystemTime::now();
+            // parse only stores the extension for cookies it accepted, carry over what is there
             let login_timestamp = if self.0.requires_oob_data() {
-                let CookieIdentityExtention {
-                    login_timestamp: lt,
-                } = res.request().extensions_mut().remove().unwrap();
-                lt
+                res.request()
+                    .extensions_mut()
+                    .remove::<CookieIdentityExtention>()
+                    .and_then(|e| e.login_timestamp)
             } else {
                 None
             };
@@ -294,4 +323,8 @@ impl IdentityPolicy for MyCookieIdentityPolicy {
         };
         ok(())
     }
+
+    fn remove_cookie<B>(&self, res: &mut ServiceResponse<B>) -> Result<(), Error> {
+        self.0.set_cookie(res, None, &self.0.name)
+    }
 }
@@ -9,15 +9,16 @@ use actix_service::{Service, Transform};
 use futures::future::{ok, FutureExt, LocalBoxFuture, Ready};
 
 use actix_http::{Response, ResponseBuilder};
+use actix_web::cookie::Cookie;
 use actix_web::dev::{Extensions, Payload, ServiceRequest, ServiceResponse};
 use actix_web::error::{Error, Result};
-use actix_web::http::StatusCode;
+use actix_web::http::{header, Method, StatusCode};
 use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use log::{debug, error};
 
 use deadpool_postgres::Pool;
 
-use crate::db::get_user_by_name;
+use crate::db::{get_two_factor, get_user_by_name, two_factor_enforced};
 use crate::errors::HandlerError;
 use crate::my_cookie_policy::MyCookieIdentityPolicy;
 use crate::user_models::SendUser;
@@ -26,11 +27,48 @@ use crate::user_models::User;
 #[derive(Clone)]
 pub struct Identity(HttpRequest);
 
-pub async fn login_user(
+// Routes a user without the enforced second factor may still use to enroll
+fn allowed_without_two_factor(method: &Method, path: &str) -> bool {
+    match *method {
+        Method::GET => path.ends_with("/me") || path.ends_with("/me/2fa"),
+        Method::POST => {
+            path.ends_with("/me/2fa")
+                || path.ends_with("/me/2fa/verify")
+                || path.ends_with("/logout")
+        }
+        _ => false,
+    }
+}
+
+async fn missing_two_factor(
+    client: &deadpool_postgres::Client,
+    user: &User,
+) -> Result<bool, Error> {
+    let enforced = match two_factor_enforced(client, &user.role).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("two_factor_enforced failed {}", e);
+            return Err(HandlerError::InternalError.into());
+        }
+    };
+    if !enforced {
+        return Ok(false);
+    }
+    match get_two_factor(client, user.id).await {
+        Ok(i) => Ok(!i.totp_enabled),
+        Err(e) => {
+            error!("get_two_factor failed {}", e);
+            Err(HandlerError::InternalError.into())
+        }
+    }
+}
+
+// Session cookies identifying the user on subsequent requests
+async fn login_cookies(
     req: HttpRequest,
     cookie_factory: &MyCookieIdentityPolicy,
-    user: User,
-) -> Response {
+    user: &User,
+) -> Vec<Cookie<'static>> {
     let mut resp = ServiceResponse::new(req, HttpResponse::new(StatusCode::OK));
     if let Some(id) = resp.request().extensions_mut().get_mut::<IdentityItem>() {
         id.user = Some(user.clone());
@@ -47,10 +85,19 @@ pub async fn login_user(
         Err(e) => error!("Could not set cookie {}", e),
     }
     let login = resp.response();
+    login.cookies().map(|c| c.into_owned()).collect()
+}
+
+pub async fn login_user(
+    req: HttpRequest,
+    cookie_factory: &MyCookieIdentityPolicy,
+    user: User,
+) -> Response {
+    let cookies = login_cookies(req, cookie_factory, &user).await;
 
     let mut resp = ResponseBuilder::new(StatusCode::OK);
 
-    for c in login.cookies() {
+    for c in cookies {
         resp.cookie(c);
     }
 
@@ -63,6 +110,23 @@ pub async fn login_user(
     resp.json(send_user)
 }
 
+// Log the user in and send the browser on to `location`, used by redirect based logins
+pub async fn login_user_redirect(
+    req: HttpRequest,
+    cookie_factory: &MyCookieIdentityPolicy,
+    user: User,
+    location: &str,
+) -> Response {
+    let cookies = login_cookies(req, cookie_factory, &user).await;
+
+    let mut resp = ResponseBuilder::new(StatusCode::FOUND);
+
+    for c in cookies {
+        resp.cookie(c);
+    }
+    resp.header(header::LOCATION, location).finish()
+}
+
 impl Identity {
     /// Return the claimed identity of the user associated request or
     /// ``None`` if no identity can be found associated with the request.
@@ -141,6 +205,9 @@ pub trait IdentityPolicy: Sized + 'static {
         cookie_name: &str,
         response: &mut ServiceResponse<B>,
     ) -> Self::ResponseFuture;
+
+    /// Expire the session cookie, for requests whose session was rejected
+    fn remove_cookie<B>(&self, response: &mut ServiceResponse<B>) -> Result<(), Error>;
 }
 
 pub struct IdentityService<T> {
@@ -240,7 +307,7 @@ where
                     };
 
                     debug!("Username in cookie is {}", id);
-                    let user: User = match get_user_by_name(client, &id).await {
+                    let user: User = match get_user_by_name(&client, &id).await {
                         Ok(user) => user,
                         Err(e) => {
                             error!("get_user failed {}", e);
@@ -249,6 +316,16 @@ where
                     };
 
                     debug!("Extracted user is: {:?}", user);
+
+                    if !allowed_without_two_factor(req.method(), req.path()) {
+                        match missing_two_factor(&client, &user).await {
+                            Ok(false) => {}
+                            Ok(true) => {
+                                return Ok(req.error_response(HandlerError::TwoFactorRequired))
+                            }
+                            Err(e) => return Ok(req.error_response(e)),
+                        }
+                    }
                     let cookie_name = user.role.clone();
 
                     req.extensions_mut().insert(IdentityItem {
@@ -284,7 +361,12 @@ where
                 }
                 Err(err) => {
                     error!("from_request failed: {}", err);
-                    Ok(req.error_response(err))
+                    // Otherwise the rejected cookie fails every later request, logout included
+                    let mut res = req.error_response(err);
+                    if let Err(e) = backend.remove_cookie(&mut res) {
+                        error!("remove_cookie failed: {}", e);
+                    }
+                    Ok(res)
                 }
             }
         }
@@ -0,0 +1,363 @@
+use crate::config::{Oidc, Session};
+use crate::errors::HandlerError;
+use crate::handlers::{check_account_active, LOGIN_CHALLENGE_TTL};
+use crate::my_cookie_policy::MyCookieIdentityPolicy;
+use crate::my_identity_service::login_user_redirect;
+use crate::oidc::{self, IdentityClaims};
+use crate::password::PasswordSettings;
+use crate::user_models::{CreateUser, OidcCallback, User, ROLES};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Result};
+use deadpool_postgres::Pool;
+use log::{debug, error, info};
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+use crate::utils;
+
+// Seconds the user has to finish the login at the identity provider
+const STATE_TTL: i64 = 10 * 60;
+
+// Binds the state to the browser that started the login, against login CSRF
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+
+fn state_cookie(state: &str, secure: bool, max_age: i64) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, state.to_string())
+        .path(STATE_COOKIE_PATH)
+        .secure(secure)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
+
+// Usernames and nicknames have to be between 4 and 64 characters
+fn account_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '.' || *c == '_' || *c == '-')
+        .take(56)
+        .collect();
+    while name.chars().count() < 4 {
+        name.push('_');
+    }
+    name
+}
+
+async fn unique_username(
+    client: &deadpool_postgres::Client,
+    claims: &IdentityClaims,
+) -> Result<String, HandlerError> {
+    let hint = match (&claims.username, &claims.email) {
+        (Some(username), _) => username.clone(),
+        (None, Some(email)) => email.split('@').next().unwrap_or_default().to_string(),
+        (None, None) => "sso_user".to_string(),
+    };
+    let base = account_name(&hint);
+
+    for i in 0..100 {
+        let candidate = if i == 0 {
+            base.clone()
+        } else {
+            format!("{}{}", base, i)
+        };
+        match db::check_username_exists(client, &candidate).await {
+            Ok(false) => return Ok(candidate),
+            Ok(true) => {}
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        }
+    }
+    error!("No free username found for {}", base);
+    Err(HandlerError::InternalError)
+}
+
+// Find the account of the identity, link it by verified email or create a new one
+async fn provision_user(
+    client: &deadpool_postgres::Client,
+    claims: &IdentityClaims,
+    conf: &Oidc,
+    passwords: &PasswordSettings,
+) -> Result<User, HandlerError> {
+    let role = if claims.is_admin { ROLES[0] } else { ROLES[1] };
+    // Without a configured role claim the role of existing accounts is managed here only
+    let mapped_role = if conf.role_claim.is_empty() || conf.admin_claim_value.is_empty() {
+        None
+    } else {
+        Some(role)
+    };
+
+    let existing = match db::get_user_by_oidc_subject(client, &claims.subject).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    // Linking by email needs the address verified here as well, set_oidc_identity checks it
+    let (existing, by_email) = match (existing, &claims.email) {
+        (Some(user), _) => (Some(user), false),
+        (None, Some(email)) if claims.email_verified => {
+            match db::get_user_by_email(client, email).await {
+                Ok(i) => {
+                    if let Some(user) = &i {
+                        info!("Linking account {} to {}", user.username, claims.subject);
+                    }
+                    (i, true)
+                }
+                Err(e) => {
+                    error!("Error occured: {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            }
+        }
+        (None, _) => (None, false),
+    };
+
+    let user = match existing {
+        Some(user) => user,
+        None => {
+            let username = unique_username(client, claims).await?;
+            let nickname = account_name(claims.name.as_ref().unwrap_or(&username));
+            // The password is never handed out, these accounts log in through the provider
+            let new_user = CreateUser {
+                username,
+                password: utils::random_token(),
+                nickname,
+                role: role.to_string(),
+                email: None,
+            };
+            match db::create_user(client, &new_user, passwords).await {
+                Ok(i) => {
+                    info!("Provisioned account {} for {}", i.username, claims.subject);
+                    i
+                }
+                Err(e) => {
+                    error!("Error occured: {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            }
+        }
+    };
+
+    let email = if claims.email_verified {
+        claims.email.as_deref()
+    } else {
+        None
+    };
+    match db::set_oidc_identity(
+        client,
+        user.id,
+        &claims.subject,
+        email,
+        mapped_role,
+        by_email,
+    )
+    .await
+    {
+        Ok(Some(i)) => Ok(i),
+        Ok(None) => {
+            info!(
+                "Refused to link account {} to {}",
+                user.username, claims.subject
+            );
+            Err(HandlerError::PermissionDenied {
+                err_message: "An account with this email address exists. Log in with its password \
+                              and verify the email address to link it."
+                    .to_string(),
+            })
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// Redirect the browser to the identity provider
+pub async fn login(
+    pool: web::Data<Pool>,
+    conf: web::Data<Oidc>,
+    session_conf: web::Data<Session>,
+) -> Result<HttpResponse, HandlerError> {
+    if !conf.enabled {
+        return Err(HandlerError::BadClientData {
+            field: "Single sign-on is not enabled".to_string(),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let client_r = reqwest::Client::new();
+    let meta = match oidc::discover(&client_r, &conf).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("OpenID discovery failed: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let state = utils::random_token();
+    let nonce = utils::random_token();
+    if let Err(e) = db::create_oidc_state(&client, &state, &nonce, STATE_TTL).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+
+    let url = match oidc::authorization_url(&meta, &conf, &state, &nonce) {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    Ok(HttpResponse::build(StatusCode::FOUND)
+        .cookie(state_cookie(&state, session_conf.secure, STATE_TTL))
+        .header(header::LOCATION, url)
+        .finish())
+}
+
+// The identity provider sends the browser back here with an authorization code
+pub async fn callback(
+    pool: web::Data<Pool>,
+    conf: web::Data<Oidc>,
+    session_conf: web::Data<Session>,
+    query: web::Query<OidcCallback>,
+    req: HttpRequest,
+    cookie_factory: web::Data<MyCookieIdentityPolicy>,
+    passwords: web::Data<PasswordSettings>,
+) -> Result<HttpResponse, HandlerError> {
+    if !conf.enabled {
+        return Err(HandlerError::BadClientData {
+            field: "Single sign-on is not enabled".to_string(),
+        });
+    }
+    if let Some(err) = &query.error {
+        error!(
+            "Identity provider returned {}: {}",
+            err,
+            query.error_description.as_deref().unwrap_or("")
+        );
+        return Err(HandlerError::AuthFail);
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(HandlerError::BadClientData {
+                field: "code and state are required".to_string(),
+            })
+        }
+    };
+    match req.cookie(STATE_COOKIE) {
+        Some(cookie) if cookie.value() == state.as_str() => {}
+        _ => {
+            error!("OpenID state does not match the state cookie");
+            return Err(HandlerError::AuthFail);
+        }
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let nonce = match db::take_oidc_state(&client, state, STATE_TTL).await {
+        Ok(Some(i)) => i,
+        Ok(None) => {
+            error!("Unknown or expired OpenID state");
+            return Err(HandlerError::AuthFail);
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let client_r = reqwest::Client::new();
+    let meta = match oidc::discover(&client_r, &conf).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("OpenID discovery failed: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let id_token = match oidc::exchange_code(&client_r, &meta, &conf, code).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("{}", e);
+            return Err(HandlerError::AuthFail);
+        }
+    };
+    let claims = match oidc::validate_id_token(&id_token, &meta, &conf, &nonce) {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Rejected id token: {}", e);
+            return Err(HandlerError::AuthFail);
+        }
+    };
+
+    let user = provision_user(&client, &claims, &conf, &passwords).await?;
+    check_account_active(&client, user.id).await?;
+
+    let location = if conf.post_login_redirect.is_empty() {
+        "/"
+    } else {
+        conf.post_login_redirect.as_str()
+    };
+    let clear_state = state_cookie("", session_conf.secure, 0);
+
+    // Like the password, the identity provider only unlocks the second step. The challenge
+    // goes in the fragment so it is not sent on to any server.
+    let two_factor = match db::get_two_factor(&client, user.id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    if two_factor.totp_enabled {
+        let challenge =
+            match db::create_login_challenge(&client, user.id, LOGIN_CHALLENGE_TTL).await {
+                Ok(i) => i,
+                Err(e) => {
+                    error!("Error occured: {}", e);
+                    return Err(HandlerError::InternalError);
+                }
+            };
+        debug!("User {} needs a second factor", user.username);
+        return Ok(HttpResponse::build(StatusCode::FOUND)
+            .cookie(clear_state)
+            .header(
+                header::LOCATION,
+                format!("{}#two_factor_challenge={}", location, challenge),
+            )
+            .finish());
+    }
+
+    debug!("User {} logged in via single sign-on", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.login_sso", "user", user.id),
+    )
+    .await;
+    let mut resp = login_user_redirect(req, cookie_factory.get_ref(), user, location).await;
+    if let Err(e) = resp.add_cookie(&clear_state) {
+        error!("Could not clear the OpenID state cookie: {}", e);
+    }
+    Ok(resp)
+}
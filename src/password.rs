@@ -0,0 +1,108 @@
+// Password policy and argon2 hashing parameters
+use crate::config::PasswordPolicy;
+use argon2::{Config, Variant, Version};
+use log::{error, info};
+use rand::Rng;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+pub struct PasswordSettings {
+    policy: PasswordPolicy,
+    variant: Variant,
+    common_passwords: HashSet<String>,
+}
+
+impl PasswordSettings {
+    /// Reads the common password list, a relative path is resolved against `conf_path`.
+    pub fn load(policy: &PasswordPolicy, conf_path: &Path) -> Self {
+        let variant = match Variant::from_str(&policy.hash_variant) {
+            Ok(i) => i,
+            Err(_) => panic!("Unknown argon2 variant {}", policy.hash_variant),
+        };
+
+        let mut common_passwords = HashSet::new();
+        if !policy.common_passwords_file.is_empty() {
+            let list_path = conf_path.join(&policy.common_passwords_file);
+            match fs::read_to_string(&list_path) {
+                Ok(list) => {
+                    common_passwords = list
+                        .lines()
+                        .map(|l| l.trim().to_lowercase())
+                        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                        .collect();
+                    info!("Loaded {} common passwords", common_passwords.len());
+                }
+                Err(e) => error!(
+                    "Could not read common password list {}: {}",
+                    list_path.to_str().unwrap_or_default(),
+                    e
+                ),
+            }
+        }
+
+        PasswordSettings {
+            policy: policy.clone(),
+            variant,
+            common_passwords,
+        }
+    }
+
+    /// Returns a message for the user if the password does not satisfy the policy.
+    pub fn check(&self, password: &str, username: &str) -> Result<(), String> {
+        let length = password.chars().count();
+        if length < self.policy.min_length {
+            return Err(format!(
+                "Password must be at least {} characters long",
+                self.policy.min_length
+            ));
+        }
+        if length > self.policy.max_length {
+            return Err(format!(
+                "Password must be at most {} characters long",
+                self.policy.max_length
+            ));
+        }
+        let lowercase = password.to_lowercase();
+        if self.policy.reject_username
+            && !username.is_empty()
+            && lowercase.contains(&username.to_lowercase())
+        {
+            return Err("Password must not contain the username".to_string());
+        }
+        if self.common_passwords.contains(&lowercase) {
+            return Err("Password is too common".to_string());
+        }
+        Ok(())
+    }
+
+    fn config(&self) -> Config<'static> {
+        Config {
+            variant: self.variant,
+            version: Version::Version13,
+            mem_cost: self.policy.hash_memory_cost,
+            time_cost: self.policy.hash_time_cost,
+            lanes: self.policy.hash_lanes,
+            ..Config::default()
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, argon2::Error> {
+        let salt: [u8; 32] = rand::thread_rng().gen();
+        argon2::hash_encoded(password.as_bytes(), &salt, &self.config())
+    }
+
+    /// True if `encoded` was hashed with other parameters than the configured ones.
+    pub fn needs_rehash(&self, encoded: &str) -> bool {
+        // $argon2i$v=19$m=4096,t=3,p=1$<salt>$<hash>
+        let parts: Vec<&str> = encoded.split('$').collect();
+        if parts.len() != 6 {
+            return true;
+        }
+        let expected = format!(
+            "m={},t={},p={}",
+            self.policy.hash_memory_cost, self.policy.hash_time_cost, self.policy.hash_lanes
+        );
+        parts[1] != self.variant.as_lowercase_str() || parts[2] != "v=19" || parts[3] != expected
+    }
+}
@@ -0,0 +1,151 @@
+// Downscaled JPEG copies of uploaded photos, stored next to the originals
+use crate::config::Renditions;
+use crate::db;
+use crate::errors::{DBError, HandlerError};
+use crate::gg_storage;
+use crate::storage::{self, Store};
+use actix_web::web;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use log::{error, info, warn};
+
+pub const SIZE_THUMB: &str = "thumb";
+pub const SIZE_MEDIUM: &str = "medium";
+pub const SIZE_ORIGINAL: &str = "original";
+pub const SIZES: [&str; 2] = [SIZE_THUMB, SIZE_MEDIUM];
+
+/// Size asked for with `?size=`, None stands for the original
+pub fn check_size(size: &Option<String>) -> Result<Option<&'static str>, HandlerError> {
+    match size.as_deref() {
+        None | Some(SIZE_ORIGINAL) => Ok(None),
+        Some(SIZE_THUMB) => Ok(Some(SIZE_THUMB)),
+        Some(SIZE_MEDIUM) => Ok(Some(SIZE_MEDIUM)),
+        Some(_) => Err(HandlerError::BadClientData {
+            field: "size must be thumb, medium or original".to_string(),
+        }),
+    }
+}
+
+/// "12.png" becomes "12_thumb.jpg". Not a number, so it never takes part in the file naming.
+pub fn rendition_name(file_path: &str, size: &str) -> String {
+    let stem = file_path.rsplitn(2, '.').last().unwrap_or(file_path);
+    format!("{}_{}.jpg", stem, size)
+}
+
+/// Encodes a JPEG per size. Blocking, so call it through web::block.
+pub fn render(
+    conf: &Renditions,
+    original: &DynamicImage,
+) -> Result<Vec<(&'static str, Vec<u8>)>, image::ImageError> {
+    let mut renditions = Vec::new();
+    for size in SIZES.iter() {
+        let edge = if *size == SIZE_THUMB {
+            conf.thumb_size
+        } else {
+            conf.medium_size
+        };
+        // Never upscale, small images are only re-encoded
+        let scaled = if original.width() > edge || original.height() > edge {
+            original.resize(edge, edge, FilterType::Triangle)
+        } else {
+            original.clone()
+        };
+        let mut buffer = Vec::new();
+        DynamicImage::ImageRgb8(scaled.to_rgb8())
+            .write_to(&mut buffer, ImageOutputFormat::Jpeg(conf.jpeg_quality))?;
+        renditions.push((*size, buffer));
+    }
+    Ok(renditions)
+}
+
+/// Renders and stores every size of a decoded photo. Failures are only logged, the original
+/// is already stored and keeps being served instead.
+pub async fn create(
+    conf: &Renditions,
+    store: &Store<'_>,
+    file_path: &str,
+    original: DynamicImage,
+) -> bool {
+    let render_conf = conf.clone();
+    let renditions = match web::block(move || render(&render_conf, &original)).await {
+        Ok(item) => item,
+        Err(e) => {
+            warn!("Could not render {}: {}", file_path, e);
+            return false;
+        }
+    };
+    let mut stored = true;
+    for (size, data) in renditions {
+        stored &= store.put(&rendition_name(file_path, size), data).await;
+    }
+    stored
+}
+
+pub async fn delete(store: &Store<'_>, file_path: &str) {
+    for size in SIZES.iter() {
+        store.delete(&rendition_name(file_path, size)).await;
+    }
+}
+
+/// Renders the missing copies of all photos, or only of the given albums.
+/// Returns how many photos got new copies.
+pub async fn backfill(
+    client: &deadpool_postgres::Client,
+    conf: &Renditions,
+    tagify_albums_path: &str,
+    google_storage_enable: bool,
+    album_ids: &[i32],
+) -> Result<usize, DBError> {
+    let client_r = reqwest::Client::new();
+    let bearer_string = match storage::read_bearer_string(google_storage_enable) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error reading oauth_key.txt  : {}", e);
+            return Ok(0);
+        }
+    };
+
+    let mut rendered = 0;
+    for (album_id, file_path) in db::get_image_files(client, album_ids).await? {
+        let album_path = format!("{}{}/", tagify_albums_path, album_id);
+        let bucket_name = format!("{}{}", gg_storage::PREFIX_BUCKET, album_id);
+        let store = Store::new(
+            google_storage_enable,
+            &client_r,
+            &bearer_string,
+            &bucket_name,
+            &album_path,
+        );
+
+        let mut missing = false;
+        for size in SIZES.iter() {
+            if !store.exists(&rendition_name(&file_path, size)).await {
+                missing = true;
+                break;
+            }
+        }
+        if !missing {
+            continue;
+        }
+
+        let image = match store.get(&file_path).await {
+            Some(item) => item,
+            None => {
+                warn!("Album {}: {} not found in storage", album_id, file_path);
+                continue;
+            }
+        };
+        let original = match web::block(move || image::load_from_memory(&image)).await {
+            Ok(item) => item,
+            Err(e) => {
+                warn!("Album {}: could not decode {}: {}", album_id, file_path, e);
+                continue;
+            }
+        };
+        if create(conf, &store, &file_path, original).await {
+            info!("Album {}: rendered {}", album_id, file_path);
+            rendered += 1;
+        }
+    }
+    Ok(rendered)
+}
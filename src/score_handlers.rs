@@ -0,0 +1,208 @@
+use crate::album_handlers::check_album_owner;
+use crate::album_models::{ScoreEntry, ScoreQuery, ScoreUpload, QUEUE_UNCERTAINTY};
+use crate::errors::HandlerError;
+use crate::my_identity_service::Identity;
+use crate::user_models::User;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use bytes::Bytes;
+use deadpool_postgres::Pool;
+use log::error;
+use serde_json::json;
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+
+/// Body limit of a score upload
+pub const MAX_SCORE_UPLOAD: usize = 16 * 1024 * 1024;
+const MAX_SCORE_ROWS: usize = 200_000;
+
+/// Expects a header line naming a `score` column and a `photo_id` or `file_path` column.
+fn parse_csv(body: &str) -> Result<Vec<ScoreEntry>, String> {
+    let mut lines = body.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    let header: Vec<String> = match lines.next() {
+        Some(line) => line
+            .split(',')
+            .map(|c| c.trim().trim_matches('"').to_lowercase())
+            .collect(),
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| header.iter().position(|c| c == name);
+    let score_column = column("score").ok_or("CSV needs a score column")?;
+    let id_column = column("photo_id");
+    let path_column = column("file_path");
+    if id_column.is_none() && path_column.is_none() {
+        return Err("CSV needs a photo_id or file_path column".to_string());
+    }
+
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let values: Vec<&str> = line
+            .split(',')
+            .map(|v| v.trim().trim_matches('"'))
+            .collect();
+        let value = |column: Option<usize>| {
+            column
+                .and_then(|c| values.get(c))
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+        };
+        let row = i + 2;
+        let score = match value(Some(score_column)).map(|v| v.parse::<f32>()) {
+            Some(Ok(score)) => score,
+            _ => return Err(format!("Invalid score in line {}", row)),
+        };
+        let photo_id = match value(id_column).map(|v| v.parse::<i32>()) {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Err(format!("Invalid photo_id in line {}", row)),
+            None => None,
+        };
+        entries.push(ScoreEntry {
+            photo_id,
+            file_path: value(path_column),
+            score,
+        });
+    }
+    Ok(entries)
+}
+
+// bulk upload of model scores as JSON or CSV
+pub async fn upload_scores(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    query: web::Query<ScoreQuery>,
+    body: Bytes,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let is_csv = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains("csv"));
+
+    let (model, kind, mut scores) = if is_csv {
+        let text = std::str::from_utf8(&body).map_err(|_| HandlerError::BadClientData {
+            field: "CSV must be UTF-8".to_string(),
+        })?;
+        let scores = parse_csv(text).map_err(|e| HandlerError::BadClientData { field: e })?;
+        (query.model.clone(), query.kind.clone(), scores)
+    } else {
+        let upload: ScoreUpload =
+            serde_json::from_slice(&body).map_err(|e| HandlerError::BadClientData {
+                field: e.to_string(),
+            })?;
+        (
+            upload.model.or_else(|| query.model.clone()),
+            upload.kind.or_else(|| query.kind.clone()),
+            upload.scores,
+        )
+    };
+
+    if scores.is_empty() || scores.len() > MAX_SCORE_ROWS {
+        return Err(HandlerError::BadClientData {
+            field: format!("Upload between 1 and {} scores", MAX_SCORE_ROWS),
+        });
+    }
+    if scores.iter().any(|s| !(0.0..=1.0).contains(&s.score)) {
+        return Err(HandlerError::BadClientData {
+            field: "Scores must be between 0 and 1".to_string(),
+        });
+    }
+    match kind.as_deref().unwrap_or("confidence") {
+        "confidence" => {}
+        "uncertainty" => {
+            for entry in scores.iter_mut() {
+                entry.score = 1.0 - entry.score;
+            }
+        }
+        _ => {
+            return Err(HandlerError::BadClientData {
+                field: "kind must be confidence or uncertainty".to_string(),
+            })
+        }
+    }
+    let model = model.unwrap_or_default();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    check_album_owner(&client, &user, album_id.0).await?;
+
+    let matched = match db::upload_photo_scores(&client, album_id.0, &model, &scores).await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    if query.rerank.unwrap_or(false) {
+        if let Err(e) = db::set_queue_strategy(&client, album_id.0, QUEUE_UNCERTAINTY).await {
+            error!("Error occured : {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    }
+
+    let summary = json!({ "model": model, "uploaded": scores.len(), "matched": matched });
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("album.scores_upload", "album", album_id.0).after(&summary),
+    )
+    .await;
+    Ok(HttpResponse::build(StatusCode::OK).json(summary))
+}
+
+// how the model scores of an album changed from upload to upload
+pub async fn get_album_score_history(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_album_score_history(&client, album_id.0).await {
+        Ok(history) => Ok(HttpResponse::build(StatusCode::OK).json(history)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+pub async fn get_photo_score_history(
+    pool: web::Data<Pool>,
+    photo_id: web::Path<(i32,)>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_photo_score_history(&client, photo_id.0).await {
+        Ok(history) => Ok(HttpResponse::build(StatusCode::OK).json(history)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
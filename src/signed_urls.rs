@@ -0,0 +1,185 @@
+// Short-lived download urls, so clients fetch photos without going through the handlers.
+// Google storage urls use V4 signing, local ones an HMAC checked by get_signed_photo.
+use crate::config::{SignedUrls, TagifyData};
+use crate::gg_storage;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use log::error;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+const GOOGLE_HOST: &str = "storage.googleapis.com";
+const GOOGLE_MAX_TTL: i64 = 7 * 24 * 60 * 60;
+pub const LOCAL_PREFIX: &str = "/api/signed";
+
+#[derive(Clone)]
+struct GoogleKey {
+    client_email: String,
+    private_key: PKey<Private>,
+}
+
+#[derive(Clone)]
+pub struct UrlSigner {
+    enabled: bool,
+    ttl: i64,
+    secret: Vec<u8>,
+    // Set if photos are in google storage
+    google: Option<GoogleKey>,
+}
+
+fn read_google_key(path: &str) -> Result<GoogleKey, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let json: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let field = |name: &str| {
+        json[name]
+            .as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| format!("{} is missing", name))
+    };
+    let private_key =
+        PKey::private_key_from_pem(field("private_key")?.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(GoogleKey {
+        client_email: field("client_email")?,
+        private_key,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn local_signature(secret: &[u8], album_id: i32, file_path: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}/{}\n{}", album_id, file_path, expires).as_bytes());
+    mac
+}
+
+impl UrlSigner {
+    pub fn new(conf: &SignedUrls, tagify_data: &TagifyData) -> Result<Self, String> {
+        // A random fallback would break urls on every restart and differ between instances
+        if conf.enabled && conf.secret.is_empty() && !tagify_data.google_storage_enable {
+            return Err("secret must be set".to_string());
+        }
+        let google = if conf.enabled && tagify_data.google_storage_enable {
+            if conf.ttl > GOOGLE_MAX_TTL {
+                return Err(format!("ttl must be at most {}", GOOGLE_MAX_TTL));
+            }
+            Some(read_google_key(&tagify_data.google_key_json)?)
+        } else {
+            None
+        };
+        Ok(UrlSigner {
+            enabled: conf.enabled,
+            ttl: conf.ttl,
+            secret: conf.secret.as_bytes().to_vec(),
+            google,
+        })
+    }
+
+    /// Download url of a photo file valid for ttl seconds from now, None if signing is disabled
+    pub fn photo_url(&self, album_id: i32, file_path: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let now = Utc::now();
+        match &self.google {
+            Some(key) => {
+                let bucket_name = format!("{}{}", gg_storage::PREFIX_BUCKET, album_id);
+                match self.sign_google(key, &bucket_name, file_path, now) {
+                    Ok(url) => Some(url),
+                    Err(e) => {
+                        error!("Error signing url of {}: {}", file_path, e);
+                        None
+                    }
+                }
+            }
+            None => {
+                let expires = now.timestamp() + self.ttl;
+                let signature = local_signature(&self.secret, album_id, file_path, expires)
+                    .finalize()
+                    .into_bytes();
+                Some(format!(
+                    "{}/{}/{}?expires={}&signature={}",
+                    LOCAL_PREFIX,
+                    album_id,
+                    file_path,
+                    expires,
+                    base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+                ))
+            }
+        }
+    }
+
+    /// Checks a local url. The signature is compared in constant time.
+    pub fn verify_local(
+        &self,
+        album_id: i32,
+        file_path: &str,
+        expires: i64,
+        signature: &str,
+    ) -> bool {
+        if !self.enabled || self.google.is_some() || expires < Utc::now().timestamp() {
+            return false;
+        }
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(item) => item,
+            Err(_) => return false,
+        };
+        local_signature(&self.secret, album_id, file_path, expires)
+            .verify(&signature)
+            .is_ok()
+    }
+
+    // https://cloud.google.com/storage/docs/access-control/signing-urls-manually
+    fn sign_google(
+        &self,
+        key: &GoogleKey,
+        bucket_name: &str,
+        object_name: &str,
+        now: DateTime<Utc>,
+    ) -> Result<String, openssl::error::ErrorStack> {
+        let datetime = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/auto/storage/goog4_request", &datetime[..8]);
+
+        // Url does the percent encoding, so the signed and the sent query are the same
+        let mut url =
+            Url::parse(&format!("https://{}/", GOOGLE_HOST)).expect("Static url is valid");
+        url.path_segments_mut()
+            .expect("Https url has a path")
+            .pop_if_empty()
+            .push(bucket_name)
+            .push(object_name);
+        url.query_pairs_mut()
+            .append_pair("X-Goog-Algorithm", "GOOG4-RSA-SHA256")
+            .append_pair(
+                "X-Goog-Credential",
+                &format!("{}/{}", key.client_email, scope),
+            )
+            .append_pair("X-Goog-Date", &datetime)
+            .append_pair("X-Goog-Expires", &self.ttl.to_string())
+            .append_pair("X-Goog-SignedHeaders", "host");
+
+        let canonical_request = format!(
+            "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            url.path(),
+            url.query().unwrap_or(""),
+            GOOGLE_HOST
+        );
+        let string_to_sign = format!(
+            "GOOG4-RSA-SHA256\n{}\n{}\n{}",
+            datetime,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &key.private_key)?;
+        signer.update(string_to_sign.as_bytes())?;
+        let signature = signer.sign_to_vec()?;
+        url.query_pairs_mut()
+            .append_pair("X-Goog-Signature", &hex(&signature));
+        Ok(url.to_string())
+    }
+}
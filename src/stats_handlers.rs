@@ -0,0 +1,157 @@
+use crate::album_handlers::check_album_owner;
+use crate::errors::HandlerError;
+use crate::my_identity_service::Identity;
+use crate::stats_models::{LeaderboardQuery, StatsQuery};
+use crate::user_models::User;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Result};
+use deadpool_postgres::Pool;
+use log::error;
+
+use crate::db;
+
+const DEFAULT_STATS_DAYS: i64 = 30;
+const MAX_STATS_DAYS: i64 = 366;
+const DEFAULT_LEADERBOARD_SIZE: i64 = 20;
+const MAX_LEADERBOARD_SIZE: i64 = 100;
+
+fn check_days(days: Option<i64>) -> Result<i64, HandlerError> {
+    let days = days.unwrap_or(DEFAULT_STATS_DAYS);
+    if days < 1 || days > MAX_STATS_DAYS {
+        return Err(HandlerError::BadClientData {
+            field: format!("days must be between 1 and {}", MAX_STATS_DAYS),
+        });
+    }
+    Ok(days)
+}
+
+fn check_limit(limit: Option<i64>) -> Result<i64, HandlerError> {
+    let limit = limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE);
+    if limit < 1 || limit > MAX_LEADERBOARD_SIZE {
+        return Err(HandlerError::BadClientData {
+            field: format!("limit must be between 1 and {}", MAX_LEADERBOARD_SIZE),
+        });
+    }
+    Ok(limit)
+}
+
+pub async fn get_album_stats(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    query: web::Query<StatsQuery>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let days = check_days(query.days)?;
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    check_album_owner(&client, &id.identity(), album_id.0).await?;
+
+    match db::get_album_stats(&client, album_id.0, days).await {
+        Ok(stats) => Ok(HttpResponse::build(StatusCode::OK).json(stats)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// own tags, reviews and accepted tags per album and per day
+pub async fn get_own_stats(
+    pool: web::Data<Pool>,
+    query: web::Query<StatsQuery>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let days = check_days(query.days)?;
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user: User = id.identity();
+    match db::get_user_contribution(&client, user.id, days).await {
+        Ok(stats) => Ok(HttpResponse::build(StatusCode::OK).json(stats)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// leaderboard over all albums whose owners switched it on
+pub async fn get_leaderboard(
+    pool: web::Data<Pool>,
+    query: web::Query<LeaderboardQuery>,
+) -> Result<HttpResponse, HandlerError> {
+    let days = check_days(query.days)?;
+    let limit = check_limit(query.limit)?;
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_leaderboard(&client, None, days, limit).await {
+        Ok(entries) => Ok(HttpResponse::build(StatusCode::OK).json(entries)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// leaderboard of one album, the owner can always look at it
+pub async fn get_album_leaderboard(
+    pool: web::Data<Pool>,
+    album_id: web::Path<(i32,)>,
+    query: web::Query<LeaderboardQuery>,
+    id: Identity,
+) -> Result<HttpResponse, HandlerError> {
+    let days = check_days(query.days)?;
+    let limit = check_limit(query.limit)?;
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let album = match db::get_album_by_id(&client, album_id.0).await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured get album: {}", e);
+            return Err(HandlerError::BadClientData {
+                field: e.to_string(),
+            });
+        }
+    };
+    let user: User = id.identity();
+    if !album.leaderboard_enabled && album.users_id != user.id && user.role != "admin" {
+        return Err(HandlerError::PermissionDenied {
+            err_message: "The leaderboard of this album is disabled".to_string(),
+        });
+    }
+
+    match db::get_leaderboard(&client, Some(album.id), days, limit).await {
+        Ok(entries) => Ok(HttpResponse::build(StatusCode::OK).json(entries)),
+        Err(e) => {
+            error!("Error occured : {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsQuery {
+    /// Number of days covered by the per day series, defaults to 30
+    pub days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LabelCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyThroughput {
+    /// UTC date as YYYY-MM-DD
+    pub day: String,
+    pub tagged: i64,
+    pub verified: i64,
+    pub rejected: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProductivity {
+    pub users_id: i32,
+    pub username: Option<String>,
+    pub tagged: i64,
+    pub reviewed: i64,
+    /// Median seconds between two consecutive tags, pauses longer than a lease are left out
+    pub median_seconds_per_image: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlbumStats {
+    pub album_id: i32,
+    pub total: i64,
+    pub tagged: i64,
+    pub verified: i64,
+    pub rejected: i64,
+    pub flagged: i64,
+    pub leased: i64,
+    pub gold: i64,
+    pub labels: Vec<LabelCount>,
+    pub per_day: Vec<DailyThroughput>,
+    pub users: Vec<UserProductivity>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlbumContribution {
+    pub album_id: i32,
+    pub title: Option<String>,
+    pub tagged: i64,
+    pub verified: i64,
+    /// Own tags that passed review
+    pub accepted: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyContribution {
+    pub day: String,
+    pub tagged: i64,
+    pub verified: i64,
+    pub accepted: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserContribution {
+    pub users_id: i32,
+    pub albums: Vec<AlbumContribution>,
+    pub per_day: Vec<DailyContribution>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardQuery {
+    pub days: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub rank: i64,
+    pub users_id: i32,
+    pub nickname: String,
+    pub tagged: i64,
+    pub accepted: i64,
+}
@@ -0,0 +1,270 @@
+// Photo files of an album, either in the local album folder or in the album's bucket
+use crate::errors::HandlerError;
+use crate::gg_storage;
+use actix_files::NamedFile;
+use actix_web::http::header::{HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use log::error;
+use std::fs;
+
+/// Photos are private, but rarely change. Once stale, the ETag makes revalidation cheap.
+const PHOTO_CACHE_CONTROL: &str = "private, max-age=600";
+
+// Request headers passed on to google storage, so it answers ranges and conditional requests
+const FORWARDED_HEADERS: [&str; 4] = ["range", "if-range", "if-none-match", "if-modified-since"];
+const RETURNED_HEADERS: [&str; 3] = ["etag", "last-modified", "content-range"];
+
+/// Token for google storage, empty if it is disabled
+pub fn read_bearer_string(google_storage_enable: bool) -> Result<String, std::io::Error> {
+    if google_storage_enable {
+        fs::read_to_string("./credential/gen_token/oauth_key.txt")
+    } else {
+        Ok("".to_string())
+    }
+}
+
+pub enum Store<'a> {
+    Local {
+        album_path: &'a str,
+    },
+    Google {
+        client: &'a reqwest::Client,
+        bearer_string: &'a String,
+        bucket_name: &'a String,
+    },
+}
+
+impl<'a> Store<'a> {
+    pub fn new(
+        google_storage_enable: bool,
+        client: &'a reqwest::Client,
+        bearer_string: &'a String,
+        bucket_name: &'a String,
+        album_path: &'a str,
+    ) -> Self {
+        if google_storage_enable {
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            }
+        } else {
+            Store::Local { album_path }
+        }
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Bytes> {
+        match self {
+            Store::Local { album_path } => fs::read(format!("{}{}", album_path, name))
+                .ok()
+                .map(Bytes::from),
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            } => match gg_storage::download_object_bytes_if_exists(
+                client,
+                bearer_string,
+                bucket_name,
+                &name.to_string(),
+            )
+            .await
+            {
+                Ok(item) => item,
+                Err(e) => {
+                    error!("Error downloading object from google storage {:?}", e);
+                    None
+                }
+            },
+        }
+    }
+
+    pub async fn exists(&self, name: &str) -> bool {
+        match self {
+            Store::Local { album_path } => {
+                std::path::Path::new(&format!("{}{}", album_path, name)).exists()
+            }
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            } => gg_storage::object_exists_in_bucket(
+                client,
+                bearer_string,
+                bucket_name,
+                &name.to_string(),
+            )
+            .await
+            .unwrap_or(false),
+        }
+    }
+
+    pub async fn put(&self, name: &str, data: Vec<u8>) -> bool {
+        match self {
+            Store::Local { album_path } => {
+                let path = format!("{}{}", album_path, name);
+                match web::block(move || fs::write(path, data)).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Error writing {}: {}", name, e);
+                        false
+                    }
+                }
+            }
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            } => match gg_storage::upload_buffer_with_name_to_bucket(
+                client,
+                bearer_string,
+                bucket_name,
+                &name.to_string(),
+                Bytes::from(data),
+            )
+            .await
+            {
+                Ok(response) if !response.contains("error") => true,
+                Ok(response) => {
+                    error!("Error uploading {} to google storage {}", name, response);
+                    false
+                }
+                Err(e) => {
+                    error!("Error uploading {} to google storage {:?}", name, e);
+                    false
+                }
+            },
+        }
+    }
+
+    pub async fn delete(&self, name: &str) {
+        match self {
+            Store::Local { album_path } => {
+                let path = format!("{}{}", album_path, name);
+                if std::path::Path::new(&path).exists() {
+                    if let Err(e) = fs::remove_file(&path) {
+                        error!("Error deleting {}: {:?}", path, e);
+                    }
+                }
+            }
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            } => {
+                if let Err(e) = gg_storage::delete_object_from_bucket(
+                    client,
+                    bearer_string,
+                    bucket_name,
+                    &name.to_string(),
+                )
+                .await
+                {
+                    error!("Error deleting object from google storage {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Streams a file with ETag and Last-Modified, answering Range and conditional requests.
+    /// None if the file does not exist.
+    pub async fn serve(
+        &self,
+        req: &HttpRequest,
+        name: &str,
+        content_type: &str,
+    ) -> Result<Option<HttpResponse>, HandlerError> {
+        match self {
+            Store::Local { album_path } => {
+                let path = format!("{}{}", album_path, name);
+                if !std::path::Path::new(&path).exists() {
+                    return Ok(None);
+                }
+                let file = NamedFile::open(&path).map_err(|e| {
+                    error!("Error openning local file {:?}", e);
+                    HandlerError::InternalError
+                })?;
+                let mut response = file.into_response(req).map_err(|e| {
+                    error!("Error serving {}: {}", path, e);
+                    HandlerError::InternalError
+                })?;
+                let headers = response.headers_mut();
+                if let Ok(value) = HeaderValue::from_str(content_type) {
+                    headers.insert(CONTENT_TYPE, value);
+                }
+                headers.insert(CACHE_CONTROL, HeaderValue::from_static(PHOTO_CACHE_CONTROL));
+                Ok(Some(response))
+            }
+            Store::Google {
+                client,
+                bearer_string,
+                bucket_name,
+            } => {
+                let mut forwarded = reqwest::header::HeaderMap::new();
+                for header in FORWARDED_HEADERS.iter() {
+                    if let Some(value) = req.headers().get(*header) {
+                        if let Ok(value) =
+                            reqwest::header::HeaderValue::from_bytes(value.as_bytes())
+                        {
+                            forwarded.insert(*header, value);
+                        }
+                    }
+                }
+                let res = match gg_storage::stream_object_from_bucket(
+                    client,
+                    bearer_string,
+                    bucket_name,
+                    &name.to_string(),
+                    forwarded,
+                )
+                .await
+                {
+                    Ok(item) => item,
+                    Err(e) => {
+                        error!("Error downloading object from google storage {:?}", e);
+                        return Err(HandlerError::InternalError);
+                    }
+                };
+
+                let status = StatusCode::from_u16(res.status().as_u16())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                match status {
+                    StatusCode::NOT_FOUND => return Ok(None),
+                    StatusCode::OK
+                    | StatusCode::PARTIAL_CONTENT
+                    | StatusCode::NOT_MODIFIED
+                    | StatusCode::RANGE_NOT_SATISFIABLE => {}
+                    _ => {
+                        error!("Error downloading {} from google storage: {}", name, status);
+                        return Err(HandlerError::InternalError);
+                    }
+                }
+
+                let mut builder = HttpResponse::build(status);
+                builder
+                    .header(CACHE_CONTROL, PHOTO_CACHE_CONTROL)
+                    .header(ACCEPT_RANGES, "bytes");
+                for header in RETURNED_HEADERS.iter() {
+                    if let Some(Ok(value)) = res.headers().get(*header).map(|v| v.to_str()) {
+                        builder.header(*header, value);
+                    }
+                }
+                if status != StatusCode::OK && status != StatusCode::PARTIAL_CONTENT {
+                    return Ok(Some(builder.finish()));
+                }
+                let object_name = name.to_string();
+                let body = res.bytes_stream().map_err(move |e| {
+                    error!(
+                        "Error streaming {} from google storage {:?}",
+                        object_name, e
+                    );
+                    HandlerError::InternalError
+                });
+                Ok(Some(builder.content_type(content_type).streaming(body)))
+            }
+        }
+    }
+}
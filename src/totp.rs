@@ -0,0 +1,142 @@
+// Time-based one-time passwords after RFC 6238 (HMAC-SHA1, 6 digits, 30s steps)
+use crate::password::PasswordSettings;
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use reqwest::Url;
+use sha1::Sha1;
+
+pub const ISSUER: &str = "Tagify";
+const DIGITS: u32 = 6;
+const STEP: i64 = 30;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_LEN: usize = 10;
+const RECOVERY_CODE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> String {
+    let secret: [u8; SECRET_LEN] = rand::thread_rng().gen();
+    base32::encode(ALPHABET, &secret)
+}
+
+// URI understood by authenticator apps, usually shown as QR code
+pub fn provisioning_uri(account: &str, secret: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("Static otpauth uri is valid");
+    uri.set_path(&format!("{}:{}", ISSUER, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string());
+    uri.to_string()
+}
+
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    code % 10u32.pow(DIGITS)
+}
+
+/// Checks `code` against the secret at unix time `now`.
+///
+/// One step of clock drift is accepted in both directions. Returns the matching
+/// time step, which must be stored to reject replays of the same code.
+pub fn verify(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(ALPHABET, secret)?;
+
+    let step = now / STEP;
+    (step - 1..=step + 1).find(|s| hotp(&key, *s as u64) == code)
+}
+
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            (0..RECOVERY_CODE_LEN)
+                .map(|_| RECOVERY_CODE_CHARS[rng.gen_range(0, RECOVERY_CODE_CHARS.len())] as char)
+                .collect()
+        })
+        .collect()
+}
+
+// Hashed like passwords, with the configured argon2 parameters
+pub fn hash_recovery_code(
+    code: &str,
+    passwords: &PasswordSettings,
+) -> Result<String, argon2::Error> {
+    passwords.hash(&normalize_recovery_code(code))
+}
+
+// Recovery codes are typed by hand, ignore case and separators
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "12345678901234567890", the key of the RFC 4226 and RFC 6238 test vectors
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let key = b"12345678901234567890";
+        let expected = [755224, 287082, 359152, 969429, 338314];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(key, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn verify_returns_the_matching_step() {
+        assert_eq!(verify(SECRET, "287082", 59), Some(1));
+        assert_eq!(verify(SECRET, "081804", 1_111_111_109), Some(37_037_036));
+        assert_eq!(verify(SECRET, "005924", 1_234_567_890), Some(41_152_263));
+    }
+
+    #[test]
+    fn verify_accepts_one_step_of_drift() {
+        // The code of step 1 is valid from 30 to 59
+        assert_eq!(verify(SECRET, "287082", 0), Some(1));
+        assert_eq!(verify(SECRET, "287082", 89), Some(1));
+        assert_eq!(verify(SECRET, "287082", 90), None);
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        assert_eq!(verify(SECRET, " 287082 ", 59), Some(1));
+        assert_eq!(verify(SECRET, "28708", 59), None);
+        assert_eq!(verify(SECRET, "2870820", 59), None);
+        assert_eq!(verify(SECRET, "28708a", 59), None);
+        assert_eq!(verify(SECRET, "287082", 59 + 3 * STEP), None);
+        assert_eq!(verify("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn recovery_codes_ignore_case_and_separators() {
+        assert_eq!(normalize_recovery_code("AbCd-E fg2"), "abcdefg2");
+        for code in generate_recovery_codes(3) {
+            assert_eq!(code.len(), RECOVERY_CODE_LEN);
+            assert_eq!(normalize_recovery_code(&code), code);
+        }
+    }
+}
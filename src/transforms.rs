@@ -0,0 +1,618 @@
+// Resized, cropped, rotated and converted copies of photos, made on request and kept in a
+// size-bounded cache on disk
+use crate::album_models::TransformQuery;
+use crate::config::Transforms;
+use crate::errors::HandlerError;
+use crate::image_info;
+use crate::utils;
+use actix_web::web;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use log::{error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Mutex;
+
+const FIT_CONTAIN: &str = "contain";
+const FIT_COVER: &str = "cover";
+const FIT_FILL: &str = "fill";
+
+const FORMAT_JPEG: &str = "jpeg";
+const FORMAT_PNG: &str = "png";
+const FORMAT_WEBP: &str = "webp";
+
+#[derive(Debug, Clone)]
+pub struct Transform {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: &'static str,
+    crop: Option<(u32, u32, u32, u32)>,
+    rotate: u32,
+    format: &'static str,
+    quality: u8,
+    orient: bool,
+    // bounds the side left open when only width or height is given
+    max_dimension: u32,
+}
+
+fn bad_request(field: &str) -> HandlerError {
+    HandlerError::BadClientData {
+        field: field.to_string(),
+    }
+}
+
+fn parse_crop(crop: &str) -> Result<(u32, u32, u32, u32), HandlerError> {
+    let values: Vec<u32> = crop
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| bad_request("crop must be x,y,width,height"))?;
+    match values.as_slice() {
+        [x, y, width, height] if *width > 0 && *height > 0 => Ok((*x, *y, *width, *height)),
+        _ => Err(bad_request("crop must be x,y,width,height")),
+    }
+}
+
+/// Checks the query. Without format the photo keeps its own, if it is one of ours.
+pub fn parse(
+    query: &TransformQuery,
+    conf: &Transforms,
+    file_path: &str,
+) -> Result<Transform, HandlerError> {
+    for size in [query.width, query.height].iter().flatten() {
+        if *size == 0 || *size > conf.max_dimension {
+            return Err(HandlerError::BadClientData {
+                field: format!("width and height must be 1 to {}", conf.max_dimension),
+            });
+        }
+    }
+    let fit = match query.fit.as_deref() {
+        None | Some(FIT_CONTAIN) => FIT_CONTAIN,
+        Some(FIT_COVER) => FIT_COVER,
+        Some(FIT_FILL) => FIT_FILL,
+        Some(_) => return Err(bad_request("fit must be contain, cover or fill")),
+    };
+    if fit != FIT_CONTAIN && (query.width.is_none() || query.height.is_none()) {
+        return Err(bad_request("cover and fill need width and height"));
+    }
+    let rotate = query.rotate.unwrap_or(0);
+    if rotate % 90 != 0 {
+        return Err(bad_request("rotate must be a multiple of 90"));
+    }
+    let format = match query.format.as_deref() {
+        Some("jpg") | Some(FORMAT_JPEG) => FORMAT_JPEG,
+        Some(FORMAT_PNG) => FORMAT_PNG,
+        Some(FORMAT_WEBP) => FORMAT_WEBP,
+        Some(_) => return Err(bad_request("format must be jpeg, png or webp")),
+        None => match utils::get_file_ext(file_path).to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => FORMAT_JPEG,
+            "webp" => FORMAT_WEBP,
+            _ => FORMAT_PNG,
+        },
+    };
+    let quality = query.quality.unwrap_or(conf.quality);
+    if !(1..=100).contains(&quality) {
+        return Err(bad_request("quality must be 1 to 100"));
+    }
+
+    Ok(Transform {
+        width: query.width,
+        height: query.height,
+        fit,
+        crop: match &query.crop {
+            Some(crop) => Some(parse_crop(crop)?),
+            None => None,
+        },
+        rotate: rotate % 360,
+        format,
+        quality,
+        orient: query.orient.unwrap_or(true),
+        max_dimension: conf.max_dimension,
+    })
+}
+
+// Same for equal transforms only, covers everything that changes the result
+fn canonical(transform: &Transform) -> String {
+    format!(
+        "{:?};{:?};{};{:?};{};{};{};{};{}",
+        transform.width,
+        transform.height,
+        transform.fit,
+        transform.crop,
+        transform.rotate,
+        transform.format,
+        transform.quality,
+        transform.orient,
+        transform.max_dimension
+    )
+}
+
+impl Transform {
+    /// File name of the result in the cache. Starts with the photo id so the results of a photo
+    /// can be dropped when it changes.
+    pub fn cache_name(&self, photo_id: i32, file_path: &str) -> String {
+        let key = format!("{}\n{}\n{}", photo_id, file_path, canonical(self));
+        let extension = if self.format == FORMAT_JPEG {
+            "jpg"
+        } else {
+            self.format
+        };
+        format!(
+            "{}_{}.{}",
+            photo_id,
+            utils::sha256_hex(key.as_bytes()),
+            extension
+        )
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            FORMAT_JPEG => "image/jpeg",
+            FORMAT_WEBP => "image/webp",
+            _ => "image/png",
+        }
+    }
+
+    /// Decodes, transforms and encodes a photo. Blocking, call it through web::block.
+    pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, HandlerError> {
+        let mut image = image::load_from_memory(data).map_err(|e| {
+            error!("Error decoding photo: {}", e);
+            HandlerError::InternalError
+        })?;
+
+        if self.orient {
+            image = match image_info::orientation(data) {
+                Some(2) => image.fliph(),
+                Some(3) => image.rotate180(),
+                Some(4) => image.flipv(),
+                Some(5) => image.rotate90().fliph(),
+                Some(6) => image.rotate90(),
+                Some(7) => image.rotate270().fliph(),
+                Some(8) => image.rotate270(),
+                _ => image,
+            };
+        }
+        if let Some((x, y, width, height)) = self.crop {
+            let inside = u64::from(x) + u64::from(width) <= u64::from(image.width())
+                && u64::from(y) + u64::from(height) <= u64::from(image.height());
+            if !inside {
+                return Err(HandlerError::BadClientData {
+                    field: format!(
+                        "crop must lie within the photo of {}x{}",
+                        image.width(),
+                        image.height()
+                    ),
+                });
+            }
+            image = image.crop_imm(x, y, width, height);
+        }
+        image = match self.rotate {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image,
+        };
+        if self.width.is_some() || self.height.is_some() {
+            let width = self.width.unwrap_or(self.max_dimension);
+            let height = self.height.unwrap_or(self.max_dimension);
+            image = match self.fit {
+                FIT_COVER => image.resize_to_fill(width, height, FilterType::Triangle),
+                FIT_FILL => image.resize_exact(width, height, FilterType::Triangle),
+                _ => image.resize(width, height, FilterType::Triangle),
+            };
+        }
+
+        self.encode(&image).map_err(|e| {
+            error!("Error encoding photo as {}: {}", self.format, e);
+            HandlerError::InternalError
+        })
+    }
+
+    fn encode(&self, image: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+        let mut buffer = Vec::new();
+        match self.format {
+            FORMAT_JPEG => DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut buffer, ImageOutputFormat::Jpeg(self.quality))?,
+            FORMAT_WEBP => {
+                let rgba = image.to_rgba8();
+                let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+                let memory = if self.quality == 100 {
+                    encoder.encode_lossless()
+                } else {
+                    encoder.encode(f32::from(self.quality))
+                };
+                buffer.extend_from_slice(&memory);
+            }
+            _ => image.write_to(&mut buffer, ImageOutputFormat::Png)?,
+        }
+        Ok(buffer)
+    }
+}
+
+struct CacheState {
+    // file name to size and last use
+    entries: HashMap<String, (u64, u64)>,
+    // last use to file name, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    total_bytes: u64,
+}
+
+/// Index of the cached results, shared by all workers
+pub struct TransformCache {
+    pub conf: Transforms,
+    path: String,
+    state: Mutex<CacheState>,
+}
+
+impl CacheState {
+    fn remove(&mut self, name: &str) -> Option<u64> {
+        let (size, last_use) = self.entries.remove(name)?;
+        self.order.remove(&last_use);
+        self.total_bytes -= size;
+        Some(size)
+    }
+
+    // Returns the least recently used results dropped to get below max_bytes
+    fn insert(&mut self, name: &str, size: u64, max_bytes: u64) -> Vec<String> {
+        self.remove(name);
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.insert(name.to_string(), (size, tick));
+        self.order.insert(tick, name.to_string());
+        self.total_bytes += size;
+
+        let mut evicted = Vec::new();
+        while self.total_bytes > max_bytes {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn touch(&mut self, name: &str) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(name) {
+            Some(entry) => {
+                self.order.remove(&entry.1);
+                entry.1 = tick;
+                self.order.insert(tick, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl TransformCache {
+    /// Picks up the results of earlier runs, oldest first
+    pub fn new(conf: Transforms, path: String) -> Self {
+        if let Err(e) = fs::create_dir_all(&path) {
+            error!("Error creating transform cache {}: {}", path, e);
+        }
+        let mut files: Vec<(std::time::SystemTime, String, u64)> = match fs::read_dir(&path) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let meta = entry.metadata().ok()?;
+                    let name = entry.file_name().into_string().ok()?;
+                    if !meta.is_file() {
+                        return None;
+                    }
+                    Some((meta.modified().ok()?, name, meta.len()))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        files.sort();
+
+        let cache = TransformCache {
+            conf,
+            path,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                total_bytes: 0,
+            }),
+        };
+        for (_, name, size) in files {
+            if name.ends_with(".tmp") {
+                let _ = fs::remove_file(format!("{}{}", cache.path, name));
+                continue;
+            }
+            cache.insert(&name, size);
+        }
+        info!(
+            "Transform cache {}: {} bytes",
+            cache.path,
+            cache.state.lock().unwrap().total_bytes
+        );
+        cache
+    }
+
+    /// Folder of the results, ends with a slash
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// True if the result is cached, it then counts as just used
+    pub fn touch(&self, name: &str) -> bool {
+        self.state.lock().unwrap().touch(name)
+    }
+
+    // Files are removed after the index is unlocked, so other requests don't wait for the disk
+    fn remove_files(&self, names: &[String]) {
+        for name in names {
+            if let Err(e) = fs::remove_file(format!("{}{}", self.path, name)) {
+                error!("Error removing cached transform {}: {}", name, e);
+            }
+        }
+    }
+
+    // Records a stored result and drops the least recently used ones above the size limit
+    fn insert(&self, name: &str, size: u64) {
+        let max_bytes = self.conf.cache_max_mb * 1024 * 1024;
+        let evicted = self.state.lock().unwrap().insert(name, size, max_bytes);
+        self.remove_files(&evicted);
+    }
+
+    /// Drops the results of a replaced or deleted photo. Blocking, call it through web::block
+    /// or use purge.
+    pub fn purge(&self, photo_id: i32) {
+        let prefix = format!("{}_", photo_id);
+        let names: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            let names: Vec<String> = state
+                .entries
+                .keys()
+                .filter(|name| name.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for name in names.iter() {
+                state.remove(name);
+            }
+            names
+        };
+        self.remove_files(&names);
+    }
+
+    /// Writes a result next to its final name first, so it is never served half written.
+    /// Blocking, call it through web::block.
+    pub fn store(&self, name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let path = format!("{}{}", self.path, name);
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, &path)?;
+        self.insert(name, data.len() as u64);
+        Ok(())
+    }
+}
+
+/// Drops the results of a replaced or deleted photo on the blocking thread pool
+pub async fn purge(cache: &web::Data<TransformCache>, photo_id: i32) {
+    let cache = cache.clone();
+    let result = web::block(move || -> Result<(), ()> {
+        cache.purge(photo_id);
+        Ok(())
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Error purging cached transforms of {}: {}", photo_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> TransformQuery {
+        TransformQuery {
+            width: None,
+            height: None,
+            fit: None,
+            crop: None,
+            rotate: None,
+            format: None,
+            quality: None,
+            orient: None,
+        }
+    }
+
+    fn parse_err(query: &TransformQuery) -> String {
+        match parse(query, &Transforms::default(), "1.png") {
+            Err(HandlerError::BadClientData { field }) => field,
+            other => panic!("expected BadClientData, got {:?}", other),
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut buffer, ImageOutputFormat::Png)
+            .unwrap();
+        buffer
+    }
+
+    fn empty_state() -> CacheState {
+        CacheState {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            total_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn parse_crop_needs_four_values_and_a_size() {
+        assert_eq!(parse_crop("1,2,3,4").unwrap(), (1, 2, 3, 4));
+        assert_eq!(parse_crop(" 1, 2 ,3 ,4").unwrap(), (1, 2, 3, 4));
+        for crop in &[
+            "1,2,3",
+            "1,2,3,4,5",
+            "1,2,0,4",
+            "1,2,3,0",
+            "-1,2,3,4",
+            "a,b,c,d",
+            "",
+        ] {
+            assert!(parse_crop(crop).is_err(), "{} was accepted", crop);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_rotations() {
+        for rotate in &[1, 45, 100, 275] {
+            let q = TransformQuery {
+                rotate: Some(*rotate),
+                ..query()
+            };
+            assert_eq!(parse_err(&q), "rotate must be a multiple of 90");
+        }
+        let q = TransformQuery {
+            rotate: Some(450),
+            ..query()
+        };
+        let transform = parse(&q, &Transforms::default(), "1.png").unwrap();
+        assert_eq!(transform.rotate, 90);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_crops() {
+        let q = TransformQuery {
+            crop: Some("0,0,10".to_string()),
+            ..query()
+        };
+        assert_eq!(parse_err(&q), "crop must be x,y,width,height");
+    }
+
+    #[test]
+    fn parse_checks_sizes_fit_format_and_quality() {
+        let max = Transforms::default().max_dimension;
+        for size in &[0, max + 1] {
+            let q = TransformQuery {
+                width: Some(*size),
+                ..query()
+            };
+            parse_err(&q);
+        }
+        let q = TransformQuery {
+            width: Some(100),
+            fit: Some("cover".to_string()),
+            ..query()
+        };
+        assert_eq!(parse_err(&q), "cover and fill need width and height");
+        let q = TransformQuery {
+            fit: Some("stretch".to_string()),
+            ..query()
+        };
+        parse_err(&q);
+        let q = TransformQuery {
+            format: Some("gif".to_string()),
+            ..query()
+        };
+        parse_err(&q);
+        for quality in &[0, 101] {
+            let q = TransformQuery {
+                quality: Some(*quality),
+                ..query()
+            };
+            assert_eq!(parse_err(&q), "quality must be 1 to 100");
+        }
+    }
+
+    #[test]
+    fn parse_keeps_the_format_of_the_photo() {
+        let conf = Transforms::default();
+        let format = |file_path| parse(&query(), &conf, file_path).unwrap().format;
+        assert_eq!(format("1.JPG"), FORMAT_JPEG);
+        assert_eq!(format("1.webp"), FORMAT_WEBP);
+        assert_eq!(format("1.png"), FORMAT_PNG);
+        assert_eq!(format("1.gif"), FORMAT_PNG);
+        let q = TransformQuery {
+            format: Some("jpg".to_string()),
+            ..query()
+        };
+        let transform = parse(&q, &conf, "1.png").unwrap();
+        assert_eq!(transform.content_type(), "image/jpeg");
+        assert_eq!(transform.quality, conf.quality);
+    }
+
+    #[test]
+    fn cache_names_differ_by_photo_and_transform() {
+        let conf = Transforms::default();
+        let q = TransformQuery {
+            width: Some(100),
+            format: Some("jpeg".to_string()),
+            ..query()
+        };
+        let transform = parse(&q, &conf, "1.png").unwrap();
+        let name = transform.cache_name(7, "1.png");
+        assert!(name.starts_with("7_") && name.ends_with(".jpg"));
+        assert_eq!(
+            name,
+            parse(&q, &conf, "1.png").unwrap().cache_name(7, "1.png")
+        );
+        assert_ne!(name, transform.cache_name(8, "1.png"));
+        assert_ne!(name, transform.cache_name(7, "2.png"));
+        let q = TransformQuery {
+            width: Some(101),
+            ..q
+        };
+        assert_ne!(
+            name,
+            parse(&q, &conf, "1.png").unwrap().cache_name(7, "1.png")
+        );
+    }
+
+    #[test]
+    fn apply_rejects_crops_outside_the_photo() {
+        let q = TransformQuery {
+            crop: Some("5,5,10,10".to_string()),
+            ..query()
+        };
+        let transform = parse(&q, &Transforms::default(), "1.png").unwrap();
+        match transform.apply(&png(10, 10)) {
+            Err(HandlerError::BadClientData { field }) => {
+                assert_eq!(field, "crop must lie within the photo of 10x10")
+            }
+            other => panic!("expected BadClientData, got {:?}", other.map(|d| d.len())),
+        }
+    }
+
+    #[test]
+    fn apply_crops_before_rotating() {
+        let q = TransformQuery {
+            crop: Some("2,2,6,4".to_string()),
+            rotate: Some(90),
+            ..query()
+        };
+        let transform = parse(&q, &Transforms::default(), "1.png").unwrap();
+        let result = image::load_from_memory(&transform.apply(&png(10, 10)).unwrap()).unwrap();
+        assert_eq!(result.dimensions(), (4, 6));
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used() {
+        let mut state = empty_state();
+        assert!(state.insert("a", 40, 100).is_empty());
+        assert!(state.insert("b", 40, 100).is_empty());
+        assert!(state.touch("a"));
+        assert_eq!(state.insert("c", 40, 100), vec!["b".to_string()]);
+        assert_eq!(state.total_bytes, 80);
+        assert!(!state.touch("b"));
+        // Replacing an entry does not count it twice
+        assert!(state.insert("c", 50, 100).is_empty());
+        assert_eq!(state.total_bytes, 90);
+        assert_eq!(
+            state.insert("d", 200, 100),
+            vec!["a".to_string(), "c".to_string(), "d".to_string()]
+        );
+        assert_eq!(state.total_bytes, 0);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::config::LoginProtection;
+use crate::errors::HandlerError;
+use crate::handlers::{check_login_lock, record_failed_login, LOGIN_CHALLENGE_TTL};
+use crate::my_cookie_policy::MyCookieIdentityPolicy;
+use crate::my_identity_service::{login_user, Identity};
+use crate::password::PasswordSettings;
+use crate::user_models::{
+    RecoveryCodes, TwoFactorCode, TwoFactorEnrollment, TwoFactorLogin, TwoFactorPolicy,
+    TwoFactorStatus, User, ROLES,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::offset::Utc;
+use deadpool_postgres::Pool;
+use log::{debug, error, info};
+
+use crate::audit::{self, AuditEvent};
+use crate::db;
+use crate::totp;
+use crate::utils;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// Accepts a TOTP code or an unused recovery code of a user with enabled 2FA
+async fn verify_second_factor(
+    client: &deadpool_postgres::Client,
+    user_id: i32,
+    code: &str,
+) -> Result<bool, HandlerError> {
+    let two_factor = match db::get_two_factor(client, user_id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let secret = match two_factor.totp_secret {
+        Some(secret) if two_factor.totp_enabled => secret,
+        _ => return Ok(false),
+    };
+
+    if let Some(step) = totp::verify(&secret, code, Utc::now().timestamp()) {
+        return match db::use_totp_step(client, user_id, step).await {
+            Ok(fresh) => Ok(fresh),
+            Err(e) => {
+                error!("Error occured: {}", e);
+                Err(HandlerError::InternalError)
+            }
+        };
+    }
+
+    let recovery_codes = match db::get_unused_recovery_codes(client, user_id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let code = totp::normalize_recovery_code(code);
+    for (code_id, code_hash) in recovery_codes {
+        if argon2::verify_encoded(&code_hash, code.as_bytes()).unwrap_or(false) {
+            info!("User {} used a recovery code", user_id);
+            return match db::use_recovery_code(client, code_id).await {
+                Ok(unused) => Ok(unused),
+                Err(e) => {
+                    error!("Error occured: {}", e);
+                    Err(HandlerError::InternalError)
+                }
+            };
+        }
+    }
+    Ok(false)
+}
+
+async fn new_recovery_codes(
+    client: &deadpool_postgres::Client,
+    passwords: &PasswordSettings,
+    user_id: i32,
+) -> Result<RecoveryCodes, HandlerError> {
+    let codes = totp::generate_recovery_codes(RECOVERY_CODE_COUNT);
+    let mut code_hashes = Vec::new();
+    for code in codes.iter() {
+        match totp::hash_recovery_code(code, passwords) {
+            Ok(i) => code_hashes.push(i),
+            Err(e) => {
+                error!("Error occured: {}", e);
+                return Err(HandlerError::InternalError);
+            }
+        }
+    }
+
+    match db::replace_recovery_codes(client, user_id, &code_hashes).await {
+        Ok(_) => Ok(RecoveryCodes {
+            recovery_codes: codes,
+        }),
+        Err(e) => {
+            error!("Error occured: {}", e);
+            Err(HandlerError::InternalError)
+        }
+    }
+}
+
+// Second step of the login, exchanges the challenge and a code for a session
+pub async fn login_two_factor(
+    data: web::Json<TwoFactorLogin>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+    cookie_factory: web::Data<MyCookieIdentityPolicy>,
+    protection: web::Data<LoginProtection>,
+) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let user_id = match db::get_login_challenge(&client, &data.challenge, LOGIN_CHALLENGE_TTL).await
+    {
+        Ok(Some(i)) => i,
+        Ok(None) => return Err(HandlerError::AuthFail),
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let user = match db::get_user(&client, user_id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::AuthFail);
+        }
+    };
+
+    let ip = utils::client_ip(&req);
+    check_login_lock(&client, &user.username, &ip).await?;
+
+    if !verify_second_factor(&client, user.id, &data.code).await? {
+        record_failed_login(&client, &protection, &user.username, &ip).await;
+        return Err(HandlerError::AuthFail);
+    }
+
+    if let Err(e) = db::delete_login_challenge(&client, &data.challenge).await {
+        error!("Deleting login challenge failed: {}", e);
+    }
+    let attempt_keys = vec![db::account_attempt_key(&user.username)];
+    if let Err(e) = db::reset_login_attempts(&client, &attempt_keys).await {
+        error!("Resetting login attempts failed: {}", e);
+    }
+
+    debug!("User {} logged in with second factor", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.login", "user", user.id),
+    )
+    .await;
+    Ok(login_user(req, cookie_factory.get_ref(), user).await)
+}
+
+pub async fn get_status(pool: web::Data<Pool>, id: Identity) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let two_factor = match db::get_two_factor(&client, user.id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    let enforced = match db::two_factor_enforced(&client, &user.role).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    Ok(HttpResponse::build(StatusCode::OK).json(TwoFactorStatus {
+        enabled: two_factor.totp_enabled,
+        enforced,
+    }))
+}
+
+// Start enrollment, the secret is only active after it was confirmed with verify
+pub async fn enroll(pool: web::Data<Pool>, id: Identity) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::get_two_factor(&client, user.id).await {
+        Ok(i) => {
+            if i.totp_enabled {
+                return Err(HandlerError::BadClientData {
+                    field: "Two-factor authentication is already enabled".to_string(),
+                });
+            }
+        }
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let secret = totp::generate_secret();
+    if let Err(e) = db::set_pending_totp_secret(&client, user.id, &secret).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+
+    let provisioning_uri = totp::provisioning_uri(&user.username, &secret);
+    Ok(
+        HttpResponse::build(StatusCode::OK).json(TwoFactorEnrollment {
+            secret,
+            provisioning_uri,
+        }),
+    )
+}
+
+// Finish enrollment with a code from the authenticator, returns the recovery codes
+pub async fn verify(
+    pool: web::Data<Pool>,
+    passwords: web::Data<PasswordSettings>,
+    id: Identity,
+    data: web::Json<TwoFactorCode>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let two_factor = match db::get_two_factor(&client, user.id).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+    if two_factor.totp_enabled {
+        return Err(HandlerError::BadClientData {
+            field: "Two-factor authentication is already enabled".to_string(),
+        });
+    }
+    let secret = match two_factor.totp_secret {
+        Some(i) => i,
+        None => {
+            return Err(HandlerError::BadClientData {
+                field: "Start the two-factor enrollment first".to_string(),
+            })
+        }
+    };
+
+    let step = match totp::verify(&secret, &data.code, Utc::now().timestamp()) {
+        Some(i) => i,
+        None => {
+            return Err(HandlerError::BadClientData {
+                field: "Invalid code".to_string(),
+            })
+        }
+    };
+    if let Err(e) = db::enable_totp(&client, user.id, step).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    info!("User {} enabled two-factor authentication", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.2fa_enable", "user", user.id),
+    )
+    .await;
+
+    let recovery_codes = new_recovery_codes(&client, &passwords, user.id).await?;
+    Ok(HttpResponse::build(StatusCode::OK).json(recovery_codes))
+}
+
+pub async fn regenerate_recovery_codes(
+    pool: web::Data<Pool>,
+    passwords: web::Data<PasswordSettings>,
+    id: Identity,
+    data: web::Json<TwoFactorCode>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    if !verify_second_factor(&client, user.id, &data.code).await? {
+        return Err(HandlerError::BadClientData {
+            field: "Invalid code".to_string(),
+        });
+    }
+
+    let recovery_codes = new_recovery_codes(&client, &passwords, user.id).await?;
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.2fa_recovery_codes", "user", user.id),
+    )
+    .await;
+    Ok(HttpResponse::build(StatusCode::OK).json(recovery_codes))
+}
+
+pub async fn disable(
+    pool: web::Data<Pool>,
+    id: Identity,
+    data: web::Json<TwoFactorCode>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    let user: User = id.identity();
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    match db::two_factor_enforced(&client, &user.role).await {
+        Ok(true) => {
+            return Err(HandlerError::PermissionDenied {
+                err_message: format!(
+                    "Two-factor authentication is required for role {}",
+                    user.role
+                ),
+            })
+        }
+        Ok(false) => {}
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    }
+
+    if !verify_second_factor(&client, user.id, &data.code).await? {
+        return Err(HandlerError::BadClientData {
+            field: "Invalid code".to_string(),
+        });
+    }
+
+    if let Err(e) = db::disable_totp(&client, user.id).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    info!("User {} disabled two-factor authentication", user.username);
+    audit::record(
+        &client,
+        &req,
+        Some(&user),
+        AuditEvent::new("user.2fa_disable", "user", user.id),
+    )
+    .await;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+pub async fn get_policies(pool: web::Data<Pool>) -> Result<HttpResponse, HandlerError> {
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    let result = match db::get_two_factor_policies(&client).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    Ok(HttpResponse::build(StatusCode::OK).json(result))
+}
+
+// Enforce or relax two-factor authentication for all users of a role
+pub async fn set_policy(
+    pool: web::Data<Pool>,
+    data: web::Json<TwoFactorPolicy>,
+    id: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse, HandlerError> {
+    if !ROLES.contains(&data.role.as_str()) {
+        return Err(HandlerError::BadClientData {
+            field: format!("Unknown role {}", data.role),
+        });
+    }
+
+    let client = match pool.get().await {
+        Ok(item) => item,
+        Err(e) => {
+            error!("Error occured: {}", e);
+            return Err(HandlerError::InternalError);
+        }
+    };
+
+    if let Err(e) = db::set_two_factor_policy(&client, &data).await {
+        error!("Error occured: {}", e);
+        return Err(HandlerError::InternalError);
+    }
+    info!(
+        "Two-factor authentication enforced={} for role {}",
+        data.enforced, data.role
+    );
+    audit::record(
+        &client,
+        &req,
+        Some(&id.identity()),
+        AuditEvent::new("2fa_policy.update", "role", &data.role).after(&*data),
+    )
+    .await;
+
+    Ok(HttpResponse::new(StatusCode::OK))
+}
@@ -1,10 +1,8 @@
+use crate::album_models::PhotoMetadata;
+use crate::password::PasswordSettings;
 use serde::{Deserialize, Serialize};
 use tokio_pg_mapper_derive::PostgresMapper;
 
-// used for password hashing
-use argon2::Config;
-use rand::Rng;
-
 #[derive(Serialize)]
 pub struct Status {
     pub status: String,
@@ -49,6 +47,63 @@ pub struct LoginData {
     pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub challenge: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub enforced: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "two_factor_policy")]
+pub struct TwoFactorPolicy {
+    pub role: String,
+    pub enforced: bool,
+}
+
+#[derive(Debug, Clone, PostgresMapper)]
+#[pg_mapper(table = "users")]
+pub struct TwoFactor {
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "users")]
 pub struct SendUser {
@@ -64,13 +119,76 @@ pub struct CreateUser {
     pub password: String,
     pub nickname: String,
     pub role: String, // TODO: Make an Enum out of it
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateUserEmail {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
 }
 
+// Purposes of the tokens in the user_tokens table
+pub const TOKEN_PASSWORD_RESET: &str = "password_reset";
+pub const TOKEN_EMAIL_VERIFICATION: &str = "email_verification";
+
+// Self registration, the role is always the user role
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterUser {
+    pub username: String,
+    pub password: String,
+    pub nickname: String,
+    pub email: Option<String>,
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "users")]
+pub struct PendingUser {
+    pub id: i32,
+    pub username: String,
+    pub nickname: String,
+    pub email: Option<String>,
+    pub status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "invite_codes")]
+pub struct InviteCode {
+    pub code: String,
+    pub created_by: Option<i32>,
+    pub expires_at: i64,
+    pub used: bool,
+    pub used_by: Option<i32>,
+}
+
+// Values of the status column of users
+pub const STATUS_ACTIVE: &str = "active";
+pub const STATUS_PENDING: &str = "pending";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CreateImageMeta {
     pub album_id: i32,
     pub file_path: String,
     pub coordinates: String,
+    pub content_hash: String,
+    pub perceptual_hash: i64,
+    pub metadata: PhotoMetadata,
 }
 #[derive(Debug, Serialize, Deserialize, Clone, PostgresMapper)]
 #[pg_mapper(table = "image_metas")]
@@ -84,81 +202,54 @@ pub struct ImageMeta {
 
 // Hash password, can be implemented for Structs containing .passwort attribut
 pub trait Hash {
-    fn hash_password(&mut self) -> Result<(), argon2::Error>;
-    fn get_hashed_password(&self) -> Result<String, argon2::Error>;
+    fn password(&self) -> &str;
+    fn set_password(&mut self, password: String);
 
-    #[allow(dead_code)]
-    fn verify_password(&self, password: &[u8]) -> Result<bool, argon2::Error>;
-}
-
-//Hash implementation for User & password in one Trait
-
-impl Hash for User {
-    fn get_hashed_password(&self) -> Result<String, argon2::Error> {
-        let salt: [u8; 32] = rand::thread_rng().gen();
-        let config = Config::default();
-
-        Ok(argon2::hash_encoded(
-            self.password.as_bytes(),
-            &salt,
-            &config,
-        )?)
+    fn get_hashed_password(&self, settings: &PasswordSettings) -> Result<String, argon2::Error> {
+        settings.hash(self.password())
     }
 
-    fn hash_password(&mut self) -> Result<(), argon2::Error> {
-        self.password = self.get_hashed_password()?;
+    fn hash_password(&mut self, settings: &PasswordSettings) -> Result<(), argon2::Error> {
+        let hashed = self.get_hashed_password(settings)?;
+        self.set_password(hashed);
         Ok(())
     }
 
     #[allow(dead_code)]
     fn verify_password(&self, password: &[u8]) -> Result<bool, argon2::Error> {
-        Ok(argon2::verify_encoded(&self.password, password)?)
+        Ok(argon2::verify_encoded(self.password(), password)?)
     }
 }
 
-impl Hash for LoginData {
-    fn get_hashed_password(&self) -> Result<String, argon2::Error> {
-        let salt: [u8; 32] = rand::thread_rng().gen();
-        let config = Config::default();
-
-        Ok(argon2::hash_encoded(
-            self.password.as_bytes(),
-            &salt,
-            &config,
-        )?)
-    }
+//Hash implementation for User & password in one Trait
 
-    fn hash_password(&mut self) -> Result<(), argon2::Error> {
-        self.password = self.get_hashed_password()?;
-        Ok(())
+impl Hash for User {
+    fn password(&self) -> &str {
+        &self.password
     }
 
-    #[allow(dead_code)]
-    fn verify_password(&self, password: &[u8]) -> Result<bool, argon2::Error> {
-        Ok(argon2::verify_encoded(&self.password, password)?)
+    fn set_password(&mut self, password: String) {
+        self.password = password;
     }
 }
 
-impl Hash for CreateUser {
-    fn get_hashed_password(&self) -> Result<String, argon2::Error> {
-        let salt: [u8; 32] = rand::thread_rng().gen();
-        let config = Config::default();
-
-        Ok(argon2::hash_encoded(
-            self.password.as_bytes(),
-            &salt,
-            &config,
-        )?)
+impl Hash for LoginData {
+    fn password(&self) -> &str {
+        &self.password
     }
 
-    fn hash_password(&mut self) -> Result<(), argon2::Error> {
-        self.password = self.get_hashed_password()?;
-        Ok(())
+    fn set_password(&mut self, password: String) {
+        self.password = password;
+    }
+}
+
+impl Hash for CreateUser {
+    fn password(&self) -> &str {
+        &self.password
     }
 
-    #[allow(dead_code)]
-    fn verify_password(&self, password: &[u8]) -> Result<bool, argon2::Error> {
-        Ok(argon2::verify_encoded(&self.password, password)?)
+    fn set_password(&mut self, password: String) {
+        self.password = password;
     }
 }
 
@@ -1,4 +1,7 @@
+use actix_web::HttpRequest;
 use log::info;
+use rand::Rng;
+use sha2::{Digest, Sha256};
 use std::convert::TryFrom;
 use std::fs;
 
@@ -12,10 +15,47 @@ use std::fs;
 //     is_role
 // }
 
+// Address of the connected peer. Forwarded headers are ignored as they can be spoofed.
+pub fn client_ip(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+// Random hex string for single use tokens
+pub fn random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Rough sanity check, the real check is the verification mail
+pub fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !email.chars().any(|c| c.is_whitespace())
+                && email.len() <= 254
+        }
+        _ => false,
+    }
+}
+
+// Text after the last dot, empty if the name has none
 pub fn get_file_ext(full_filename: &str) -> String {
-    let vec: Vec<&str> = full_filename.split(".").collect();
-    let file_ext: &str = vec[1];
-    return file_ext.to_string();
+    match full_filename.rfind('.') {
+        Some(i) => full_filename[i + 1..].to_string(),
+        None => String::new(),
+    }
 }
 
 pub fn get_filenames_in_folder(folder_path: &str) -> Vec<String> {